@@ -0,0 +1,170 @@
+use {
+    std::time::Duration,
+    log::{info, warn},
+    tokio::{task, time::sleep},
+    crate::{
+        error::{Error, Result},
+        source::get_fan_speeds,
+        IpmiSession,
+    },
+};
+
+/// Parameters for stepping down the duty cycle of a zone.
+#[derive(Clone, Debug)]
+pub struct Params {
+    /// Fan sensors that must keep spinning
+    pub fans: Vec<String>,
+    /// Lowest duty cycle to try
+    pub min_dcycle: u8,
+    /// Amount to decrease the duty cycle by at each step
+    pub step: u8,
+    /// Time to wait for the fans to settle after changing the duty cycle
+    pub settle: Duration,
+    /// Fans reporting less than this speed are considered stalled
+    pub min_rpm: f64,
+    /// Extra duty cycle added to the lowest stable duty cycle for the
+    /// recommendation
+    pub margin: u8,
+}
+
+/// Fan speeds measured at a single duty cycle.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub dcycle: u8,
+    /// Speed of each fan in RPM, in the same order as [`Params::fans`]
+    pub rpms: Vec<Option<f64>>,
+}
+
+/// Result of calibrating a single IPMI zone.
+#[derive(Clone, Debug)]
+pub struct Calibration {
+    pub zone: u8,
+    pub samples: Vec<Sample>,
+    /// Lowest duty cycle where every fan kept spinning or [`None`] if a fan
+    /// stalled even at 100%
+    pub min_stable: Option<u8>,
+}
+
+/// Get the names of every fan sensor in the session. Duplicate names are only
+/// returned once.
+pub fn all_fans(session: &IpmiSession) -> Result<Vec<String>> {
+    let sensors = session.ipmi.lock().unwrap().get_fan_readings()?;
+    let mut fans = vec![];
+
+    for sensor in sensors {
+        if !fans.contains(&sensor.name) {
+            fans.push(sensor.name);
+        }
+    }
+
+    if fans.is_empty() {
+        return Err(Error::NoFanSensors);
+    }
+
+    Ok(fans)
+}
+
+/// Set the duty cycle of a zone, wait for the fans to settle, and read their
+/// speeds.
+async fn measure(
+    session: &IpmiSession,
+    zone: u8,
+    dcycle: u8,
+    params: &Params,
+) -> Result<Sample> {
+    info!("[{}] Setting zone {} duty cycle to {}%", session.name, zone, dcycle);
+    task::block_in_place(|| session.ipmi.lock().unwrap().set_duty_cycle(zone, dcycle))?;
+
+    sleep(params.settle).await;
+
+    let rpms = task::block_in_place(|| {
+        get_fan_speeds(&mut **session.ipmi.lock().unwrap(), &params.fans)
+    })?;
+
+    Ok(Sample { dcycle, rpms })
+}
+
+/// Step the duty cycle of a zone down from 100% until a fan stalls or the
+/// minimum duty cycle is reached. The zone is set back to 100% afterwards. Only
+/// the zone being calibrated is changed, so fans in other zones will keep
+/// spinning and should not be mistaken for stalls.
+pub async fn calibrate_zone(
+    session: &IpmiSession,
+    zone: u8,
+    params: &Params,
+) -> Result<Calibration> {
+    let mut calibration = Calibration {
+        zone,
+        samples: vec![],
+        min_stable: None,
+    };
+    let mut dcycle = 100;
+
+    loop {
+        let sample = measure(session, zone, dcycle, params).await?;
+        let stalled: Vec<_> = params.fans
+            .iter()
+            .zip(&sample.rpms)
+            .filter(|(_, rpm)| match rpm {
+                Some(r) => *r <= 0.0 || *r < params.min_rpm,
+                None => true,
+            })
+            .map(|(f, _)| f.as_str())
+            .collect();
+
+        info!("[{}] Zone {} at {}%: {:?} RPM", session.name, zone, dcycle, sample.rpms);
+        calibration.samples.push(sample);
+
+        if !stalled.is_empty() {
+            warn!("[{}] Fan(s) {:?} stalled at {}% duty cycle", session.name, stalled, dcycle);
+            break;
+        }
+
+        calibration.min_stable = Some(dcycle);
+
+        if dcycle <= params.min_dcycle {
+            break;
+        }
+
+        dcycle = dcycle.saturating_sub(params.step).max(params.min_dcycle);
+    }
+
+    info!("[{}] Setting zone {} duty cycle to 100%", session.name, zone);
+    task::block_in_place(|| session.ipmi.lock().unwrap().set_duty_cycle(zone, 100))?;
+
+    Ok(calibration)
+}
+
+/// Print the measured fan speeds and the recommended minimum duty cycle.
+pub fn print_report(calibration: &Calibration, params: &Params) {
+    let header = params.fans
+        .iter()
+        .map(|f| format!("{:>10}", f))
+        .collect::<Vec<_>>()
+        .join("  ");
+
+    println!("Zone {}:", calibration.zone);
+    println!("  {:>6}  {}", "DCYCLE", header);
+
+    for sample in &calibration.samples {
+        let rpms = sample.rpms
+            .iter()
+            .map(|r| match r {
+                Some(r) => format!("{:>10.0}", r),
+                None => format!("{:>10}", "-"),
+            })
+            .collect::<Vec<_>>()
+            .join("  ");
+
+        println!("  {:>5}%  {}", sample.dcycle, rpms);
+    }
+
+    match calibration.min_stable {
+        Some(d) => {
+            println!("  Lowest duty cycle with every fan spinning: {}%", d);
+            println!("  Recommended minimum dcycle for `steps`: {}% (includes {}% margin)",
+                     d.saturating_add(params.margin).min(100), params.margin);
+        }
+        None => println!("  A fan stalled even at 100% duty cycle; check the hardware"),
+    }
+}
@@ -0,0 +1,296 @@
+use {
+    std::{
+        fmt::Display,
+        path::{Path, PathBuf},
+    },
+    tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::UnixStream,
+    },
+    crate::{
+        config::load_config,
+        error::{Error, Result},
+        status::{ControlModeStatus, DaemonStatus, Request, Response},
+    },
+};
+
+/// Find the control socket path from either the explicitly specified path or
+/// the `control_socket` option in the config file.
+pub fn socket_path(socket: Option<&Path>, config: Option<&Path>) -> Result<PathBuf> {
+    if let Some(s) = socket {
+        return Ok(s.to_owned());
+    }
+
+    // Guaranteed by clap
+    let config_path = config.unwrap();
+    let config = load_config(config_path)?;
+
+    config.control_socket
+        .map(PathBuf::from)
+        .ok_or_else(|| Error::ControlSocketNotConfigured(config_path.to_owned()))
+}
+
+/// Send a single request to the daemon's control socket and wait for the
+/// response. An error response from the daemon is returned as
+/// [`Error::ControlRequest`].
+pub async fn request(path: &Path, request: &Request) -> Result<Response> {
+    let stream = UnixStream::connect(path).await
+        .map_err(|e| Error::Io { path: path.to_owned(), source: e })?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut data = serde_json::to_vec(request)
+        .map_err(Error::ControlResponse)?;
+    data.push(b'\n');
+
+    writer.write_all(&data).await
+        .map_err(|e| Error::Io { path: path.to_owned(), source: e })?;
+
+    let line = BufReader::new(reader).lines().next_line().await
+        .map_err(|e| Error::Io { path: path.to_owned(), source: e })?
+        .unwrap_or_default();
+
+    match serde_json::from_str(&line).map_err(Error::ControlResponse)? {
+        Response::Error { message } => Err(Error::ControlRequest(message)),
+        r => Ok(r),
+    }
+}
+
+/// Get the status of the running daemon.
+pub async fn get_status(path: &Path) -> Result<DaemonStatus> {
+    match request(path, &Request::Status).await? {
+        Response::Status(s) => Ok(s),
+        r => Err(Error::ControlRequest(format!("Unexpected response: {:?}", r))),
+    }
+}
+
+/// Format rows of cells with each column padded to the widest cell.
+fn format_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) -> String {
+    let mut widths = header.map(str::len);
+
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+
+    let format_row = |cells: &mut dyn Iterator<Item = &str>| {
+        let line = cells
+            .zip(widths)
+            .map(|(c, w)| format!("{:<w$}", c, w = w))
+            .collect::<Vec<_>>()
+            .join("  ");
+
+        format!("{}\n", line.trim_end())
+    };
+
+    let mut table = format_row(&mut header.iter().copied());
+    for row in rows {
+        table += &format_row(&mut row.iter().map(String::as_str));
+    }
+
+    table
+}
+
+fn format_option<T: Display>(value: Option<T>, suffix: &str) -> String {
+    value.map_or_else(|| "-".to_owned(), |v| format!("{}{}", v, suffix))
+}
+
+/// Format the daemon status as pretty-printed JSON or as human-readable
+/// tables.
+pub fn format_status(status: &DaemonStatus, json: bool) -> Result<String> {
+    if json {
+        return serde_json::to_string_pretty(status)
+            .map(|s| s + "\n")
+            .map_err(Error::ControlResponse);
+    }
+
+    let zone_rows: Vec<_> = status.zones
+        .iter()
+        .map(|z| {
+            let mode = match &z.mode {
+                ControlModeStatus::Auto => "auto".to_owned(),
+                ControlModeStatus::Paused => "paused".to_owned(),
+                ControlModeStatus::Manual { dcycle, expires_in_secs: None } => {
+                    format!("manual ({}%)", dcycle)
+                }
+                ControlModeStatus::Manual { dcycle, expires_in_secs: Some(s) } => {
+                    format!("manual ({}%, {}s left)", dcycle, s)
+                }
+            };
+            let actual = z.status.actual_dcycles
+                .iter()
+                .map(|a| format!("{}={}%", a.zone, a.dcycle))
+                .collect::<Vec<_>>()
+                .join(",");
+
+            [
+                z.index.to_string(),
+                z.status.session.clone(),
+                mode,
+                format_option(z.status.temp.map(|t| format!("{:.1}", t)), "C"),
+                format_option(z.status.temp_raw.map(|t| format!("{:.1}", t)), "C"),
+                format_option(z.status.dcycle, "%"),
+                if actual.is_empty() { "-".to_owned() } else { actual },
+                format_option(z.status.fan_mode.as_ref(), ""),
+                z.status.failures.to_string(),
+                format_option(z.status.last_error.as_ref(), ""),
+            ]
+        })
+        .collect();
+
+    let mut output = format_table(
+        ["ZONE", "SESSION", "MODE", "TEMP", "RAW", "DCYCLE", "ACTUAL", "FAN MODE",
+         "FAILURES", "LAST ERROR"],
+        &zone_rows,
+    );
+
+    let source_rows: Vec<_> = status.zones
+        .iter()
+        .flat_map(|z| {
+            z.status.sources.iter().map(|s| [
+                z.index.to_string(),
+                s.source.clone(),
+                format_option(s.temp.map(|t| format!("{:.1}", t)), "C"),
+            ])
+        })
+        .collect();
+
+    output += "\n";
+    output += &format_table(["ZONE", "SOURCE", "TEMP"], &source_rows);
+
+    let fan_rows: Vec<_> = status.zones
+        .iter()
+        .flat_map(|z| {
+            z.status.fans.iter().map(|f| [
+                z.index.to_string(),
+                f.fan.clone(),
+                format_option(f.rpm.map(|r| format!("{:.0}", r)), " RPM"),
+            ])
+        })
+        .collect();
+
+    if !fan_rows.is_empty() {
+        output += "\n";
+        output += &format_table(["ZONE", "FAN", "SPEED"], &fan_rows);
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        serde_json::{json, Value},
+        crate::status::{
+            FanStatus, IpmiZoneStatus, SessionKind, SessionStatus, SourceStatus, ZoneEntry,
+            ZoneStatus,
+        },
+        super::*,
+    };
+
+    fn status() -> DaemonStatus {
+        DaemonStatus {
+            sessions: vec![SessionStatus {
+                name: "default".to_owned(),
+                kind: SessionKind::Local,
+                hostname: None,
+                fan_mode: "full".to_owned(),
+                ipmi_errors: 2,
+            }],
+            zones: vec![
+                ZoneEntry {
+                    index: 0,
+                    mode: ControlModeStatus::Auto,
+                    status: ZoneStatus {
+                        session: "default".to_owned(),
+                        ipmi_zones: vec![0, 1],
+                        temp_raw: Some(45.25),
+                        temp: Some(44.0),
+                        sources: vec![SourceStatus {
+                            source: "ipmi:CPU Temp".to_owned(),
+                            temp: Some(45.25),
+                            errors: 0,
+                        }],
+                        dcycle: Some(40),
+                        actual_dcycles: vec![
+                            IpmiZoneStatus { zone: 0, dcycle: 40 },
+                            IpmiZoneStatus { zone: 1, dcycle: 40 },
+                        ],
+                        fans: vec![FanStatus { fan: "FAN1".to_owned(), rpm: Some(1200.0) }],
+                        fan_mode: Some("full".to_owned()),
+                        ..Default::default()
+                    },
+                },
+                ZoneEntry {
+                    index: 1,
+                    mode: ControlModeStatus::Manual { dcycle: 80, expires_in_secs: Some(30) },
+                    status: ZoneStatus {
+                        session: "default".to_owned(),
+                        ipmi_zones: vec![2],
+                        sources: vec![SourceStatus {
+                            source: "file:/sys/temp".to_owned(),
+                            temp: None,
+                            errors: 3,
+                        }],
+                        failures: 3,
+                        last_error: Some("No readings".to_owned()),
+                        ..Default::default()
+                    },
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn status_table() {
+        let expected = "\
+ZONE  SESSION  MODE                    TEMP   RAW    DCYCLE  ACTUAL       FAN MODE  FAILURES  LAST ERROR
+0     default  auto                    44.0C  45.2C  40%     0=40%,1=40%  full      0         -
+1     default  manual (80%, 30s left)  -      -      -       -            -         3         No readings
+
+ZONE  SOURCE          TEMP
+0     ipmi:CPU Temp   45.2C
+1     file:/sys/temp  -
+
+ZONE  FAN   SPEED
+0     FAN1  1200 RPM
+";
+
+        assert_eq!(format_status(&status(), false).unwrap(), expected);
+    }
+
+    #[test]
+    fn status_json() {
+        let output = format_status(&status(), true).unwrap();
+        assert!(output.ends_with("}\n"));
+
+        let value: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["sessions"], json!([{
+            "name": "default",
+            "kind": "local",
+            "hostname": null,
+            "fan_mode": "full",
+            "ipmi_errors": 2,
+        }]));
+        assert_eq!(value["zones"][1], json!({
+            "index": 1,
+            "mode": { "type": "manual", "dcycle": 80, "expires_in_secs": 30 },
+            "session": "default",
+            "ipmi_zones": [2],
+            "temp_raw": null,
+            "temp": null,
+            "sources": [{ "source": "file:/sys/temp", "temp": null, "errors": 3 }],
+            "dcycle": null,
+            "actual_dcycles": [],
+            "fans": [],
+            "fan_mode": null,
+            "failures": 3,
+            "retries_exhausted": 0,
+            "last_error": "No readings",
+        }));
+
+        // The output can be read back by other tools
+        let parsed: DaemonStatus = serde_json::from_str(&output).unwrap();
+        assert_eq!(parsed.zones[0].status.actual_dcycles.len(), 2);
+    }
+}
@@ -1,12 +1,15 @@
 use {
     std::{
         collections::HashMap,
+        env,
         fmt,
         fs,
-        path::Path,
+        net::SocketAddr,
+        path::{Path, PathBuf},
         time::Duration,
     },
     clap::{Parser, ValueEnum},
+    regex::Regex,
     retry::delay::Fixed,
     serde::{
         de::{
@@ -19,7 +22,10 @@ use {
         Deserialize,
         Deserializer,
     },
-    crate::error::{Error, Result},
+    crate::{
+        error::{Error, Result},
+        ipmi::FanMode,
+    },
 };
 
 #[derive(Clone, Copy, Debug, Deserialize)]
@@ -50,7 +56,7 @@ impl fmt::Display for LogLevel {
     }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
 pub struct Interval(pub u16);
 
 impl Interval {
@@ -65,7 +71,7 @@ impl Default for Interval {
     }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
 pub struct Retries(pub usize);
 
 impl Default for Retries {
@@ -74,7 +80,7 @@ impl Default for Retries {
     }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
 pub struct RetryDelayMs(pub u64);
 
 impl RetryDelayMs {
@@ -89,14 +95,127 @@ impl Default for RetryDelayMs {
     }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Step {
-    pub temp: u8,
+    pub temp: f64,
     pub dcycle: u8,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+/// Regular expression that is compiled when the config is loaded
+#[derive(Clone, Debug)]
+pub struct Pattern(pub Regex);
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pattern = String::deserialize(deserializer)?;
+
+        Regex::new(&pattern)
+            .map(Self)
+            .map_err(de::Error::custom)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TempUnits {
+    #[default]
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+/// Method of extracting the temperature from a command's output.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "lowercase", tag = "type")]
+pub enum CommandParser {
+    /// The entire output is a number
+    #[default]
+    Number,
+    /// The first capture group (or the whole match if there are no groups) is
+    /// a number
+    Regex {
+        pattern: Pattern,
+    },
+    /// The output is JSON and the value at the pointer is a number
+    Json {
+        pointer: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct CommandTimeoutSecs(pub u64);
+
+impl CommandTimeoutSecs {
+    pub fn to_duration(self) -> Duration {
+        Duration::from_secs(self.0)
+    }
+}
+
+impl Default for CommandTimeoutSecs {
+    fn default() -> Self {
+        Self(10)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ExitCodes(pub Vec<i32>);
+
+impl Default for ExitCodes {
+    fn default() -> Self {
+        Self(vec![0])
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct Scale(pub f64);
+
+impl Default for Scale {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
+/// Units that an IPMI sensor is expected to report. Readings in other units
+/// are rejected.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpmiUnits {
+    #[default]
+    Celsius,
+    Fahrenheit,
+    /// Either Celsius or Fahrenheit
+    Any,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct FileScale(pub f64);
+
+impl Default for FileScale {
+    fn default() -> Self {
+        // Milli-degrees, as used by sysfs
+        Self(0.001)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    #[default]
+    Integer,
+    Float,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct SessionName(pub String);
 
 impl Default for SessionName {
@@ -105,15 +224,34 @@ impl Default for SessionName {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields, rename_all = "lowercase", tag = "type")]
 pub enum Source {
+    /// Exactly one of `sensor`, `sensor_regex`, `record_id`, or `entity_id`
+    /// must be specified. `entity_instance` narrows down `entity_id`.
     Ipmi {
-        sensor: String,
+        /// Exact sensor name
+        sensor: Option<String>,
+        /// Regular expression matching sensor names
+        sensor_regex: Option<Pattern>,
+        /// SDR record ID
+        record_id: Option<u16>,
+        entity_id: Option<u8>,
+        entity_instance: Option<u8>,
+        #[serde(default)]
+        units: IpmiUnits,
     },
     File {
         // TOML can't encode OsString
         path: String,
+        #[serde(default)]
+        scale: FileScale,
+        #[serde(default)]
+        offset: f64,
+        #[serde(default)]
+        units: TempUnits,
+        #[serde(default)]
+        format: FileFormat,
     },
     Smart {
         // TOML can't encode OsString
@@ -123,9 +261,87 @@ pub enum Source {
         // TOML can't encode OsString
         block_dev: String,
     },
+    Hwmon {
+        chip: String,
+        label: String,
+    },
+    Nvme {
+        // TOML can't encode OsString
+        device: String,
+        sensor: Option<u8>,
+    },
+    Command {
+        // TOML can't encode OsString
+        command: Vec<String>,
+        #[serde(default)]
+        parser: CommandParser,
+        #[serde(default)]
+        timeout_secs: CommandTimeoutSecs,
+        #[serde(default)]
+        exit_codes: ExitCodes,
+        #[serde(default)]
+        scale: Scale,
+        #[serde(default)]
+        units: TempUnits,
+    },
 }
 
-#[derive(Clone, Debug, Deserialize)]
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ipmi { sensor: Some(sensor), .. } => write!(f, "ipmi:{}", sensor),
+            Self::Ipmi { sensor_regex: Some(regex), .. } => {
+                write!(f, "ipmi:/{}/", regex.0.as_str())
+            }
+            Self::Ipmi { record_id: Some(id), .. } => write!(f, "ipmi:record_id={}", id),
+            Self::Ipmi { entity_id: Some(id), entity_instance, .. } => {
+                write!(f, "ipmi:entity={}", id)?;
+                if let Some(instance) = entity_instance {
+                    write!(f, ".{}", instance)?;
+                }
+                Ok(())
+            }
+            Self::Ipmi { .. } => write!(f, "ipmi:(none)"),
+            Self::File { path, .. } => write!(f, "file:{}", path),
+            Self::Smart { block_dev } => write!(f, "smart:{}", block_dev),
+            Self::Hdparm { block_dev } => write!(f, "hdparm:{}", block_dev),
+            Self::Hwmon { chip, label } => write!(f, "hwmon:{}/{}", chip, label),
+            Self::Nvme { device, sensor: None } => write!(f, "nvme:{}", device),
+            Self::Nvme { device, sensor: Some(n) } => {
+                write!(f, "nvme:{} (sensor {})", device, n)
+            }
+            Self::Command { command, .. } => write!(f, "command:{}", command.join(" ")),
+        }
+    }
+}
+
+/// A temperature source along with the options for handling failed readings.
+/// Unknown fields are still rejected by [`Source`].
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ZoneSource {
+    #[serde(flatten)]
+    pub source: Source,
+    #[serde(default)]
+    pub optional: bool,
+    pub stale_after_secs: Option<u64>,
+}
+
+impl ZoneSource {
+    pub fn stale_after(&self) -> Option<Duration> {
+        self.stale_after_secs.map(Duration::from_secs)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct MinSources(pub usize);
+
+impl Default for MinSources {
+    fn default() -> Self {
+        Self(1)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields, rename_all = "lowercase", tag = "type")]
 pub enum Aggregation {
     Maximum,
@@ -140,7 +356,85 @@ impl Default for Aggregation {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "lowercase", tag = "type")]
+pub enum Smoothing {
+    Ema {
+        alpha: f64,
+    },
+    Window {
+        size: usize,
+    },
+    Median {
+        size: usize,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct MaxDutyCycle(pub u8);
+
+impl Default for MaxDutyCycle {
+    fn default() -> Self {
+        Self(100)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Pid {
+    pub target: f64,
+    pub kp: f64,
+    #[serde(default)]
+    pub ki: f64,
+    #[serde(default)]
+    pub kd: f64,
+    #[serde(default)]
+    pub min_dcycle: u8,
+    #[serde(default)]
+    pub max_dcycle: MaxDutyCycle,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "lowercase", tag = "type")]
+pub enum Controller {
+    #[default]
+    Curve,
+    Pid(Pid),
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    #[default]
+    Exit,
+    Failsafe,
+    Restore,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct FailsafeDutyCycle(pub u8);
+
+impl Default for FailsafeDutyCycle {
+    fn default() -> Self {
+        Self(100)
+    }
+}
+
+/// Fan stall detection for a zone. A fan is considered stalled if it has no
+/// reading, reports 0 RPM, or reports less than `min_rpm` while the duty cycle
+/// of the zone is above `min_dcycle`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StallDetection {
+    /// Names of the IPMI fan sensors in the zone
+    pub fans: Vec<String>,
+    #[serde(default)]
+    pub min_rpm: f64,
+    #[serde(default)]
+    pub min_dcycle: u8,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Zone {
     #[serde(default)]
@@ -152,10 +446,25 @@ pub struct Zone {
     #[serde(default)]
     pub retry_delay_ms: RetryDelayMs,
     pub ipmi_zones: Vec<u8>,
-    pub sources: Vec<Source>,
+    pub sources: Vec<ZoneSource>,
+    #[serde(default)]
+    pub min_sources: MinSources,
     #[serde(default)]
     pub aggregation: Aggregation,
+    pub smoothing: Option<Smoothing>,
+    #[serde(default)]
+    pub controller: Controller,
+    #[serde(default)]
     pub steps: Vec<Step>,
+    #[serde(default)]
+    pub hysteresis_c: f64,
+    pub max_step_up: Option<u8>,
+    pub max_step_down: Option<u8>,
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
+    #[serde(default)]
+    pub failsafe_dcycle: FailsafeDutyCycle,
+    pub stall_detection: Option<StallDetection>,
 }
 
 impl Zone {
@@ -165,7 +474,7 @@ impl Zone {
 }
 
 /// Simple wrapper around a password string with a redacted Debug implementation
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, Eq, PartialEq)]
 pub struct Password(pub String);
 
 impl fmt::Debug for Password {
@@ -174,7 +483,49 @@ impl fmt::Debug for Password {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// Scripted values of a simulated sensor. Each reading uses the next value,
+/// repeating the last value once they run out.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SimulatedSensor {
+    pub name: String,
+    pub values: Vec<f64>,
+}
+
+/// Calls to a simulated BMC that should fail. Each list contains the call
+/// numbers (starting at 1) of the corresponding operation that fail.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SimulatedFailures {
+    #[serde(default)]
+    pub get_fan_mode: Vec<u64>,
+    #[serde(default)]
+    pub set_fan_mode: Vec<u64>,
+    #[serde(default)]
+    pub get_duty_cycle: Vec<u64>,
+    #[serde(default)]
+    pub set_duty_cycle: Vec<u64>,
+    #[serde(default)]
+    pub get_temperature_readings: Vec<u64>,
+    #[serde(default)]
+    pub get_fan_readings: Vec<u64>,
+}
+
+/// In-memory BMC for testing and demos.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SimulatedBmc {
+    /// Initial fan mode (default: standard)
+    pub fan_mode: Option<FanMode>,
+    #[serde(default)]
+    pub temperature_sensors: Vec<SimulatedSensor>,
+    #[serde(default)]
+    pub fan_sensors: Vec<SimulatedSensor>,
+    #[serde(default)]
+    pub failures: SimulatedFailures,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields, rename_all = "lowercase", tag = "type")]
 pub enum SessionType {
     Local,
@@ -183,6 +534,7 @@ pub enum SessionType {
         username: String,
         password: Password,
     },
+    Simulated(SimulatedBmc),
 }
 
 impl Default for SessionType {
@@ -270,16 +622,70 @@ impl<'de> Deserialize<'de> for SessionTypeCompat {
 #[derive(Debug, Default, Deserialize)]
 pub struct Sessions(pub HashMap<String, SessionTypeCompat>);
 
+/// Lower thresholds (in RPM) to set for fan sensors in a session. Thresholds
+/// that are not specified are left unchanged.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FanThresholds {
+    /// Names of the IPMI fan sensors
+    pub fans: Vec<String>,
+    pub lower_non_critical: Option<f64>,
+    pub lower_critical: Option<f64>,
+    pub lower_non_recoverable: Option<f64>,
+}
+
+impl FanThresholds {
+    /// Get the configured thresholds in the order used by the Get/Set Sensor
+    /// Thresholds commands. Only the lower thresholds can be configured.
+    pub fn values(&self) -> [(&'static str, Option<f64>); 3] {
+        [
+            ("lower_non_critical", self.lower_non_critical),
+            ("lower_critical", self.lower_critical),
+            ("lower_non_recoverable", self.lower_non_recoverable),
+        ]
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Metrics {
+    pub address: SocketAddr,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     #[serde(default)]
     pub log_level: LogLevel,
+    /// Read sources and log the chosen duty cycles without changing anything
+    #[serde(default)]
+    pub dry_run: bool,
+    // TOML can't encode OsString
+    pub control_socket: Option<String>,
+    /// Directory for state that must survive restarts
+    // TOML can't encode OsString
+    pub state_dir: Option<String>,
+    pub metrics: Option<Metrics>,
     #[serde(default)]
     pub sessions: Sessions,
+    /// Fan thresholds for each session
+    #[serde(default)]
+    pub fan_thresholds: HashMap<String, FanThresholds>,
     pub zones: Vec<Zone>,
 }
 
+impl Config {
+    /// Get the directory for state that must survive restarts. If it is not
+    /// configured, the state directory provided by systemd is used, if any.
+    pub fn state_dir(&self) -> Option<PathBuf> {
+        match &self.state_dir {
+            Some(d) => Some(PathBuf::from(d)),
+            None => env::var_os("STATE_DIRECTORY")
+                .and_then(|d| env::split_paths(&d).next()),
+        }
+    }
+}
+
 pub fn load_config(path: &Path) -> Result<Config> {
     let contents = fs::read_to_string(path)
         .map_err(|e| Error::Io { path: path.to_owned(), source: e })?;
@@ -320,6 +726,67 @@ pub fn load_config(path: &Path) -> Result<Config> {
             });
         }
 
+        for (j, s) in zone_config.sources.iter().enumerate() {
+            match &s.source {
+                Source::Ipmi { sensor, sensor_regex, record_id, entity_id, .. }
+                    if [sensor.is_some(), sensor_regex.is_some(), record_id.is_some(),
+                        entity_id.is_some()].iter().filter(|s| **s).count() != 1 =>
+                {
+                    return Err(Error::ConfigValidation {
+                        path: path.to_owned(),
+                        reason: format!("zones[{}].sources[{}]: exactly one of sensor, sensor_regex, record_id, or entity_id must be specified", i, j),
+                    });
+                }
+                Source::Ipmi { entity_id: None, entity_instance: Some(_), .. } => {
+                    return Err(Error::ConfigValidation {
+                        path: path.to_owned(),
+                        reason: format!("zones[{}].sources[{}].entity_instance: requires entity_id", i, j),
+                    });
+                }
+                Source::Nvme { sensor: Some(n), .. } if !(1..=8).contains(n) => {
+                    return Err(Error::ConfigValidation {
+                        path: path.to_owned(),
+                        reason: format!("zones[{}].sources[{}].sensor: must be between 1 and 8", i, j),
+                    });
+                }
+                Source::Command { command, .. } if command.is_empty() => {
+                    return Err(Error::ConfigValidation {
+                        path: path.to_owned(),
+                        reason: format!("zones[{}].sources[{}].command: must be non-empty", i, j),
+                    });
+                }
+                Source::Command { timeout_secs, .. } if timeout_secs.0 == 0 => {
+                    return Err(Error::ConfigValidation {
+                        path: path.to_owned(),
+                        reason: format!("zones[{}].sources[{}].timeout_secs: must be greater than 0", i, j),
+                    });
+                }
+                Source::Command { scale: Scale(scale), .. }
+                | Source::File { scale: FileScale(scale), .. }
+                    if !scale.is_finite() || *scale == 0.0 =>
+                {
+                    return Err(Error::ConfigValidation {
+                        path: path.to_owned(),
+                        reason: format!("zones[{}].sources[{}].scale: must be a non-zero number", i, j),
+                    });
+                }
+                Source::File { offset, .. } if !offset.is_finite() => {
+                    return Err(Error::ConfigValidation {
+                        path: path.to_owned(),
+                        reason: format!("zones[{}].sources[{}].offset: must be a finite number", i, j),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        if zone_config.min_sources.0 == 0 || zone_config.min_sources.0 > zone_config.sources.len() {
+            return Err(Error::ConfigValidation {
+                path: path.to_owned(),
+                reason: format!("zones[{}].min_sources: must be between 1 and the number of sources", i),
+            });
+        }
+
         if !config.sessions.0.contains_key(&zone_config.session.0) {
             return Err(Error::ConfigValidation {
                 path: path.to_owned(),
@@ -334,6 +801,105 @@ pub fn load_config(path: &Path) -> Result<Config> {
             });
         }
 
+        match zone_config.smoothing {
+            Some(Smoothing::Ema { alpha }) if !(alpha > 0.0 && alpha <= 1.0) => {
+                return Err(Error::ConfigValidation {
+                    path: path.to_owned(),
+                    reason: format!("zones[{}].smoothing[type=ema].alpha: must be in the range (0, 1]", i),
+                });
+            }
+            Some(Smoothing::Window { size: 0 }) => {
+                return Err(Error::ConfigValidation {
+                    path: path.to_owned(),
+                    reason: format!("zones[{}].smoothing[type=window].size: must be greater than 0", i),
+                });
+            }
+            Some(Smoothing::Median { size: 0 }) => {
+                return Err(Error::ConfigValidation {
+                    path: path.to_owned(),
+                    reason: format!("zones[{}].smoothing[type=median].size: must be greater than 0", i),
+                });
+            }
+            _ => {}
+        }
+
+        if let Controller::Pid(pid) = &zone_config.controller {
+            for (name, value) in [("kp", pid.kp), ("ki", pid.ki), ("kd", pid.kd)] {
+                if !value.is_finite() || value < 0.0 {
+                    return Err(Error::ConfigValidation {
+                        path: path.to_owned(),
+                        reason: format!("zones[{}].controller[type=pid].{}: must be a non-negative number", i, name),
+                    });
+                }
+            }
+
+            if !pid.target.is_finite() {
+                return Err(Error::ConfigValidation {
+                    path: path.to_owned(),
+                    reason: format!("zones[{}].controller[type=pid].target: must be a finite number", i),
+                });
+            } else if pid.max_dcycle.0 > 100 {
+                return Err(Error::ConfigValidation {
+                    path: path.to_owned(),
+                    reason: format!("zones[{}].controller[type=pid].max_dcycle: invalid percentage: {}", i, pid.max_dcycle.0),
+                });
+            } else if pid.min_dcycle > pid.max_dcycle.0 {
+                return Err(Error::ConfigValidation {
+                    path: path.to_owned(),
+                    reason: format!("zones[{}].controller[type=pid].min_dcycle: must not be greater than max_dcycle", i),
+                });
+            }
+        }
+
+        if !zone_config.hysteresis_c.is_finite() || zone_config.hysteresis_c < 0.0 {
+            return Err(Error::ConfigValidation {
+                path: path.to_owned(),
+                reason: format!("zones[{}].hysteresis_c: must be a non-negative number", i),
+            });
+        }
+
+        for (name, value) in [("max_step_up", zone_config.max_step_up), ("max_step_down", zone_config.max_step_down)] {
+            if value == Some(0) {
+                return Err(Error::ConfigValidation {
+                    path: path.to_owned(),
+                    reason: format!("zones[{}].{}: must be greater than 0", i, name),
+                });
+            }
+        }
+
+        if zone_config.failsafe_dcycle.0 > 100 {
+            return Err(Error::ConfigValidation {
+                path: path.to_owned(),
+                reason: format!("zones[{}].failsafe_dcycle: invalid percentage: {}", i, zone_config.failsafe_dcycle.0),
+            });
+        }
+
+        if let Some(stall) = &zone_config.stall_detection {
+            if stall.fans.is_empty() {
+                return Err(Error::ConfigValidation {
+                    path: path.to_owned(),
+                    reason: format!("zones[{}].stall_detection.fans: must be non-empty", i),
+                });
+            } else if !stall.min_rpm.is_finite() || stall.min_rpm < 0.0 {
+                return Err(Error::ConfigValidation {
+                    path: path.to_owned(),
+                    reason: format!("zones[{}].stall_detection.min_rpm: must be a non-negative number", i),
+                });
+            } else if stall.min_dcycle > 100 {
+                return Err(Error::ConfigValidation {
+                    path: path.to_owned(),
+                    reason: format!("zones[{}].stall_detection.min_dcycle: invalid percentage: {}", i, stall.min_dcycle),
+                });
+            }
+        }
+
+        if matches!(zone_config.controller, Controller::Curve) && zone_config.steps.is_empty() {
+            return Err(Error::ConfigValidation {
+                path: path.to_owned(),
+                reason: format!("zones[{}].steps: must be non-empty when using the curve controller", i),
+            });
+        }
+
         for window in zone_config.steps.windows(2) {
             if window[0].temp >= window[1].temp {
                 return Err(Error::ConfigValidation {
@@ -349,7 +915,12 @@ pub fn load_config(path: &Path) -> Result<Config> {
         }
 
         for (j, &step) in zone_config.steps.iter().enumerate() {
-            if step.dcycle > 100 {
+            if !step.temp.is_finite() {
+                return Err(Error::ConfigValidation {
+                    path: path.to_owned(),
+                    reason: format!("zones[{}].steps[{}].temp: must be a finite number", i, j),
+                });
+            } else if step.dcycle > 100 {
                 return Err(Error::ConfigValidation {
                     path: path.to_owned(),
                     reason: format!("zones[{}].steps[{}].dcycle: invalid percentage: {}", i, j, step.dcycle),
@@ -358,5 +929,46 @@ pub fn load_config(path: &Path) -> Result<Config> {
         }
     }
 
+    for (name, thresholds) in &config.fan_thresholds {
+        if !config.sessions.0.contains_key(name) {
+            return Err(Error::ConfigValidation {
+                path: path.to_owned(),
+                reason: format!("fan_thresholds.{}: session does not exist", name),
+            });
+        } else if thresholds.fans.is_empty() {
+            return Err(Error::ConfigValidation {
+                path: path.to_owned(),
+                reason: format!("fan_thresholds.{}.fans: must be non-empty", name),
+            });
+        }
+
+        let values = thresholds.values();
+
+        if values.iter().all(|(_, v)| v.is_none()) {
+            return Err(Error::ConfigValidation {
+                path: path.to_owned(),
+                reason: format!("fan_thresholds.{}: at least one threshold must be specified", name),
+            });
+        }
+
+        for (field, value) in values {
+            if value.is_some_and(|v| !v.is_finite() || v < 0.0) {
+                return Err(Error::ConfigValidation {
+                    path: path.to_owned(),
+                    reason: format!("fan_thresholds.{}.{}: must be a non-negative number", name, field),
+                });
+            }
+        }
+
+        // Each threshold must not be above the less severe ones
+        let set: Vec<_> = values.iter().filter_map(|(_, v)| *v).collect();
+        if set.windows(2).any(|w| w[0] < w[1]) {
+            return Err(Error::ConfigValidation {
+                path: path.to_owned(),
+                reason: format!("fan_thresholds.{}: lower_non_critical >= lower_critical >= lower_non_recoverable is required", name),
+            });
+        }
+    }
+
     Ok(config)
 }
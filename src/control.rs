@@ -0,0 +1,442 @@
+use crate::config::{Controller, Pid, Step, Zone};
+
+/// Compute the duty cycle for a temperature by linearly interpolating between
+/// the two steps surrounding the temperature. If there are no steps, then the
+/// duty cycle is 100%.
+pub fn curve_dcycle(steps: &[Step], temp: f64) -> u8 {
+    // Index of first step >= the current temperature (if exists)
+    let above_index = Some(steps.partition_point(|s| s.temp < temp))
+        .filter(|i| *i < steps.len());
+    // Index of first step < the current temperature (if exists)
+    let below_index = match above_index {
+        Some(0) => None,
+        Some(i) => Some(i - 1),
+        None => None,
+    };
+    // If step above doesn't exist, use last step's dcycle or 100%
+    let above_step = match above_index {
+        Some(i) => steps[i],
+        None => {
+            let dcycle = steps.last()
+                .map_or(100, |s| s.dcycle);
+
+            Step {
+                temp,
+                dcycle,
+            }
+        }
+    };
+    // If step below doesn't exist, use same step as step above
+    let below_step = match below_index {
+        Some(i) => steps[i],
+        None => above_step,
+    };
+
+    if below_step.temp == above_step.temp {
+        below_step.dcycle
+    } else {
+        // Linearly scale the dcycle
+        let fraction = (temp - below_step.temp) / (above_step.temp - below_step.temp);
+
+        (f64::from(below_step.dcycle)
+            + fraction * f64::from(above_step.dcycle - below_step.dcycle)) as u8
+    }
+}
+
+/// State of a PID controller that drives the duty cycle towards keeping the
+/// temperature at a fixed target.
+#[derive(Debug, Default)]
+pub struct PidState {
+    /// Accumulated integral term, already scaled by `ki`. This is [`None`]
+    /// until the first update.
+    integral: Option<f64>,
+    /// Temperature from the previous update for computing the derivative term
+    prev_temp: Option<f64>,
+    /// Duty cycle that the next update should continue from
+    tracking: Option<u8>,
+}
+
+impl PidState {
+    /// Compute the next duty cycle given the current temperature and the
+    /// number of seconds elapsed since the previous update. The output is
+    /// clamped to the configured minimum and maximum duty cycles.
+    ///
+    /// To avoid integrator windup, the integral term is clamped to the output
+    /// range and is not accumulated while the output is saturated in the same
+    /// direction as the error.
+    pub fn update(&mut self, pid: &Pid, temp: f64, dt: f64) -> u8 {
+        let min = f64::from(pid.min_dcycle);
+        let max = f64::from(pid.max_dcycle.0);
+
+        // Positive error means the temperature is too high, which calls for
+        // more cooling
+        let error = temp - pid.target;
+
+        let p = pid.kp * error;
+        // Derivative on measurement to avoid kicks when the target changes
+        let d = match self.prev_temp {
+            Some(prev) if dt > 0.0 => pid.kd * (temp - prev) / dt,
+            _ => 0.0,
+        };
+        let i_prev = match self.tracking.take() {
+            // Make the output continuous with the tracked duty cycle
+            Some(dcycle) => (f64::from(dcycle) - p - d).clamp(min, max),
+            None => self.integral.unwrap_or(min),
+        };
+        let i_new = (i_prev + pid.ki * error * dt).clamp(min, max);
+
+        let unclamped = p + i_new + d;
+        let saturated = (unclamped > max && error > 0.0)
+            || (unclamped < min && error < 0.0);
+        let i = if saturated { i_prev } else { i_new };
+
+        self.integral = Some(i);
+        self.prev_temp = Some(temp);
+
+        (p + i + d).clamp(min, max).round() as u8
+    }
+
+    /// Continue from `dcycle` on the next update. The integral term is reset so
+    /// that the output only changes by the amount the integral term
+    /// accumulates during that update. This allows the controller to continue
+    /// smoothly from a duty cycle that was applied by something else.
+    pub fn track(&mut self, dcycle: u8) {
+        self.tracking = Some(dcycle);
+    }
+}
+
+/// Per-zone controller state that persists across fan update iterations.
+#[derive(Debug, Default)]
+pub struct ControllerState {
+    pid: PidState,
+}
+
+impl ControllerState {
+    /// Compute the duty cycle for the given temperature using the zone's
+    /// controller type. `dt` is the number of seconds elapsed since the
+    /// previous call.
+    pub fn dcycle(&mut self, zone_config: &Zone, temp: f64, dt: f64) -> u8 {
+        match &zone_config.controller {
+            Controller::Curve => curve_dcycle(&zone_config.steps, temp),
+            Controller::Pid(pid) => self.pid.update(pid, temp, dt),
+        }
+    }
+
+    /// Continue from a duty cycle that was applied by something other than
+    /// the controller (eg. a manual override or the failsafe policy).
+    pub fn track(&mut self, zone_config: &Zone, dcycle: u8) {
+        if let Controller::Pid(_) = &zone_config.controller {
+            self.pid.track(dcycle);
+        }
+    }
+}
+
+/// Reason why the duty cycle chosen by the controller was not used as-is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Adjustment {
+    /// The duty cycle was used as-is
+    None,
+    /// A decrease was suppressed because the temperature has not dropped far
+    /// enough below the temperature that caused the last increase
+    Suppressed,
+    /// The change was limited by the maximum step size per interval
+    Clamped,
+}
+
+/// Per-zone state for applying hysteresis and ramp rate limits to the duty
+/// cycles chosen by the controller.
+#[derive(Debug, Default)]
+pub struct Limiter {
+    /// Previously applied duty cycle
+    dcycle: Option<u8>,
+    /// Temperature at the time the duty cycle was last raised
+    raise_temp: f64,
+}
+
+impl Limiter {
+    /// Apply the zone's hysteresis and ramp rate limits to the target duty
+    /// cycle. Returns the duty cycle to use and whether it differs from the
+    /// target. The first call always returns the target as-is.
+    pub fn apply(&mut self, zone_config: &Zone, temp: f64, target: u8) -> (u8, Adjustment) {
+        let prev = match self.dcycle {
+            Some(d) => d,
+            None => {
+                self.dcycle = Some(target);
+                self.raise_temp = temp;
+                return (target, Adjustment::None);
+            }
+        };
+
+        let mut dcycle = target;
+        let mut adjustment = Adjustment::None;
+
+        // Only lower the speed once the temperature has dropped sufficiently
+        // below the point that raised it
+        if zone_config.hysteresis_c > 0.0
+            && dcycle < prev
+            && temp > self.raise_temp - zone_config.hysteresis_c
+        {
+            dcycle = prev;
+            adjustment = Adjustment::Suppressed;
+        }
+
+        if let Some(step) = zone_config.max_step_up {
+            if dcycle > prev.saturating_add(step) {
+                dcycle = prev.saturating_add(step);
+                adjustment = Adjustment::Clamped;
+            }
+        }
+        if let Some(step) = zone_config.max_step_down {
+            if dcycle < prev.saturating_sub(step) {
+                dcycle = prev.saturating_sub(step);
+                adjustment = Adjustment::Clamped;
+            }
+        }
+
+        if dcycle > prev {
+            self.raise_temp = temp;
+        }
+        self.dcycle = Some(dcycle);
+
+        (dcycle, adjustment)
+    }
+
+    /// Get the duty cycle returned by the previous call to [`Self::apply`].
+    pub fn last(&self) -> Option<u8> {
+        self.dcycle
+    }
+
+    /// Continue from a duty cycle that was applied by something other than
+    /// the limiter (eg. a manual override or the failsafe policy), so that the
+    /// ramp rate limits and hysteresis apply relative to what the fans are
+    /// actually running at.
+    pub fn track(&mut self, temp: f64, dcycle: u8) {
+        if !matches!(self.dcycle, Some(d) if dcycle <= d) {
+            self.raise_temp = temp;
+        }
+        self.dcycle = Some(dcycle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::config::MaxDutyCycle,
+        super::*,
+    };
+
+    fn zone(extra: &str) -> Zone {
+        toml::from_str(&format!(r#"
+            ipmi_zones = [0]
+            sources = [{{ type = "ipmi", sensor = "CPU Temp" }}]
+            {}
+        "#, extra)).unwrap()
+    }
+
+    /// Feed `(temp, target, dcycle, adjustment)` cases through a limiter and
+    /// check that each `(temp, target)` results in `(dcycle, adjustment)`.
+    fn check_limiter(
+        zone_config: &Zone,
+        limiter: &mut Limiter,
+        cases: &[(f64, u8, u8, Adjustment)],
+    ) {
+        for (i, (temp, target, dcycle, adjustment)) in cases.iter().enumerate() {
+            assert_eq!(limiter.apply(zone_config, *temp, *target), (*dcycle, *adjustment),
+                       "case {}: temp={}, target={}", i, temp, target);
+        }
+    }
+
+    fn pid(target: f64, kp: f64, ki: f64, kd: f64) -> Pid {
+        Pid {
+            target,
+            kp,
+            ki,
+            kd,
+            min_dcycle: 0,
+            max_dcycle: MaxDutyCycle(100),
+        }
+    }
+
+    #[test]
+    fn pid_integral_clamped() {
+        let pid = pid(50.0, 0.0, 10.0, 0.0);
+        let mut state = PidState::default();
+
+        assert_eq!(state.update(&pid, 60.0, 1.0), 100);
+        assert_eq!(state.update(&pid, 60.0, 1.0), 100);
+        // The integral term stopped at 100% instead of growing to 200%
+        assert_eq!(state.update(&pid, 49.0, 1.0), 90);
+    }
+
+    #[test]
+    fn pid_anti_windup() {
+        let pid = pid(50.0, 1.0, 1.0, 0.0);
+
+        // Saturated at 100%
+        let mut state = PidState::default();
+        for _ in 0..10 {
+            assert_eq!(state.update(&pid, 150.0, 1.0), 100);
+        }
+        assert_eq!(state.update(&pid, 50.0, 1.0), 0);
+        assert_eq!(state.update(&pid, 60.0, 1.0), 20);
+
+        // Saturated at 0%
+        let mut state = PidState::default();
+        for _ in 0..10 {
+            assert_eq!(state.update(&pid, 0.0, 1.0), 0);
+        }
+        assert_eq!(state.update(&pid, 60.0, 1.0), 20);
+    }
+
+    #[test]
+    fn pid_derivative_on_measurement() {
+        let mut state = PidState::default();
+
+        assert_eq!(state.update(&pid(40.0, 1.0, 0.0, 2.0), 50.0, 1.0), 10);
+        // Changing the target only changes the proportional term
+        assert_eq!(state.update(&pid(30.0, 1.0, 0.0, 2.0), 50.0, 1.0), 20);
+        assert_eq!(state.update(&pid(30.0, 1.0, 0.0, 2.0), 52.0, 1.0), 26);
+    }
+
+    #[test]
+    fn pid_tracking() {
+        let pid = pid(50.0, 1.0, 1.0, 0.0);
+        let mut state = PidState::default();
+
+        assert_eq!(state.update(&pid, 60.0, 1.0), 20);
+
+        // Continue from the applied duty cycle plus one update's worth of
+        // integration
+        state.track(70);
+        assert_eq!(state.update(&pid, 60.0, 1.0), 80);
+        assert_eq!(state.update(&pid, 60.0, 1.0), 90);
+
+        state.track(30);
+        assert_eq!(state.update(&pid, 50.0, 1.0), 30);
+    }
+
+    #[test]
+    fn pid_measured_dt() {
+        // (ki, kd, dt, duty cycles at 50C and then 54C)
+        let cases = [
+            (2.0, 0.0, 0.5, [0, 4]),
+            (2.0, 0.0, 3.0, [0, 24]),
+            (0.0, 1.0, 2.0, [0, 2]),
+            (0.0, 1.0, 0.5, [0, 8]),
+            (2.0, 1.0, 2.0, [0, 18]),
+            // No derivative term without elapsed time
+            (0.0, 1.0, 0.0, [0, 0]),
+        ];
+
+        for (ki, kd, dt, expected) in cases {
+            let pid = pid(50.0, 0.0, ki, kd);
+            let mut state = PidState::default();
+            let actual = [50.0, 54.0].map(|t| state.update(&pid, t, dt));
+
+            assert_eq!(actual, expected, "ki={}, kd={}, dt={}", ki, kd, dt);
+        }
+    }
+
+    #[test]
+    fn curve_interpolation() {
+        let steps = [
+            Step { temp: 30.0, dcycle: 20 },
+            Step { temp: 40.5, dcycle: 30 },
+            Step { temp: 41.5, dcycle: 40 },
+            Step { temp: 70.0, dcycle: 100 },
+        ];
+
+        for (temp, expected) in [
+            (20.0, 20),
+            (30.0, 20),
+            (35.25, 25),
+            (40.5, 30),
+            (41.0, 35),
+            (41.5, 40),
+            (55.75, 70),
+            (69.9, 99),
+            (70.0, 100),
+            (70.1, 100),
+        ] {
+            assert_eq!(curve_dcycle(&steps, temp), expected, "temp={}", temp);
+        }
+
+        assert_eq!(curve_dcycle(&[], 50.0), 100);
+    }
+
+    #[test]
+    fn limiter_hysteresis() {
+        use Adjustment::*;
+
+        let zone_config = zone("hysteresis_c = 3");
+        let mut limiter = Limiter::default();
+
+        check_limiter(&zone_config, &mut limiter, &[
+            (60.0, 50, 50, None),
+            // Falling, but still within 3C of the temperature that raised it
+            (59.0, 40, 50, Suppressed),
+            (58.0, 40, 50, Suppressed),
+            // Falling to the edge of the band
+            (57.0, 40, 40, None),
+            // Rising within the band is never suppressed
+            (58.0, 45, 45, None),
+            // The band is now relative to the temperature of the last increase
+            (56.0, 40, 45, Suppressed),
+            (55.0, 40, 40, None),
+        ]);
+    }
+
+    #[test]
+    fn limiter_max_steps() {
+        use Adjustment::*;
+
+        let zone_config = zone("max_step_up = 10\nmax_step_down = 5");
+        let mut limiter = Limiter::default();
+
+        check_limiter(&zone_config, &mut limiter, &[
+            // The first decision is never limited
+            (50.0, 20, 20, None),
+            (50.0, 60, 30, Clamped),
+            (50.0, 60, 40, Clamped),
+            (50.0, 45, 45, None),
+            (50.0, 0, 40, Clamped),
+            (50.0, 36, 36, None),
+        ]);
+
+        // Steps near the ends of the range do not overflow
+        let zone_config = zone("max_step_up = 200\nmax_step_down = 200");
+        let mut limiter = Limiter::default();
+
+        check_limiter(&zone_config, &mut limiter, &[
+            (50.0, 0, 0, None),
+            (50.0, 100, 100, None),
+            (50.0, 0, 0, None),
+        ]);
+    }
+
+    #[test]
+    fn limiter_track() {
+        use Adjustment::*;
+
+        let zone_config = zone("hysteresis_c = 3\nmax_step_down = 5");
+        let mut limiter = Limiter::default();
+
+        check_limiter(&zone_config, &mut limiter, &[(60.0, 50, 50, None)]);
+
+        // Raised by something else, so the hysteresis band moves
+        limiter.track(62.0, 80);
+        assert_eq!(limiter.last(), Some(80));
+        check_limiter(&zone_config, &mut limiter, &[
+            (60.0, 40, 80, Suppressed),
+            (58.0, 40, 75, Clamped),
+        ]);
+
+        // Lowered by something else, so the hysteresis band stays put
+        limiter.track(50.0, 30);
+        assert_eq!(limiter.last(), Some(30));
+        check_limiter(&zone_config, &mut limiter, &[
+            (48.0, 20, 25, Clamped),
+            (48.0, 20, 20, None),
+        ]);
+    }
+}
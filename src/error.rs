@@ -1,19 +1,31 @@
 use {
     std::{
         io,
-        num::ParseIntError,
+        net::SocketAddr,
+        num::{ParseFloatError, ParseIntError},
         path::PathBuf,
         process::ExitStatus,
         result,
+        time::Duration,
     },
     thiserror::Error,
     tokio::task::JoinError,
     crate::{
+        config::IpmiUnits,
         freeipmi::{SensorUnits, SensorValue},
         ipmi,
     },
 };
 
+/// Error when parsing a numeric sensor value.
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error(transparent)]
+    Int(#[from] ParseIntError),
+    #[error(transparent)]
+    Float(#[from] ParseFloatError),
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Failed to parse config: {path:?}: {source}")]
@@ -29,22 +41,38 @@ pub enum Error {
     #[error("Failed to parse sensor value: {value:?}: {source}")]
     SensorValueParse {
         value: String,
-        source: ParseIntError,
+        source: ParseError,
     },
     #[error("Sensor not found: {0}")]
     SensorNotFound(String),
-    #[error("Unsupported sensor units: {sensor}: {units:?}")]
+    #[error("Unexpected sensor units: {sensor}: {units:?} (expected: {expected:?})")]
     SensorBadUnits {
         sensor: String,
         units: SensorUnits,
+        expected: IpmiUnits,
     },
     #[error("Unsupported sensor value: {sensor}: {value:?}")]
     SensorBadValue {
         sensor: String,
         value: SensorValue,
     },
+    #[error("Unexpected fan sensor units: {sensor}: {units:?}")]
+    FanBadUnits {
+        sensor: String,
+        units: SensorUnits,
+    },
+    #[error("Fan(s) stalled at {dcycle}% duty cycle: {fans:?}")]
+    FanStalled {
+        fans: Vec<String>,
+        dcycle: u8,
+    },
     #[error("Sensor reading not available: {0}")]
     SensorNoReading(String),
+    #[error("Only {available} source(s) have readings, but {required} are required")]
+    NotEnoughSources {
+        available: usize,
+        required: usize,
+    },
     #[error("Temperature reading out of bounds")]
     ReadingExceedsBounds,
     #[error("Failed to parse SMART output for block device: {block_dev:?}: {source}")]
@@ -58,10 +86,56 @@ pub enum Error {
     HdparmNoData(PathBuf),
     #[error("hdparm reported bad data: {0:?}")]
     HdparmBadData(PathBuf),
-    #[error("Failed to run: {command:?}: {status}")]
+    #[error("hwmon chip not found: {0}")]
+    HwmonChipNotFound(String),
+    #[error("hwmon chip {chip} has no temperature sensor labelled: {label}")]
+    HwmonLabelNotFound {
+        chip: String,
+        label: String,
+    },
+    #[error("Multiple hwmon sensors match {chip}/{label}: {paths:?}")]
+    HwmonAmbiguous {
+        chip: String,
+        label: String,
+        paths: Vec<PathBuf>,
+    },
+    #[error("NVMe command failed: {device:?}: status {status:#x}")]
+    NvmeCommand {
+        device: PathBuf,
+        status: i32,
+    },
+    #[error("NVMe device has no temperature reading: {device:?} (sensor: {sensor:?})")]
+    NvmeNoReading {
+        device: PathBuf,
+        sensor: Option<u8>,
+    },
+    #[error("Command timed out after {timeout:?}: {command:?}")]
+    CommandTimeout {
+        command: PathBuf,
+        timeout: Duration,
+    },
+    #[error("Command output did not match pattern: {0:?}")]
+    CommandNoMatch(PathBuf),
+    #[error("Failed to parse command output as JSON: {command:?}: {source}")]
+    CommandJson {
+        command: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("Command output has no value at JSON pointer: {command:?}: {pointer}")]
+    CommandJsonPointer {
+        command: PathBuf,
+        pointer: String,
+    },
+    #[error("Command output is not a number: {command:?}: {value:?}")]
+    CommandBadValue {
+        command: PathBuf,
+        value: String,
+    },
+    #[error("Failed to run: {command:?}: {status}: {stderr:?}")]
     Command {
         command: PathBuf,
         status: ExitStatus,
+        stderr: String,
     },
     #[error("Failed all {attempts} attempt(s); last attempt error: {source}")]
     RetriesFailed {
@@ -75,6 +149,58 @@ pub enum Error {
         path: PathBuf,
         source: io::Error,
     },
+    #[error("Control socket is not configured in: {0:?}")]
+    ControlSocketNotConfigured(PathBuf),
+    #[error("Control socket is already in use by another process: {0:?}")]
+    ControlSocketInUse(PathBuf),
+    #[error("Invalid control socket message: {0}")]
+    ControlResponse(#[source] serde_json::Error),
+    #[error("Control socket request failed: {0}")]
+    ControlRequest(String),
+    #[error("Failed to listen for metrics requests: {address}: {source}")]
+    MetricsBind {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    #[error("Unsupported SDR record for sensor {sensor}: {reason}")]
+    SdrUnsupported {
+        sensor: String,
+        reason: &'static str,
+    },
+    #[error("Threshold cannot be set for sensor {sensor}: {threshold}")]
+    ThresholdNotSettable {
+        sensor: String,
+        threshold: &'static str,
+    },
+    #[error("Threshold value cannot be represented for sensor {sensor}: {threshold} = {value}")]
+    ThresholdOutOfRange {
+        sensor: String,
+        threshold: &'static str,
+        value: f64,
+    },
+    #[error("Failed to parse saved fan thresholds: {path:?}: {source}")]
+    ThresholdsParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("Failed to parse recording: {path:?}: line {line}: {source}")]
+    RecordingParse {
+        path: PathBuf,
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("Zone not found in config: {0}")]
+    ZoneNotFound(usize),
+    #[error("Session not found in config: {0}")]
+    SessionNotFound(String),
+    #[error("No IPMI zones to calibrate for session: {0}")]
+    NoZones(String),
+    #[error("No fan thresholds configured for session: {0}")]
+    NoFanThresholds(String),
+    #[error("No fan sensors found")]
+    NoFanSensors,
+    #[error("No state directory configured for saving the original fan thresholds")]
+    NoStateDir,
     #[error("Zone monitor loop panicked: {0}")]
     LoopPanicked(#[source] JoinError),
 }
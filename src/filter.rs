@@ -0,0 +1,112 @@
+use {
+    std::collections::VecDeque,
+    crate::config::Smoothing,
+};
+
+/// Per-zone state for smoothing aggregated temperature readings over time.
+#[derive(Debug, Default)]
+pub struct Smoother {
+    /// Most recent samples, oldest first (window and median filters)
+    history: VecDeque<f64>,
+    /// Current exponential moving average
+    average: Option<f64>,
+}
+
+impl Smoother {
+    /// Add a new sample and return the smoothed value. If `smoothing` is
+    /// [`None`], the sample is returned as-is.
+    pub fn apply(&mut self, smoothing: Option<&Smoothing>, temp: f64) -> f64 {
+        match smoothing {
+            None => temp,
+            Some(Smoothing::Ema { alpha }) => {
+                let average = match self.average {
+                    Some(a) => alpha * temp + (1.0 - alpha) * a,
+                    None => temp,
+                };
+                self.average = Some(average);
+
+                average
+            }
+            Some(Smoothing::Window { size }) => {
+                self.push(*size, temp);
+
+                self.history.iter().sum::<f64>() / self.history.len() as f64
+            }
+            Some(Smoothing::Median { size }) => {
+                self.push(*size, temp);
+
+                let mut sorted: Vec<_> = self.history.iter().copied().collect();
+                sorted.sort_by(f64::total_cmp);
+
+                let mid = sorted.len() / 2;
+                if sorted.len() % 2 == 0 {
+                    (sorted[mid - 1] + sorted[mid]) / 2.0
+                } else {
+                    sorted[mid]
+                }
+            }
+        }
+    }
+
+    /// Append a sample to the history, discarding the oldest samples so that
+    /// at most `size` remain.
+    fn push(&mut self, size: usize, temp: f64) {
+        self.history.push_back(temp);
+
+        while self.history.len() > size {
+            self.history.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed samples through a new smoother and return the smoothed values.
+    fn smooth(smoothing: Option<&Smoothing>, temps: &[f64]) -> Vec<f64> {
+        let mut smoother = Smoother::default();
+
+        temps.iter().map(|t| smoother.apply(smoothing, *t)).collect()
+    }
+
+    #[test]
+    fn no_smoothing() {
+        assert_eq!(smooth(None, &[40.0, 60.0, 50.5]), [40.0, 60.0, 50.5]);
+    }
+
+    #[test]
+    fn ema() {
+        let smoothing = Smoothing::Ema { alpha: 0.5 };
+
+        assert_eq!(smooth(Some(&smoothing), &[40.0, 50.0, 50.0, 50.0]),
+                   [40.0, 45.0, 47.5, 48.75]);
+
+        let mut temps = vec![40.0];
+        temps.extend([50.0; 30]);
+        let last = *smooth(Some(&smoothing), &temps).last().unwrap();
+        assert!((last - 50.0).abs() < 1e-6, "{} did not converge to 50", last);
+    }
+
+    #[test]
+    fn window() {
+        let smoothing = Smoothing::Window { size: 3 };
+
+        // The oldest samples are evicted once the window is full
+        assert_eq!(smooth(Some(&smoothing), &[40.0, 50.0, 60.0, 70.0, 20.0]),
+                   [40.0, 45.0, 50.0, 60.0, 50.0]);
+    }
+
+    #[test]
+    fn median() {
+        let temps = [40.0, 60.0, 80.0, 30.0, 35.0];
+
+        for (size, expected) in [
+            (3, [40.0, 50.0, 60.0, 60.0, 35.0]),
+            (4, [40.0, 50.0, 60.0, 50.0, 47.5]),
+        ] {
+            assert_eq!(smooth(Some(&Smoothing::Median { size }), &temps), expected,
+                       "size={}", size);
+        }
+    }
+}
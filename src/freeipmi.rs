@@ -20,7 +20,7 @@ use {
 #[cfg(windows)]
 use std::path::PathBuf;
 
-#[derive(Debug, Eq, thiserror::Error, PartialEq)]
+#[derive(Clone, Debug, Eq, thiserror::Error, PartialEq)]
 pub enum Error {
     #[error("Failed to parse as UTF-8: {0}")]
     NotUtf8(#[from] Utf8Error),
@@ -64,9 +64,26 @@ pub enum SensorValue {
 pub enum SensorUnits {
     Celsius,
     Fahrenheit,
+    Rpm,
     Unknown(c_uint),
 }
 
+/// Types of sensors that can be iterated with [`LimSession::sensor_readings`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SensorType {
+    Temperature,
+    Fan,
+}
+
+impl SensorType {
+    fn to_raw(self) -> bindings::ipmi_monitoring_sensor_type {
+        match self {
+            Self::Temperature => bindings::ipmi_monitoring_sensor_type_IPMI_MONITORING_SENSOR_TYPE_TEMPERATURE,
+            Self::Fan => bindings::ipmi_monitoring_sensor_type_IPMI_MONITORING_SENSOR_TYPE_FAN,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct SensorReading {
     pub value: SensorValue,
@@ -240,6 +257,7 @@ impl LfiSession {
             SessionType::Remote { hostname, username, password } => {
                 ctx.open_out_of_band(hostname, username, &password.0)?;
             },
+            SessionType::Simulated(_) => unreachable!("Simulated sessions do not use libfreeipmi"),
         };
 
         Ok(Self(ctx))
@@ -324,6 +342,9 @@ fn lim_strerror(errnum: c_int) -> Result<&'static str> {
     }
 }
 
+/// Maximum size of an SDR record (`IPMI_SDR_MAX_RECORD_LENGTH` in freeipmi)
+const SDR_MAX_RECORD_LENGTH: usize = 261;
+
 static LIM_INITIALIZED: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
 
 /// Initialize libipmimonitoring globally. This is thread safe and is a no-op if
@@ -391,6 +412,7 @@ impl LimSession {
                 CString::new(username.as_str()).unwrap().into_raw(),
                 CString::new(password.0.as_str()).unwrap().into_raw(),
             ),
+            SessionType::Simulated(_) => unreachable!("Simulated sessions do not use libipmimonitoring"),
         };
 
         // [Unsafe] No memory safety concerns. This will never leak because no
@@ -490,16 +512,17 @@ impl LimSession {
         Ok(())
     }
 
-    /// Start iteration of temperature sensor readings. Use [`iterator_next`] to
-    /// advance the iterator and [`read_sensor_name`]/[`read_sensor`] to get the
-    /// actual values.
-    pub fn temperature_sensor_readings(&mut self) -> Result<usize> {
+    /// Start iteration of sensor readings for the given sensor type. Use
+    /// [`iterator_next`] to advance the iterator and
+    /// [`read_sensor_name`]/[`read_sensor`] to get the actual values.
+    /// [`read_record_id`]/[`read_entity`] identify the sensor.
+    pub fn sensor_readings(&mut self, sensor_type: SensorType) -> Result<usize> {
         // LIM does not store this string
         let hostname_cstr = self.hostname.as_ref()
             .map(|s| CString::new(s.as_str()).unwrap());
         let hostname_ptr = hostname_cstr.as_ref()
             .map_or(ptr::null(), |s| s.as_ptr());
-        let mut sensor_type = bindings::ipmi_monitoring_sensor_type_IPMI_MONITORING_SENSOR_TYPE_TEMPERATURE;
+        let mut sensor_type = sensor_type.to_raw();
 
         // [Unsafe] config and sensor_type are passed as mutable pointers to
         // satisfy the type signature only. They are never modified. The
@@ -519,7 +542,7 @@ impl LimSession {
         };
         if ret < 0 {
             return Err(Error::Lim {
-                action: "get temperature sensor readings",
+                action: "get sensor readings",
                 message: self.error_msg()?,
             });
         }
@@ -563,6 +586,93 @@ impl LimSession {
         Ok(cstr.to_str()?.to_owned())
     }
 
+    /// Get the SDR record ID for the current item during sensor reading
+    /// iteration.
+    pub fn read_record_id(&mut self) -> Result<u16> {
+        // [Unsafe] No memory safety concerns
+        let ret = unsafe {
+            bindings::ipmi_monitoring_sensor_read_record_id(self.ctx)
+        };
+        if ret < 0 {
+            return Err(Error::Lim {
+                action: "read record ID",
+                message: self.error_msg()?,
+            });
+        }
+
+        Ok(ret as u16)
+    }
+
+    /// Get the sensor number for the current item during sensor reading
+    /// iteration.
+    pub fn read_sensor_number(&mut self) -> Result<u8> {
+        // [Unsafe] No memory safety concerns
+        let ret = unsafe {
+            bindings::ipmi_monitoring_sensor_read_sensor_number(self.ctx)
+        };
+        if ret < 0 {
+            return Err(Error::Lim {
+                action: "read sensor number",
+                message: self.error_msg()?,
+            });
+        }
+
+        Ok(ret as u8)
+    }
+
+    /// Get the raw SDR record for the current item during sensor reading
+    /// iteration.
+    pub fn read_sdr_record(&mut self) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; SDR_MAX_RECORD_LENGTH];
+
+        // [Unsafe] The buffer is valid for the specified length
+        let ret = unsafe {
+            bindings::ipmi_monitoring_sensor_read_sdr_record(
+                self.ctx,
+                buf.as_mut_ptr().cast(),
+                buf.len() as c_uint,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Lim {
+                action: "read SDR record",
+                message: self.error_msg()?,
+            });
+        }
+
+        buf.truncate(ret as usize);
+
+        Ok(buf)
+    }
+
+    /// Get the entity ID and entity instance for the current item during
+    /// sensor reading iteration.
+    pub fn read_entity(&mut self) -> Result<(u8, u8)> {
+        // [Unsafe] No memory safety concerns
+        let id_ret = unsafe {
+            bindings::ipmi_monitoring_sensor_read_entity_id(self.ctx)
+        };
+        if id_ret < 0 {
+            return Err(Error::Lim {
+                action: "read entity ID",
+                message: self.error_msg()?,
+            });
+        }
+
+        // [Unsafe] No memory safety concerns
+        let instance_ret = unsafe {
+            bindings::ipmi_monitoring_sensor_read_entity_instance(self.ctx)
+        };
+        if instance_ret < 0 {
+            return Err(Error::Lim {
+                action: "read entity instance",
+                message: self.error_msg()?,
+            });
+        }
+
+        Ok((id_ret as u8, instance_ret as u8))
+    }
+
     /// Get the sensor reading (value and units) for the current item during
     /// sensor reading iteration.
     pub fn read_sensor(&mut self) -> Result<Option<SensorReading>> {
@@ -618,6 +728,8 @@ impl LimSession {
                 SensorUnits::Celsius,
             bindings::ipmi_monitoring_sensor_units_IPMI_MONITORING_SENSOR_UNITS_FAHRENHEIT =>
                 SensorUnits::Fahrenheit,
+            bindings::ipmi_monitoring_sensor_units_IPMI_MONITORING_SENSOR_UNITS_RPM =>
+                SensorUnits::Rpm,
             o => SensorUnits::Unknown(o),
         };
 
@@ -1,18 +1,56 @@
 use {
     std::{
-        collections::HashMap,
         env,
+        fmt,
         result,
+        str::FromStr,
+        sync::{
+            Arc,
+            atomic::{AtomicU64, Ordering},
+        },
     },
-    log::trace,
+    log::{info, trace},
+    serde::{de, Deserialize, Deserializer, Serialize},
     crate::{
         bindings,
         config::SessionType,
-        freeipmi::{self, LfiSession, LimSession, SensorReading},
+        freeipmi::{self, LfiSession, LimSession, SensorReading, SensorType},
+        simulated::SimulatedIpmi,
     },
 };
 
-#[derive(Debug, thiserror::Error)]
+/// A sensor reading along with the information identifying the sensor. Sensor
+/// names are not necessarily unique.
+#[derive(Clone, Debug)]
+pub struct Sensor {
+    pub record_id: u16,
+    pub name: String,
+    pub entity_id: u8,
+    pub entity_instance: u8,
+    pub reading: Option<SensorReading>,
+}
+
+/// The information needed to address a sensor with IPMI sensor commands,
+/// along with its raw SDR record.
+#[derive(Clone, Debug)]
+pub struct SensorRecord {
+    pub name: String,
+    pub sensor_number: u8,
+    pub sdr: Vec<u8>,
+}
+
+/// Raw threshold values of a sensor, in the order used by the Get/Set Sensor
+/// Thresholds commands: lower non-critical, lower critical, lower
+/// non-recoverable, upper non-critical, upper critical, and upper
+/// non-recoverable. Bit `n` of `mask` indicates whether `values[n]` is
+/// readable (when getting) or should be changed (when setting).
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RawThresholds {
+    pub mask: u8,
+    pub values: [u8; 6],
+}
+
+#[derive(Clone, Debug, thiserror::Error)]
 pub enum Error {
     #[error("{0}")]
     FreeIpmi(#[from] freeipmi::Error),
@@ -20,10 +58,12 @@ pub enum Error {
     BadResponseSize {
         expected: usize,
         actual: usize,
-    }
+    },
+    #[error("Simulated failure: {0}")]
+    Simulated(&'static str),
 }
 
-type Result<T, E = Error> = result::Result<T, E>;
+pub type Result<T, E = Error> = result::Result<T, E>;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum FanMode {
@@ -58,9 +98,173 @@ impl From<FanMode> for u8 {
     }
 }
 
+impl fmt::Display for FanMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Standard => f.write_str("standard"),
+            Self::Full => f.write_str("full"),
+            Self::Optimal => f.write_str("optimal"),
+            Self::HeavyIo => f.write_str("heavyio"),
+            Self::Unknown(n) => write!(f, "unknown({})", n),
+        }
+    }
+}
+
+impl FromStr for FanMode {
+    type Err = String;
+
+    /// Parse a fan mode name or a raw fan mode number.
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(Self::Standard),
+            "full" => Ok(Self::Full),
+            "optimal" => Ok(Self::Optimal),
+            "heavyio" => Ok(Self::HeavyIo),
+            _ => s.parse::<u8>()
+                .map(Self::from)
+                .map_err(|_| format!("invalid fan mode: {:?}", s)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FanMode {
+    fn deserialize<D>(deserializer: D) -> result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Operations on a BMC. This is implemented by [`Ipmi`] for real hardware and
+/// by [`SimulatedIpmi`] for testing.
+pub trait IpmiBackend: Send {
+    /// Get a shared counter of the number of failed IPMI commands and sensor
+    /// queries. The counter can be read without locking the backend.
+    fn error_counter(&self) -> Arc<AtomicU64>;
+
+    /// Get the current fan mode.
+    fn get_fan_mode(&mut self) -> Result<FanMode>;
+
+    /// Set the fan mode.
+    fn set_fan_mode(&mut self, mode: FanMode) -> Result<()>;
+
+    /// Get the current duty cycle. The value should be in the range [0, 100],
+    /// but is not guaranteed as this function returns the raw value supplied by
+    /// the BMC.
+    fn get_duty_cycle(&mut self, zone: u8) -> Result<u8>;
+
+    /// Set the duty cycle. The value should be in the range [0, 100], but this
+    /// is not validated. The raw `dcycle` value will be sent to the BMC as-is.
+    fn set_duty_cycle(&mut self, zone: u8, dcycle: u8) -> Result<()>;
+
+    /// Get the thresholds of a sensor owned by the BMC.
+    fn get_sensor_thresholds(&mut self, sensor_number: u8) -> Result<RawThresholds>;
+
+    /// Set the thresholds of a sensor owned by the BMC. Only the thresholds
+    /// selected by the mask are changed. The raw values are sent to the BMC
+    /// as-is.
+    fn set_sensor_thresholds(
+        &mut self,
+        sensor_number: u8,
+        thresholds: &RawThresholds,
+    ) -> Result<()>;
+
+    /// Get readings for all temperature sensors. If an error occurs, no partial
+    /// results will be returned. If a temperature sensor has no reading, then
+    /// the value in the result will be [`None`].
+    fn get_temperature_readings(&mut self) -> Result<Vec<Sensor>>;
+
+    /// Get readings for all fan sensors. If an error occurs, no partial
+    /// results will be returned. If a fan sensor has no reading, then the
+    /// value in the result will be [`None`].
+    fn get_fan_readings(&mut self) -> Result<Vec<Sensor>>;
+
+    /// Get the SDR records of all fan sensors.
+    fn get_fan_records(&mut self) -> Result<Vec<SensorRecord>>;
+}
+
+/// Create the backend for the given session type.
+pub fn connect(st: &SessionType) -> Result<Box<dyn IpmiBackend>> {
+    match st {
+        SessionType::Simulated(bmc) => Ok(Box::new(SimulatedIpmi::new(bmc))),
+        _ => Ok(Box::new(Ipmi::new(st)?)),
+    }
+}
+
+/// Backend that passes reads through to another backend, but only logs
+/// writes. Nothing about the BMC's state is ever changed.
+pub struct DryRun {
+    name: String,
+    inner: Box<dyn IpmiBackend>,
+}
+
+impl DryRun {
+    pub fn new(name: &str, inner: Box<dyn IpmiBackend>) -> Self {
+        Self {
+            name: name.to_owned(),
+            inner,
+        }
+    }
+}
+
+impl IpmiBackend for DryRun {
+    fn error_counter(&self) -> Arc<AtomicU64> {
+        self.inner.error_counter()
+    }
+
+    fn get_fan_mode(&mut self) -> Result<FanMode> {
+        self.inner.get_fan_mode()
+    }
+
+    fn set_fan_mode(&mut self, mode: FanMode) -> Result<()> {
+        info!("[{}] Dry run: not setting fan mode to: {:?}", self.name, mode);
+        Ok(())
+    }
+
+    fn get_duty_cycle(&mut self, zone: u8) -> Result<u8> {
+        self.inner.get_duty_cycle(zone)
+    }
+
+    fn set_duty_cycle(&mut self, zone: u8, dcycle: u8) -> Result<()> {
+        info!("[{}] Dry run: not setting zone {} duty cycle to {}%", self.name, zone, dcycle);
+        Ok(())
+    }
+
+    fn get_sensor_thresholds(&mut self, sensor_number: u8) -> Result<RawThresholds> {
+        self.inner.get_sensor_thresholds(sensor_number)
+    }
+
+    fn set_sensor_thresholds(
+        &mut self,
+        sensor_number: u8,
+        _thresholds: &RawThresholds,
+    ) -> Result<()> {
+        info!("[{}] Dry run: not setting sensor {} thresholds", self.name, sensor_number);
+        Ok(())
+    }
+
+    fn get_temperature_readings(&mut self) -> Result<Vec<Sensor>> {
+        self.inner.get_temperature_readings()
+    }
+
+    fn get_fan_readings(&mut self) -> Result<Vec<Sensor>> {
+        self.inner.get_fan_readings()
+    }
+
+    fn get_fan_records(&mut self) -> Result<Vec<SensorRecord>> {
+        self.inner.get_fan_records()
+    }
+}
+
 const NET_FN_GENERIC: u8 = bindings::IPMI_NET_FN_OEM_SUPERMICRO_GENERIC_RQ as u8;
 const CMD_FAN_MODE: u8 = 0x45;
 const CMD_GENERIC_EXT: u8 = bindings::IPMI_CMD_OEM_SUPERMICRO_GENERIC_EXTENSION as u8;
+const NET_FN_SENSOR: u8 = bindings::IPMI_NET_FN_SENSOR_EVENT_RQ as u8;
+const CMD_GET_SENSOR_THRESHOLDS: u8 = bindings::IPMI_CMD_GET_SENSOR_THRESHOLDS as u8;
+const CMD_SET_SENSOR_THRESHOLDS: u8 = bindings::IPMI_CMD_SET_SENSOR_THRESHOLDS as u8;
 const DATA_DUTY_CYCLE: u8 = 0x66;
 const DATA_ACTION_READ: u8 = 0x0;
 const DATA_ACTION_WRITE: u8 = 0x1;
@@ -70,6 +274,8 @@ const DATA_ACTION_WRITE: u8 = 0x1;
 pub struct Ipmi {
     lfi: LfiSession,
     lim: LimSession,
+    /// Number of failed IPMI commands and sensor queries
+    errors: Arc<AtomicU64>,
 }
 
 impl Ipmi {
@@ -85,7 +291,16 @@ impl Ipmi {
         // This call is required, even if we're not loading a file
         lim.set_sensor_config_file(None)?;
 
-        Ok(Self { lfi, lim })
+        Ok(Self { lfi, lim, errors: Arc::default() })
+    }
+
+    /// Increment the error counter if the result is an error.
+    fn count_error<T>(&self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
     }
 
     /// Execute raw IPMI command and return the output. The output does not
@@ -102,20 +317,73 @@ impl Ipmi {
         trace!("Running IPMI command: net_fn={:02x}, command={:02x}, data={:02x?}",
                net_fn, command, data);
 
-        let response = self.lfi.raw_command(net_fn, command, data)?;
+        let result = self.lfi.raw_command(net_fn, command, data)
+            .map_err(Error::from)
+            .and_then(|response| {
+                if response.len() != expected_size {
+                    return Err(Error::BadResponseSize {
+                        expected: expected_size,
+                        actual: response.len(),
+                    });
+                }
 
-        if response.len() != expected_size {
-            return Err(Error::BadResponseSize {
-                expected: expected_size,
-                actual: response.len(),
+                Ok(response)
             });
+
+        self.count_error(result)
+    }
+
+    fn read_sensors(&mut self, sensor_type: SensorType) -> Result<Vec<Sensor>> {
+        let num_sensors = self.lim.sensor_readings(sensor_type)?;
+        trace!("Number of {:?} sensors: {}", sensor_type, num_sensors);
+
+        let mut result = Vec::with_capacity(num_sensors);
+
+        for _ in 0..num_sensors {
+            let (entity_id, entity_instance) = self.lim.read_entity()?;
+
+            result.push(Sensor {
+                record_id: self.lim.read_record_id()?,
+                name: self.lim.read_sensor_name()?,
+                entity_id,
+                entity_instance,
+                reading: self.lim.read_sensor()?,
+            });
+
+            self.lim.iterator_next()?;
         }
 
-        Ok(response)
+        trace!("{:?} sensors: {:#?}", sensor_type, result);
+
+        Ok(result)
     }
 
-    /// Get the current fan mode.
-    pub fn get_fan_mode(&mut self) -> Result<FanMode> {
+    fn read_sensor_records(&mut self, sensor_type: SensorType) -> Result<Vec<SensorRecord>> {
+        let num_sensors = self.lim.sensor_readings(sensor_type)?;
+        let mut result = Vec::with_capacity(num_sensors);
+
+        for _ in 0..num_sensors {
+            result.push(SensorRecord {
+                name: self.lim.read_sensor_name()?,
+                sensor_number: self.lim.read_sensor_number()?,
+                sdr: self.lim.read_sdr_record()?,
+            });
+
+            self.lim.iterator_next()?;
+        }
+
+        trace!("{:?} sensor records: {:02x?}", sensor_type, result);
+
+        Ok(result)
+    }
+}
+
+impl IpmiBackend for Ipmi {
+    fn error_counter(&self) -> Arc<AtomicU64> {
+        self.errors.clone()
+    }
+
+    fn get_fan_mode(&mut self) -> Result<FanMode> {
         let response = self.execute(
             NET_FN_GENERIC,
             CMD_FAN_MODE,
@@ -126,8 +394,7 @@ impl Ipmi {
         Ok(FanMode::from(response[0]))
     }
 
-    /// Set the fan mode.
-    pub fn set_fan_mode(&mut self, mode: FanMode) -> Result<()> {
+    fn set_fan_mode(&mut self, mode: FanMode) -> Result<()> {
         self.execute(
             NET_FN_GENERIC,
             CMD_FAN_MODE,
@@ -141,10 +408,7 @@ impl Ipmi {
         Ok(())
     }
 
-    /// Get the current duty cycle. The valud should be in the range [0, 100],
-    /// but is not guaranteed as this function returns the raw value supplied by
-    /// the BMC.
-    pub fn get_duty_cycle(&mut self, zone: u8) -> Result<u8> {
+    fn get_duty_cycle(&mut self, zone: u8) -> Result<u8> {
         let response = self.execute(
             NET_FN_GENERIC,
             CMD_GENERIC_EXT,
@@ -159,9 +423,7 @@ impl Ipmi {
         Ok(response[0])
     }
 
-    /// Set the duty cycle. The valud should be in the range [0, 100], but this
-    /// is not validated. The raw `dcycle` value will be sent to the BMC as-is.
-    pub fn set_duty_cycle(&mut self, zone: u8, dcycle: u8) -> Result<()> {
+    fn set_duty_cycle(&mut self, zone: u8, dcycle: u8) -> Result<()> {
         self.execute(
             NET_FN_GENERIC,
             CMD_GENERIC_EXT,
@@ -177,25 +439,50 @@ impl Ipmi {
         Ok(())
     }
 
-    /// Get readings for all temperature sensors. If an error occurs, no partial
-    /// results will be returned. If a temperature sensor has no reading, then
-    /// the value in the result will be [`None`].
-    pub fn get_temperature_readings(&mut self)
-        -> Result<HashMap<String, Option<SensorReading>>> {
-        let num_sensors = self.lim.temperature_sensor_readings()?;
-        trace!("Number of sensors: {}", num_sensors);
+    fn get_sensor_thresholds(&mut self, sensor_number: u8) -> Result<RawThresholds> {
+        let response = self.execute(
+            NET_FN_SENSOR,
+            CMD_GET_SENSOR_THRESHOLDS,
+            &[sensor_number],
+            7,
+        )?;
 
-        let mut result = HashMap::new();
+        let mut values = [0u8; 6];
+        values.copy_from_slice(&response[1..]);
 
-        for _ in 0..num_sensors {
-            result.insert(
-                self.lim.read_sensor_name()?,
-                self.lim.read_sensor()?,
-            );
+        Ok(RawThresholds { mask: response[0], values })
+    }
 
-            self.lim.iterator_next()?;
-        }
+    fn set_sensor_thresholds(
+        &mut self,
+        sensor_number: u8,
+        thresholds: &RawThresholds,
+    ) -> Result<()> {
+        let mut data = vec![sensor_number, thresholds.mask];
+        data.extend_from_slice(&thresholds.values);
 
-        Ok(result)
+        self.execute(
+            NET_FN_SENSOR,
+            CMD_SET_SENSOR_THRESHOLDS,
+            &data,
+            0,
+        )?;
+
+        Ok(())
+    }
+
+    fn get_temperature_readings(&mut self) -> Result<Vec<Sensor>> {
+        let result = self.read_sensors(SensorType::Temperature);
+        self.count_error(result)
+    }
+
+    fn get_fan_readings(&mut self) -> Result<Vec<Sensor>> {
+        let result = self.read_sensors(SensorType::Fan);
+        self.count_error(result)
+    }
+
+    fn get_fan_records(&mut self) -> Result<Vec<SensorRecord>> {
+        let result = self.read_sensor_records(SensorType::Fan);
+        self.count_error(result)
     }
 }
@@ -1,37 +1,64 @@
 mod bindings;
+mod calibrate;
+#[cfg(unix)]
+mod client;
 mod config;
+mod control;
 mod error;
+mod filter;
 mod freeipmi;
+mod metrics;
+#[cfg(unix)]
+mod notify;
+#[cfg(target_os = "linux")]
+mod nvme;
+mod record;
+#[cfg(unix)]
+mod server;
+mod simulate;
+mod simulated;
 mod source;
+mod status;
+mod threshold;
 mod ipmi;
 
 use {
     std::{
-        cmp::Reverse,
         collections::HashMap,
         env,
+        fs,
         io,
-        path::PathBuf,
+        mem,
+        path::{Path, PathBuf},
         process,
         sync::{
             Arc,
             atomic::{AtomicBool, Ordering},
             Mutex,
         },
+        time::{Duration, Instant},
         u8,
     },
-    clap::Parser,
-    log::{debug, error, info, trace},
+    clap::{Args, Parser, Subcommand, ValueEnum},
+    log::{debug, error, info, trace, warn},
     retry::retry_with_index,
     tokio::{
         task::{self, JoinSet},
-        time::sleep,
+        time::{sleep, timeout},
     },
 
-    config::{Aggregation, Config, load_config, SessionType, Step, Zone},
+    config::{
+        Aggregation, Config, FailurePolicy, FanThresholds, load_config, LogLevel, Password,
+        SessionType, StallDetection, Zone,
+    },
+    control::{Adjustment, ControllerState, Limiter},
     error::{Error, Result},
-    ipmi::{FanMode, Ipmi},
-    source::get_source_readings,
+    filter::Smoother,
+    ipmi::{DryRun, FanMode, IpmiBackend, RawThresholds},
+    record::{Recorder, Tick},
+    source::{get_fan_speeds, get_source_readings, ReadingCache},
+    status::{ControlMode, ControlState, IpmiZoneStatus, SessionInfo, ZoneHandle},
+    threshold::{SavedThresholds, ThresholdStore},
 };
 
 static LOGGING_INITIALIZED: AtomicBool = AtomicBool::new(false);
@@ -63,53 +90,399 @@ async fn interrupted() -> io::Result<()> {
     Ok(())
 }
 
+#[cfg(unix)]
+async fn hangup() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    signal(SignalKind::hangup())?.recv().await;
+
+    Ok(())
+}
+
+#[cfg(windows)]
+async fn hangup() -> io::Result<()> {
+    // There is no equivalent of SIGHUP
+    std::future::pending().await
+}
+
 struct IpmiSession {
-    /// Session name (for logging only)
+    /// Session name (for logging and status reporting only)
     name: String,
+    /// Remote hostname (for status reporting only)
+    hostname: Option<String>,
+    /// Session configuration (for detecting changes when reloading)
+    session_type: SessionType,
     /// IPMI session
-    ipmi: Arc<Mutex<Ipmi>>,
+    ipmi: Arc<Mutex<Box<dyn IpmiBackend>>>,
     /// Original fan mode
     orig_fan_mode: FanMode,
+    /// Current fan mode (for status reporting only)
+    fan_mode: Arc<Mutex<FanMode>>,
     /// Set these zones to dcycle 100% before restoring original fan mode
-    restore_zones: Vec<u8>,
+    restore_zones: Mutex<Vec<u8>>,
+    /// Zones whose fan control was handed back to the BMC by the restore
+    /// failure policy
+    released_zones: Mutex<Vec<u8>>,
+    /// Original thresholds of fan sensors that were changed
+    orig_thresholds: Mutex<Vec<SavedThresholds>>,
+    /// Where to keep the original thresholds until they are restored
+    threshold_store: Option<ThresholdStore>,
+    /// Never change the BMC's state
+    dry_run: bool,
 }
 
 impl IpmiSession {
-    pub fn new<N, R>(name: N, st: &SessionType, restore_zones: R) -> Result<Self>
+    pub fn new<N, R>(name: N, st: &SessionType, restore_zones: R, dry_run: bool) -> Result<Self>
+    where
+        N: AsRef<str>,
+        R: IntoIterator<Item = u8>,
+    {
+        Self::with_backend(name, st, ipmi::connect(st)?, restore_zones, dry_run)
+    }
+
+    /// Create a session that uses an existing backend. `st` is only used for
+    /// detecting configuration changes. If `dry_run` is true, the backend is
+    /// only used for reading.
+    pub fn with_backend<N, R>(
+        name: N,
+        st: &SessionType,
+        ipmi: Box<dyn IpmiBackend>,
+        restore_zones: R,
+        dry_run: bool,
+    ) -> Result<Self>
     where
         N: AsRef<str>,
         R: IntoIterator<Item = u8>,
     {
-        let mut ipmi = Ipmi::new(st)?;
+        let mut ipmi = if dry_run {
+            Box::new(DryRun::new(name.as_ref(), ipmi))
+        } else {
+            ipmi
+        };
         let orig_fan_mode = ipmi.get_fan_mode()?;
 
         info!("[{}] Original fan mode: {:?}", name.as_ref(), orig_fan_mode);
 
-        if orig_fan_mode != FanMode::Full {
+        if dry_run {
+            info!("[{}] Dry run: leaving fan control to the BMC", name.as_ref());
+        } else if orig_fan_mode != FanMode::Full {
             info!("[{}] Setting fan mode to: {:?}", name.as_ref(), FanMode::Full);
             ipmi.set_fan_mode(FanMode::Full)?;
         }
 
+        let hostname = match st {
+            SessionType::Local => None,
+            SessionType::Remote { hostname, .. } => Some(hostname.clone()),
+            SessionType::Simulated(_) => None,
+        };
+
         Ok(Self {
             name: name.as_ref().to_owned(),
+            hostname,
+            session_type: st.clone(),
             ipmi: Arc::new(Mutex::new(ipmi)),
             orig_fan_mode,
-            restore_zones: restore_zones.into_iter().collect(),
+            fan_mode: Arc::new(Mutex::new(if dry_run { orig_fan_mode } else { FanMode::Full })),
+            restore_zones: Mutex::new(restore_zones.into_iter().collect()),
+            released_zones: Mutex::default(),
+            orig_thresholds: Mutex::default(),
+            threshold_store: None,
+            dry_run,
         })
     }
+
+    /// Keep the original fan thresholds in a file under `state_dir` until
+    /// they are restored. Without this, they are only kept in memory.
+    fn with_state_dir(mut self, state_dir: Option<&Path>) -> Self {
+        self.threshold_store = state_dir
+            .map(|d| ThresholdStore::new(d, &self.name, &self.session_type));
+        self
+    }
+
+    /// Replace the list of zones to restore on exit. Zones that are no longer
+    /// in the list are no longer controlled by anything, so they are set to
+    /// 100% duty cycle immediately. Failures are logged, but otherwise
+    /// ignored.
+    fn set_restore_zones(&self, zones: Vec<u8>) {
+        let mut restore_zones = self.restore_zones.lock().unwrap();
+
+        for z in restore_zones.iter().filter(|z| !zones.contains(z)) {
+            info!("[{}] Setting zone {} duty cycle to 100%", self.name, z);
+            if let Err(e) = self.ipmi.lock().unwrap().set_duty_cycle(*z, 100) {
+                error!("[{}] Failed to set duty cycle: {}", self.name, e);
+            }
+        }
+
+        *restore_zones = zones;
+    }
+
+    /// Whether the session configuration refers to the same BMC as this
+    /// session. Simulated BMCs are never shared.
+    fn controls_same_bmc(&self, st: &SessionType) -> bool {
+        match (&self.session_type, st) {
+            (SessionType::Local, SessionType::Local) => true,
+            (SessionType::Remote { hostname: a, .. }, SessionType::Remote { hostname: b, .. }) => {
+                a == b
+            }
+            _ => false,
+        }
+    }
+
+    /// Take over the original fan mode and fan thresholds saved by an old
+    /// session for the same BMC. Otherwise, this session would restore the
+    /// state that the old session set instead.
+    fn inherit(&mut self, old: &IpmiSession) {
+        self.orig_fan_mode = old.orig_fan_mode;
+        *self.orig_thresholds.get_mut().unwrap() = old.orig_thresholds.lock().unwrap().clone();
+    }
+
+    /// Forget everything that would be restored when the session is dropped.
+    /// This is for when another session for the same BMC is responsible for
+    /// restoring it instead.
+    fn disarm(&mut self) {
+        self.restore_zones.get_mut().unwrap().clear();
+        self.orig_thresholds.get_mut().unwrap().clear();
+        self.threshold_store = None;
+        self.orig_fan_mode = FanMode::Full;
+    }
+
+    /// Temporarily hand fan control back to the BMC for the given zones. This
+    /// sets the zones to 100% duty cycle and then restores the original fan
+    /// mode. Zones that were already released are left alone. The fan mode
+    /// applies to every zone in the session, so the other zones stop changing
+    /// their duty cycles until every released zone is taken back with
+    /// [`Self::reacquire`].
+    fn release(&self, zones: &[u8]) -> Result<()> {
+        let mut ipmi_lock = self.ipmi.lock().unwrap();
+        let mut released = self.released_zones.lock().unwrap();
+        let new: Vec<_> = zones.iter().filter(|z| !released.contains(z)).copied().collect();
+
+        if new.is_empty() {
+            return Ok(());
+        }
+
+        for z in &new {
+            info!("[{}] Setting zone {} duty cycle to 100%", self.name, z);
+            ipmi_lock.set_duty_cycle(*z, 100)?;
+        }
+
+        let mut fan_mode = self.fan_mode.lock().unwrap();
+        if *fan_mode != self.orig_fan_mode {
+            info!("[{}] Restoring fan mode to: {:?}", self.name, self.orig_fan_mode);
+            ipmi_lock.set_fan_mode(self.orig_fan_mode)?;
+            *fan_mode = self.orig_fan_mode;
+        }
+
+        released.extend(new);
+
+        Ok(())
+    }
+
+    /// Whether the BMC is in control of the fans because a zone was released
+    /// with [`Self::release`].
+    fn released(&self) -> bool {
+        self.orig_fan_mode != FanMode::Full && !self.released_zones.lock().unwrap().is_empty()
+    }
+
+    /// Set the fan thresholds configured for the session. The original
+    /// thresholds of a sensor are saved the first time it is changed so that
+    /// they can be restored on exit. If the session has a threshold store, the
+    /// originals are written to it before anything is changed, and originals
+    /// left there by a previous run that did not restore them are used instead
+    /// of the current thresholds. Sensors that are no longer configured are
+    /// restored immediately. Thresholds that differ from the config (eg.
+    /// because the BMC was reset) are logged.
+    fn set_fan_thresholds(&self, config: Option<&FanThresholds>) -> Result<()> {
+        if self.dry_run {
+            if config.is_some() {
+                info!("[{}] Dry run: not setting fan thresholds", self.name);
+            }
+            return Ok(());
+        }
+
+        Self::update_fan_thresholds(
+            &self.name,
+            &mut **self.ipmi.lock().unwrap(),
+            &mut self.orig_thresholds.lock().unwrap(),
+            self.threshold_store.as_ref(),
+            config,
+        )
+    }
+
+    /// Implementation of [`Self::set_fan_thresholds`] that works without a
+    /// session, so that the fan mode is left alone.
+    fn update_fan_thresholds(
+        name: &str,
+        ipmi: &mut dyn IpmiBackend,
+        saved: &mut Vec<SavedThresholds>,
+        store: Option<&ThresholdStore>,
+        config: Option<&FanThresholds>,
+    ) -> Result<()> {
+        let persist = |saved: &[SavedThresholds]| match store {
+            Some(store) => store.save(saved),
+            None => Ok(()),
+        };
+
+        if let Some(store) = store {
+            for p in store.load()? {
+                if !saved.iter().any(|s| s.sensor_number == p.sensor_number) {
+                    warn!("[{}] Using original fan {} thresholds left by a previous run: {:?}",
+                          name, p.name, store.path());
+                    saved.push(p);
+                }
+            }
+        }
+
+        let sensors = match config {
+            Some(c) => threshold::configured_sensors(ipmi, c)?,
+            None => vec![],
+        };
+
+        let (keep, restore): (Vec<_>, Vec<_>) = mem::take(saved)
+            .into_iter()
+            .partition(|s| sensors.iter().any(|f| f.sensor_number == s.sensor_number));
+        *saved = keep;
+        saved.extend(Self::restore_thresholds(name, ipmi, &restore));
+
+        let config = match config {
+            Some(c) => c,
+            None => return persist(saved),
+        };
+        let mut changes = vec![];
+
+        for sensor in &sensors {
+            let desired = sensor.desired(config)?;
+            let current = ipmi.get_sensor_thresholds(sensor.sensor_number)?;
+
+            if !saved.iter().any(|s| s.sensor_number == sensor.sensor_number) {
+                if desired.mask & !current.mask != 0 {
+                    warn!("[{}] Fan {} has unreadable thresholds that cannot be restored",
+                          name, sensor.name);
+                }
+
+                saved.push(SavedThresholds {
+                    name: sensor.name.clone(),
+                    sensor_number: sensor.sensor_number,
+                    thresholds: RawThresholds {
+                        mask: desired.mask & current.mask,
+                        values: current.values,
+                    },
+                });
+            }
+
+            let drifted = threshold::drift(&current, &desired);
+            if drifted.is_empty() {
+                debug!("[{}] Fan {} thresholds match the config", name, sensor.name);
+            } else {
+                info!("[{}] Fan {} thresholds differ from the config: {:?}",
+                      name, sensor.name, drifted);
+            }
+
+            changes.push((sensor, desired));
+        }
+
+        persist(saved)?;
+
+        for (sensor, desired) in changes {
+            info!("[{}] Setting fan {} thresholds: {:?}",
+                  name, sensor.name, Self::describe_thresholds(sensor, &desired));
+            ipmi.set_sensor_thresholds(sensor.sensor_number, &desired)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the original thresholds to the session's threshold store, if any.
+    fn persist_thresholds(&self, saved: &[SavedThresholds]) -> Result<()> {
+        match &self.threshold_store {
+            Some(store) => store.save(saved),
+            None => Ok(()),
+        }
+    }
+
+    /// List the thresholds selected by the mask as `(name, RPM)` pairs.
+    fn describe_thresholds(
+        sensor: &threshold::FanSensor,
+        thresholds: &RawThresholds,
+    ) -> Vec<(&'static str, String)> {
+        threshold::THRESHOLD_NAMES
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| thresholds.mask & (1 << i) != 0)
+            .map(|(i, n)| (*n, sensor.format(thresholds, i)))
+            .collect()
+    }
+
+    /// Restore saved fan thresholds. Failures are logged and the thresholds
+    /// that could not be restored are returned.
+    fn restore_thresholds(
+        name: &str,
+        ipmi: &mut dyn IpmiBackend,
+        saved: &[SavedThresholds],
+    ) -> Vec<SavedThresholds> {
+        let mut failed = vec![];
+
+        for s in saved {
+            info!("[{}] Restoring fan {} thresholds", name, s.name);
+            if let Err(e) = ipmi.set_sensor_thresholds(s.sensor_number, &s.thresholds) {
+                error!("[{}] Failed to restore fan thresholds: {}", name, e);
+                failed.push(s.clone());
+            }
+        }
+
+        failed
+    }
+
+    /// Take fan control back from the BMC for zones that were released with
+    /// [`Self::release`]. The fan mode is only set back to full once no zone in
+    /// the session is still released. Returns whether the fan mode was
+    /// changed, which may have reset the duty cycles of every zone.
+    fn reacquire(&self, ipmi: &mut dyn IpmiBackend, zones: &[u8]) -> Result<bool> {
+        let mut released = self.released_zones.lock().unwrap();
+        let remaining: Vec<_> = released.iter().filter(|z| !zones.contains(z)).copied().collect();
+        let mut fan_mode = self.fan_mode.lock().unwrap();
+        let changed = remaining.is_empty() && !self.dry_run && *fan_mode != FanMode::Full;
+
+        if changed {
+            info!("[{}] Setting fan mode to: {:?}", self.name, FanMode::Full);
+            ipmi.set_fan_mode(FanMode::Full)?;
+            *fan_mode = FanMode::Full;
+        }
+
+        *released = remaining;
+
+        Ok(changed)
+    }
 }
 
 impl Drop for IpmiSession {
     fn drop(&mut self) {
+        // Nothing was changed
+        if self.dry_run {
+            return;
+        }
+
         let mut ipmi_lock = self.ipmi.lock().unwrap();
 
-        for z in &self.restore_zones {
+        for z in self.restore_zones.get_mut().unwrap().iter() {
             info!("[{}] Setting zone {} duty cycle to 100%", self.name, z);
             if let Err(e) = ipmi_lock.set_duty_cycle(*z, 100) {
                 error!("[{}] Failed to set duty cycle: {}", self.name, e);
             }
         }
 
+        let failed = Self::restore_thresholds(&self.name, &mut **ipmi_lock,
+                                              self.orig_thresholds.get_mut().unwrap());
+        if let Some(store) = &self.threshold_store {
+            if !failed.is_empty() {
+                warn!("[{}] Keeping original fan thresholds that were not restored: {:?}",
+                      self.name, store.path());
+            }
+        }
+        if let Err(e) = self.persist_thresholds(&failed) {
+            error!("[{}] Failed to update saved fan thresholds: {}", self.name, e);
+        }
+
         if self.orig_fan_mode != FanMode::Full {
             info!("[{}] Restoring fan mode to: {:?}", self.name, self.orig_fan_mode);
             if let Err(e) = ipmi_lock.set_fan_mode(self.orig_fan_mode) {
@@ -119,51 +492,436 @@ impl Drop for IpmiSession {
     }
 }
 
+/// State for a zone that persists across fan update iterations.
+#[derive(Default)]
+struct ZoneState {
+    /// Controller state (eg. PID integrator)
+    controller: ControllerState,
+    /// Hysteresis and ramp rate limiting state
+    limiter: Limiter,
+    /// Temperature smoothing filter state
+    smoother: Smoother,
+    /// Number of consecutive failed fan update iterations
+    failures: u64,
+    /// Last good reading for each source
+    cache: ReadingCache,
+    /// Time of the last successful decision
+    last_decision: Option<Instant>,
+}
+
+/// Duty cycle chosen for an aggregated temperature reading.
+struct Decision {
+    /// Smoothed temperature
+    temp: f64,
+    /// Duty cycle chosen by the controller
+    target: u8,
+    /// Duty cycle after applying hysteresis and ramp rate limits
+    dcycle: u8,
+    adjustment: Adjustment,
+}
+
+impl ZoneState {
+    /// Run an aggregated temperature reading through the zone's smoothing
+    /// filter, controller, and limits. `applied` is the duty cycle that the
+    /// fans are currently running at, if known. If it differs from the previous
+    /// decision (eg. because of a manual override or the failsafe policy), the
+    /// controller and limits continue from there instead. `dt` is the number
+    /// of seconds elapsed since the previous decision.
+    fn decide(
+        &mut self,
+        zone_config: &Zone,
+        temp_raw: f64,
+        applied: Option<u8>,
+        dt: f64,
+    ) -> Decision {
+        let temp = self.smoother.apply(zone_config.smoothing.as_ref(), temp_raw);
+
+        if let Some(a) = applied.filter(|a| self.limiter.last() != Some(*a)) {
+            self.controller.track(zone_config, a);
+            self.limiter.track(temp, a);
+        }
+
+        let target = self.controller.dcycle(zone_config, temp, dt);
+        let (dcycle, adjustment) = self.limiter.apply(zone_config, temp, target);
+
+        Decision { temp, target, dcycle, adjustment }
+    }
+}
+
+/// IPMI session opened for a reloaded config.
+struct NewSession {
+    session: IpmiSession,
+    /// Name of the old session for the same BMC that this session takes over
+    /// from
+    replaces: Option<String>,
+}
+
 struct MainApp {
+    /// Path to the config file (for reloading)
+    config_path: PathBuf,
     config: Config,
     sessions: HashMap<String, Arc<IpmiSession>>,
+    /// State shared with the control socket for each zone
+    zones: Vec<Arc<ZoneHandle>>,
+    /// Controller state for each zone (kept across reloads)
+    zone_states: Vec<Arc<Mutex<ZoneState>>>,
+    /// Where to record each zone's fan update iterations
+    recorder: Option<Arc<Recorder>>,
+    /// Dry run requested on the command line, regardless of the config
+    force_dry_run: bool,
+    /// Service manager notifications (if running under systemd)
+    #[cfg(unix)]
+    notifier: Option<Arc<notify::Notifier>>,
 }
 
 impl MainApp {
-    fn new(config: Config) -> Result<Self> {
+    fn new(
+        config_path: PathBuf,
+        config: Config,
+        recorder: Option<Recorder>,
+        force_dry_run: bool,
+    ) -> Result<Self> {
+        let dry_run = force_dry_run || config.dry_run;
         let mut sessions = HashMap::new();
 
+        if dry_run {
+            warn!("Dry run: fan modes, duty cycles, and fan thresholds will not be changed");
+        }
+
+        let state_dir = config.state_dir();
+        if state_dir.is_none() && !config.fan_thresholds.is_empty() {
+            warn!("No state directory configured: original fan thresholds are only kept in memory");
+        }
+
         for (name, st) in &config.sessions.0 {
-            let restore_zones: Vec<_> = config.zones
-                .iter()
-                .filter(|z| &z.session.0 == name)
-                .flat_map(|z| &z.ipmi_zones)
-                .copied()
-                .collect();
+            let restore_zones = Self::session_zones(&config, name);
 
             // Don't waste resources if nothing would use the session
             if restore_zones.is_empty() {
                 continue;
             }
 
-            sessions.insert(name.clone(), Arc::new(
-                IpmiSession::new(name, &st.0, restore_zones)?));
+            let session = IpmiSession::new(name, &st.0, restore_zones, dry_run)?
+                .with_state_dir(state_dir.as_deref());
+            session.set_fan_thresholds(config.fan_thresholds.get(name))?;
+
+            sessions.insert(name.clone(), Arc::new(session));
         }
 
+        let zones = config.zones
+            .iter()
+            .map(|z| Arc::new(ZoneHandle::new(z)))
+            .collect();
+        let zone_states = config.zones
+            .iter()
+            .map(|_| Arc::default())
+            .collect();
+
         Ok(Self {
+            config_path,
             config,
             sessions,
+            zones,
+            zone_states,
+            recorder: recorder.map(Arc::new),
+            force_dry_run,
+            #[cfg(unix)]
+            notifier: notify::Notifier::from_env()?.map(Arc::new),
         })
     }
 
-    /// Run asynchronous loops for each zone. Returns when interrupted via
-    /// signal handlers (eg. ^C) or if a fatal error occurs.
-    async fn run(&mut self) -> Result<()> {
-        let mut loops = JoinSet::new();
+    /// Get all IPMI zones that use the specified session.
+    fn session_zones(config: &Config, name: &str) -> Vec<u8> {
+        config.zones
+            .iter()
+            .filter(|z| z.session.0 == name)
+            .flat_map(|z| &z.ipmi_zones)
+            .copied()
+            .collect()
+    }
+
+    /// Whether an existing session can be used as-is with a reloaded config.
+    /// Sessions are reopened if dry run was toggled.
+    fn can_keep(session: &IpmiSession, config: &Config, dry_run: bool) -> bool {
+        session.dry_run == dry_run
+            && config.sessions.0
+                .get(&session.name)
+                .is_some_and(|st| st.0 == session.session_type)
+            && !Self::session_zones(config, &session.name).is_empty()
+    }
+
+    /// Prepare the IPMI sessions for a reloaded config while the old tasks
+    /// are still running. Sessions that are kept have their fan thresholds
+    /// and fan mode updated. Sessions that changed are opened, taking over
+    /// from an old session for the same BMC, if there is one. If anything
+    /// fails, the newly opened sessions are closed without undoing changes
+    /// that the old sessions still rely on.
+    fn prepare_sessions(&self, config: &Config, dry_run: bool) -> Result<Vec<NewSession>> {
+        let state_dir = config.state_dir();
+        let mut opened: Vec<NewSession> = vec![];
+
+        let mut prepare = || -> Result<()> {
+            for (name, st) in &config.sessions.0 {
+                let restore_zones = Self::session_zones(config, name);
+
+                // Don't waste resources if nothing would use the session
+                if restore_zones.is_empty() {
+                    continue;
+                }
+
+                if let Some(s) = self.sessions.get(name)
+                    .filter(|s| Self::can_keep(s, config, dry_run))
+                {
+                    debug!("[{}] Keeping unchanged session", name);
+
+                    s.set_fan_thresholds(config.fan_thresholds.get(name))?;
+                    // Zones that handed control back to the BMC take it back
+                    // once they recover, except for those that are no longer
+                    // in the config
+                    let unused: Vec<_> = s.released_zones.lock().unwrap()
+                        .iter()
+                        .filter(|z| !restore_zones.contains(z))
+                        .copied()
+                        .collect();
+                    s.reacquire(&mut **s.ipmi.lock().unwrap(), &unused)?;
+                    continue;
+                }
+
+                let replaces = self.sessions
+                    .values()
+                    .filter(|s| !dry_run && !s.dry_run)
+                    .filter(|s| !Self::can_keep(s, config, dry_run))
+                    .filter(|s| !opened.iter().any(|n| n.replaces.as_ref() == Some(&s.name)))
+                    .find(|s| s.controls_same_bmc(&st.0));
+
+                info!("[{}] Opening session", name);
+
+                let mut session = IpmiSession::new(name, &st.0, restore_zones, dry_run)?
+                    .with_state_dir(state_dir.as_deref());
+                if let Some(old) = replaces {
+                    debug!("[{}] Taking over from session: {}", name, old.name);
+                    session.inherit(old);
+                }
+
+                let replaces = replaces.map(|s| s.name.clone());
+                opened.push(NewSession { session, replaces });
+                opened.last().unwrap().session.set_fan_thresholds(config.fan_thresholds.get(name))?;
+            }
+
+            Ok(())
+        };
+
+        if let Err(e) = prepare() {
+            for n in opened.iter_mut().filter(|n| n.replaces.is_some()) {
+                n.session.disarm();
+            }
+
+            return Err(e);
+        }
+
+        Ok(opened)
+    }
+
+    /// Reload the config file. If the new config fails to load or the IPMI
+    /// sessions cannot be prepared for it, an error is logged and the old
+    /// config continues to be used. Otherwise, all tasks are stopped and
+    /// respawned with the new config. IPMI sessions are only closed or opened
+    /// if their session configuration changed. Control modes are kept for
+    /// zones that still control the same IPMI zones with the same session.
+    /// The controller state is also kept if the rest of the zone's config is
+    /// unchanged.
+    async fn reload(&mut self, tasks: &mut JoinSet<Result<()>>) -> Result<()> {
+        info!("Reloading config: {:?}", self.config_path);
+
+        let config = match load_config(&self.config_path) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to reload config; keeping old config: {}", e);
+                return Ok(());
+            }
+        };
+
+        trace!("Loaded config: {:#?}", config);
+
+        #[cfg(unix)]
+        self.notify("RELOADING=1");
+
+        let dry_run = self.force_dry_run || config.dry_run;
+        let opened = match task::block_in_place(|| self.prepare_sessions(&config, dry_run)) {
+            Ok(o) => o,
+            Err(e) => {
+                error!("Failed to apply reloaded config; keeping old config: {}", e);
+
+                #[cfg(unix)]
+                self.notify("READY=1");
+
+                return Ok(());
+            }
+        };
+
+        // Stop all tasks so that nothing else holds references to the sessions
+        tasks.abort_all();
+        while let Some(r) = tasks.join_next().await {
+            match r {
+                Err(e) if e.is_cancelled() => {}
+                Err(e) => return Err(Error::LoopPanicked(e)),
+                Ok(r) => r?,
+            }
+        }
+
+        let mut old_sessions = mem::take(&mut self.sessions);
+
+        task::block_in_place(|| {
+            for NewSession { session, replaces } in opened {
+                if let Some(mut old) = replaces.and_then(|n| old_sessions.remove(&n)) {
+                    info!("[{}] Closing session", old.name);
+
+                    old.set_restore_zones(session.restore_zones.lock().unwrap().clone());
+                    match Arc::get_mut(&mut old) {
+                        Some(o) => o.disarm(),
+                        None => warn!("[{}] Session is still in use", old.name),
+                    }
+                }
+
+                self.sessions.insert(session.name.clone(), Arc::new(session));
+            }
+
+            for (name, s) in old_sessions {
+                if Self::can_keep(&s, &config, dry_run) {
+                    s.set_restore_zones(Self::session_zones(&config, &name));
+                    self.sessions.insert(name, s);
+                } else {
+                    info!("[{}] Closing session", name);
+                }
+            }
+        });
+
+        let mut old_states: Vec<_> = mem::take(&mut self.zone_states)
+            .into_iter()
+            .map(Some)
+            .collect();
+
+        (self.zones, self.zone_states) = config.zones
+            .iter()
+            .map(|z| {
+                let handle = ZoneHandle::new(z);
+                let mut state = None;
+
+                let matched = self.config.zones
+                    .iter()
+                    .enumerate()
+                    .position(|(i, old_z)| {
+                        old_z.session.0 == z.session.0
+                            && old_z.ipmi_zones == z.ipmi_zones
+                            && old_states[i].is_some()
+                    });
+
+                if let Some(i) = matched {
+                    *handle.mode.lock().unwrap() = self.zones[i].current_mode();
 
-        for zone_config in &self.config.zones {
+                    if self.config.zones[i] == *z {
+                        debug!("[{}] Keeping controller state for IPMI zones {:?}",
+                               z.session.0, z.ipmi_zones);
+                        state = old_states[i].take();
+                    }
+                }
+
+                (Arc::new(handle), state.unwrap_or_default())
+            })
+            .unzip();
+        self.config = config;
+
+        self.spawn_tasks(tasks);
+
+        info!("Reloaded config");
+
+        #[cfg(unix)]
+        self.notify("READY=1");
+
+        Ok(())
+    }
+
+    /// Get the state needed for status reporting and control.
+    fn control_state(&self) -> Arc<ControlState> {
+        Arc::new(ControlState {
+            sessions: self.sessions
+                .values()
+                .map(|s| SessionInfo {
+                    name: s.name.clone(),
+                    kind: (&s.session_type).into(),
+                    hostname: s.hostname.clone(),
+                    fan_mode: s.fan_mode.clone(),
+                    ipmi_errors: s.ipmi.lock().unwrap().error_counter(),
+                })
+                .collect(),
+            zones: self.zones.clone(),
+        })
+    }
+
+    /// Spawn loops for each zone along with the control socket and metrics
+    /// servers, if enabled.
+    fn spawn_tasks(&self, loops: &mut JoinSet<Result<()>>) {
+        let zones = self.config.zones.iter().zip(&self.zones).zip(&self.zone_states);
+
+        for ((zone_config, handle), state) in zones {
             loops.spawn(Self::zone_loop(
-                self.sessions.get_mut(&zone_config.session.0).unwrap().clone(),
+                self.sessions[&zone_config.session.0].clone(),
                 // Cloned since there's no structured concurrency support yet
                 Arc::new(zone_config.clone()),
+                handle.clone(),
+                state.clone(),
+                self.recorder.clone(),
             ));
         }
 
+        #[cfg(unix)]
+        if let Some(path) = &self.config.control_socket {
+            let state = self.control_state();
+            let path = PathBuf::from(path);
+
+            loops.spawn(async move { server::serve(&path, state).await });
+        }
+
+        if let Some(m) = &self.config.metrics {
+            let state = self.control_state();
+            let address = m.address;
+
+            loops.spawn(async move { metrics::serve(address, state).await });
+        }
+
+        #[cfg(unix)]
+        if let Some(notifier) = &self.notifier {
+            let notifier = notifier.clone();
+            let zones = self.config.zones
+                .iter()
+                .zip(&self.zones)
+                .map(|(z, h)| (h.clone(), notify::zone_deadline(z)))
+                .collect();
+
+            loops.spawn(notify::watchdog(notifier, zones));
+        }
+    }
+
+    /// Send a notification to the service manager, if there is one.
+    #[cfg(unix)]
+    fn notify(&self, state: &str) {
+        if let Some(notifier) = &self.notifier {
+            notifier.notify(state);
+        }
+    }
+
+    /// Run asynchronous loops for each zone. Returns when interrupted via
+    /// signal handlers (eg. ^C) or if a fatal error occurs. The config is
+    /// reloaded on SIGHUP.
+    async fn run(&mut self) -> Result<()> {
+        let mut loops = JoinSet::new();
+
+        self.spawn_tasks(&mut loops);
+
+        // Every session was switched to full fan mode in Self::new()
+        #[cfg(unix)]
+        self.notify("READY=1");
+
         let mut first_result = None;
 
         loop {
@@ -175,6 +933,16 @@ impl MainApp {
                     }
                     c.map_err(|e| Error::Io { path: "(interrupt)".into(), source: e })
                 }
+                // Reload requested, unless already shutting down
+                h = hangup(), if first_result.is_none() => {
+                    match h {
+                        Ok(()) => match self.reload(&mut loops).await {
+                            Ok(()) => continue,
+                            Err(e) => Err(e),
+                        },
+                        Err(e) => Err(Error::Io { path: "(hangup)".into(), source: e }),
+                    }
+                }
                 // Oh boy, this is an Option<Result<Result<()>, JoinError>>
                 r = loops.join_next() => {
                     match r {
@@ -196,6 +964,9 @@ impl MainApp {
 
             if first_result.is_none() {
                 first_result = Some(ret);
+
+                #[cfg(unix)]
+                self.notify("STOPPING=1");
             }
 
             // If tokio::select returned, then a loop exited or the program was
@@ -212,117 +983,321 @@ impl MainApp {
     }
 
     /// Main loop for a zone. The loop runs forever while the future is being
-    /// polled.
+    /// polled. If an iteration fails, the zone's failure policy is applied and
+    /// the loop only returns an error if the policy is to exit. The zone's
+    /// state is only locked during an iteration so that it can be handed to
+    /// the next loop when the config is reloaded.
     ///
     /// All communication with the IPMI is behind a mutex to avoid needing
     /// multiple IPMI sessions.
     async fn zone_loop(
         session: Arc<IpmiSession>,
         zone_config: Arc<Zone>,
+        handle: Arc<ZoneHandle>,
+        state: Arc<Mutex<ZoneState>>,
+        recorder: Option<Arc<Recorder>>,
     ) -> Result<()> {
         info!("[{}] Starting loop for IPMI zones {:?}",
               session.name, zone_config.ipmi_zones);
 
         loop {
-            let s = session.clone();
-            let z = zone_config.clone();
+            {
+                let s = session.clone();
+                let z = zone_config.clone();
+                let h = handle.clone();
+                let mut state = state.lock().unwrap();
+                let st = &mut *state;
+
+                let result = task::block_in_place(move || {
+                    Self::update_duty_cycle(s, z.as_ref(), h.as_ref(), st, &Instant::now)
+                });
 
-            task::block_in_place(move || {
-                Self::update_duty_cycle(s, z.as_ref())
-            })?;
+                // Failing to record should not affect fan control
+                let result = result.map(|tick| {
+                    if let Some(r) = &recorder {
+                        if let Err(e) = task::block_in_place(|| r.record(&tick)) {
+                            error!("[{}] Failed to record iteration: {}", session.name, e);
+                        }
+                    }
+                });
+
+                task::block_in_place(|| {
+                    Self::handle_result(&session, &zone_config, &handle, &mut state, result)
+                })?;
+            }
+
+            *handle.last_iteration.lock().unwrap() = Instant::now();
 
             sleep(zone_config.interval.to_duration()).await;
         }
     }
 
-    /// Update fan PWM duty cycle based on the CPU temperature
-    fn update_duty_cycle(session: Arc<IpmiSession>, zone_config: &Zone) -> Result<()> {
-        let temp = Self::get_temp(session.ipmi.clone(), zone_config)?;
+    /// Apply the zone's failure policy based on the result of a fan update
+    /// iteration. The consecutive failure counter is cleared once an iteration
+    /// succeeds again. With the exit policy, the failure is still counted and
+    /// logged before the error is returned.
+    fn handle_result(
+        session: &IpmiSession,
+        zone_config: &Zone,
+        handle: &ZoneHandle,
+        state: &mut ZoneState,
+        result: Result<()>,
+    ) -> Result<()> {
+        {
+            let mut status = handle.status.lock().unwrap();
 
-        let result = zone_config.steps.binary_search_by(|s| s.temp.cmp(&temp));
-        // Index of first step >= the current temperature (if exists)
-        let above_index = match result {
-            Ok(i) => Some(i),
-            Err(i) if i == zone_config.steps.len() => None,
-            Err(i) => Some(i),
-        };
-        // Index of first step < the current temperature (if exists)
-        let below_index = match above_index {
-            Some(0) => None,
-            Some(i) => Some(i - 1),
-            None => None,
-        };
-        // If step above doesn't exist, use last step's dcycle or 100%
-        let above_step = match above_index {
-            Some(i) => zone_config.steps[i],
-            None => {
-                let dcycle = zone_config.steps.last()
-                    .map_or(100, |s| s.dcycle);
-
-                Step {
-                    temp,
-                    dcycle,
+            status.failures = if result.is_ok() { 0 } else { state.failures + 1 };
+            if let Err(e) = &result {
+                if matches!(e, Error::RetriesFailed { .. }) {
+                    status.retries_exhausted += 1;
                 }
+                status.last_error = Some(e.to_string());
             }
-        };
-        // If step below doesn't exist, use same step as step above
-        let below_step = match below_index {
-            Some(i) => zone_config.steps[i],
-            None => above_step,
-        };
+            for (i, s) in status.sources.iter_mut().enumerate() {
+                s.errors = state.cache.error_count(i);
+            }
+        }
 
-        let dcycle_new = if below_step.temp == above_step.temp {
-            below_step.dcycle
-        } else {
-            // Linearly scale the dcycle
-            (u32::from(temp - below_step.temp)
-                * u32::from(above_step.dcycle - below_step.dcycle)
-                / u32::from(above_step.temp - below_step.temp)
-                + u32::from(below_step.dcycle)) as u8
+        let e = match result {
+            Ok(()) => {
+                if state.failures > 0 {
+                    info!("[{}] Zones {:?} recovered after {} consecutive failure(s)",
+                          session.name, zone_config.ipmi_zones, state.failures);
+                    state.failures = 0;
+                }
+
+                return Ok(());
+            }
+            Err(e) => e,
         };
 
-        let mut ipmi_lock = session.ipmi.lock().unwrap();
+        state.failures += 1;
 
-        for z in &zone_config.ipmi_zones {
-            let dcycle_cur = ipmi_lock.get_duty_cycle(*z)?;
+        warn!("[{}] Failed to update zones {:?} ({} consecutive failure(s)): {}",
+              session.name, zone_config.ipmi_zones, state.failures, e);
+
+        match zone_config.on_failure {
+            FailurePolicy::Exit => return Err(e),
+            FailurePolicy::Failsafe => {
+                let mut ipmi_lock = session.ipmi.lock().unwrap();
 
-            debug!("[{}] Zone {}: zone_temp={}C, dcycle_cur={}%, dcycle_new={}%",
-                   session.name, z, temp, dcycle_cur, dcycle_new);
+                for z in &zone_config.ipmi_zones {
+                    warn!("[{}] Setting zone {} to failsafe duty cycle: {}%",
+                          session.name, z, zone_config.failsafe_dcycle.0);
 
-            if dcycle_new != dcycle_cur {
-                ipmi_lock.set_duty_cycle(*z, dcycle_new)?;
+                    if let Err(e) = ipmi_lock.set_duty_cycle(*z, zone_config.failsafe_dcycle.0) {
+                        error!("[{}] Failed to set duty cycle: {}", session.name, e);
+                    }
+                }
+            }
+            FailurePolicy::Restore => {
+                // This does nothing if control was already handed back, so a
+                // failed attempt is retried on the next failure
+                if let Err(e) = session.release(&zone_config.ipmi_zones) {
+                    error!("[{}] Failed to restore fan control to BMC: {}",
+                           session.name, e);
+                }
             }
         }
 
         Ok(())
     }
 
-    /// Get temperature sensor value in degrees Celsius using the zone's
-    /// data aggregation method.
-    fn get_temp(ipmi: Arc<Mutex<Ipmi>>, zone_config: &Zone) -> Result<u8> {
-        let mut readings = retry_with_index(zone_config.retry_iter(), move |i| {
-            trace!("Querying sources for zones {:?} (attempt {}/{})",
+    /// Update fan PWM duty cycle based on the CPU temperature. The zone's
+    /// control mode determines whether the duty cycle chosen by the controller
+    /// is actually used. The zone's status is updated if no error occurs and
+    /// the inputs and outputs of the iteration are returned for recording.
+    /// `clock` returns the current time, which is used for measuring how much
+    /// time has passed between the readings of consecutive iterations.
+    fn update_duty_cycle(
+        session: Arc<IpmiSession>,
+        zone_config: &Zone,
+        handle: &ZoneHandle,
+        state: &mut ZoneState,
+        clock: &dyn Fn() -> Instant,
+    ) -> Result<Tick> {
+        let (readings, temp_raw) = Self::get_temp(
+            session.ipmi.clone(), zone_config, &mut state.cache)?;
+        let now = clock();
+
+        let mut ipmi_lock = session.ipmi.lock().unwrap();
+
+        let mut dcycles_cur = vec![];
+        for z in &zone_config.ipmi_zones {
+            dcycles_cur.push(ipmi_lock.get_duty_cycle(*z)?);
+        }
+
+        // In dry run mode or while a zone of the session is released, the fans
+        // are controlled by the BMC, so the decisions should continue from the
+        // previous decision instead. There is always at least one IPMI zone.
+        let bmc_control = session.dry_run || session.released();
+        let applied = Some(*dcycles_cur.iter().max().unwrap()).filter(|_| !bmc_control);
+        // Retries and slow sources can make iterations take longer than the
+        // interval
+        let dt = state.last_decision
+            .map_or(zone_config.interval.to_duration(), |t| now.saturating_duration_since(t))
+            .as_secs_f64();
+        let Decision { temp, target: dcycle_target, dcycle: dcycle_auto, adjustment } =
+            state.decide(zone_config, temp_raw, applied, dt);
+        state.last_decision = Some(now);
+
+        let (dcycle_new, note) = match handle.current_mode() {
+            ControlMode::Auto => {
+                let note = match adjustment {
+                    Adjustment::None => String::new(),
+                    Adjustment::Suppressed => format!(
+                        " (decrease to {}% suppressed by hysteresis)", dcycle_target),
+                    Adjustment::Clamped => format!(
+                        " (change to {}% clamped by ramp rate limit)", dcycle_target),
+                };
+
+                (Some(dcycle_auto), note)
+            }
+            ControlMode::Paused => (None, " (paused)".to_owned()),
+            ControlMode::Manual { dcycle, .. } => {
+                (Some(dcycle), " (manual override)".to_owned())
+            }
+        };
+
+        let mut actual_dcycles = vec![];
+
+        if let Some(stall) = &zone_config.stall_detection {
+            Self::check_stall(&session, stall, &mut **ipmi_lock, handle, &dcycles_cur)?;
+        }
+
+        // If the zone was released, it recovered. Control is taken back before
+        // anything is written because changing the fan mode may reset the duty
+        // cycles. Another zone of the session may still be released though.
+        let reset = session.reacquire(&mut **ipmi_lock, &zone_config.ipmi_zones)?;
+        let released = session.released();
+
+        for (z, dcycle_cur) in zone_config.ipmi_zones.iter().zip(dcycles_cur) {
+            let dcycle = dcycle_new.unwrap_or(dcycle_cur);
+
+            debug!("[{}] Zone {}: zone_temp_raw={:.1}C, zone_temp={:.1}C, dcycle_cur={}%, dcycle_new={}%{}",
+                   session.name, z, temp_raw, temp, dcycle_cur, dcycle, note);
+
+            if session.dry_run {
+                info!("[{}] Zone {}: dry run: zone_temp={:.1}C, dcycle_cur={}%, would set dcycle={}%{}",
+                      session.name, z, temp, dcycle_cur, dcycle, note);
+                actual_dcycles.push(IpmiZoneStatus { zone: *z, dcycle: dcycle_cur });
+                continue;
+            } else if released {
+                info!("[{}] Zone {}: fan control released to BMC: zone_temp={:.1}C, dcycle_cur={}%, would set dcycle={}%{}",
+                      session.name, z, temp, dcycle_cur, dcycle, note);
+                actual_dcycles.push(IpmiZoneStatus { zone: *z, dcycle: dcycle_cur });
+                continue;
+            }
+
+            if dcycle != dcycle_cur || reset {
+                ipmi_lock.set_duty_cycle(*z, dcycle)?;
+            }
+
+            actual_dcycles.push(IpmiZoneStatus { zone: *z, dcycle });
+        }
+
+        drop(ipmi_lock);
+
+        let mut status = handle.status.lock().unwrap();
+        status.temp_raw = Some(temp_raw);
+        status.temp = Some(temp);
+        for (s, t) in status.sources.iter_mut().zip(&readings) {
+            s.temp = t.as_ref().and_then(|r| r.iter().copied().max_by(f64::total_cmp));
+        }
+        status.dcycle = Some(dcycle_auto);
+        status.actual_dcycles = actual_dcycles.clone();
+        status.fan_mode = Some(session.fan_mode.lock().unwrap().to_string());
+
+        Ok(Tick::new(&session.name, &zone_config.ipmi_zones, readings, temp_raw, actual_dcycles))
+    }
+
+    /// Check the fans monitored by the zone's stall detection. If any fan has
+    /// no reading, reports 0 RPM, or reports less than the minimum speed while
+    /// every IPMI zone's current duty cycle is above the floor, an error is
+    /// returned before the duty cycle is changed. The fan speeds are recorded
+    /// in the zone's status either way.
+    fn check_stall(
+        session: &IpmiSession,
+        stall: &StallDetection,
+        ipmi: &mut dyn IpmiBackend,
+        handle: &ZoneHandle,
+        dcycles_cur: &[u8],
+    ) -> Result<()> {
+        let speeds = get_fan_speeds(ipmi, &stall.fans)?;
+
+        {
+            let mut status = handle.status.lock().unwrap();
+            for (f, rpm) in status.fans.iter_mut().zip(&speeds) {
+                f.rpm = *rpm;
+            }
+        }
+
+        // There is always at least one IPMI zone
+        let dcycle = dcycles_cur.iter().copied().min().unwrap();
+        if dcycle <= stall.min_dcycle {
+            return Ok(());
+        }
+
+        let stalled: Vec<_> = stall.fans
+            .iter()
+            .zip(&speeds)
+            .filter(|(_, rpm)| match rpm {
+                Some(r) => *r <= 0.0 || *r < stall.min_rpm,
+                None => true,
+            })
+            .map(|(f, _)| f.clone())
+            .collect();
+
+        if stalled.is_empty() {
+            return Ok(());
+        }
+
+        error!("[{}] Fan(s) {:?} stalled at {}% duty cycle: {:?} RPM (minimum: {} RPM)",
+               session.name, stalled, dcycle, speeds, stall.min_rpm);
+
+        Err(Error::FanStalled { fans: stalled, dcycle })
+    }
+
+    /// Get temperature sensor value in degrees Celsius using the zone's
+    /// data aggregation method. The individual source readings are returned
+    /// as well. Sources with multiple readings contribute all of them to the
+    /// aggregation.
+    fn get_temp(
+        ipmi: Arc<Mutex<Box<dyn IpmiBackend>>>,
+        zone_config: &Zone,
+        cache: &mut ReadingCache,
+    ) -> Result<(Vec<Option<Vec<f64>>>, f64)> {
+        let source_readings = retry_with_index(zone_config.retry_iter(), move |i| {
+            trace!("Querying sources for zones {:?} (attempt {}/{})",
                    zone_config.ipmi_zones, i, zone_config.retries.0 + 1);
-            get_source_readings(ipmi.clone(), &zone_config.sources)
+            get_source_readings(ipmi.clone(), &zone_config.sources,
+                                zone_config.min_sources.0, cache)
         })?;
-        readings.sort_by_key(|r| Reverse(*r));
 
-        // The source list is guaranteed to never be empty so if no error
-        // occurs, there will always be an equal number of readings
+        let temp = Self::aggregate(zone_config, &source_readings);
+
+        Ok((source_readings, temp))
+    }
+
+    /// Combine the source readings using the zone's data aggregation method.
+    /// There must be at least one reading.
+    fn aggregate(zone_config: &Zone, source_readings: &[Option<Vec<f64>>]) -> f64 {
+        let mut readings: Vec<_> = source_readings.iter().flatten().flatten().copied().collect();
+        readings.sort_by(|a, b| b.total_cmp(a));
+
+        // There is always at least one reading because min_sources is
+        // guaranteed to be non-zero
         match zone_config.aggregation {
-            Aggregation::Maximum => {
-                Ok(readings.first().copied().unwrap())
-            }
+            Aggregation::Maximum => readings.first().copied().unwrap(),
             Aggregation::Average { top } => {
-                let n = top.unwrap_or(readings.len());
+                let n = top.map_or(readings.len(), |t| t.min(readings.len()));
 
                 let sum = readings
                     .into_iter()
                     .take(n)
-                    .map(u32::from)
-                    .sum::<u32>();
+                    .sum::<f64>();
 
-                Ok((sum as f32 / n as f32) as u8)
+                sum / n as f64
             }
         }
     }
@@ -338,24 +1313,336 @@ fn bool_env(name: &str, default: bool) -> bool {
     }
 }
 
-#[derive(Debug, Parser)]
-struct Opt {
+/// Options for running the fan control daemon
+#[derive(Debug, Args)]
+struct RunOpt {
     /// Path to config file
     #[clap(short, long)]
     config: PathBuf,
+
+    /// Append each zone's source readings, aggregated temperature, and written
+    /// duty cycles to this file as JSON lines on every iteration
+    #[clap(long)]
+    record: Option<PathBuf>,
+
+    /// Read sources and log the duty cycles that would be chosen without
+    /// changing the fan mode, duty cycles, or fan thresholds
+    #[clap(long)]
+    dry_run: bool,
 }
 
-async fn main_wrapper() -> Result<()> {
-    let opt = Opt::parse();
-    let config = load_config(&opt.config)?;
+/// Options for finding the lowest safe duty cycle
+#[derive(Debug, Args)]
+struct CalibrateOpt {
+    /// Path to config file
+    #[clap(short, long)]
+    config: PathBuf,
+
+    /// Name of the IPMI session to use
+    #[clap(short, long, default_value = "default")]
+    session: String,
+
+    /// IPMI zone to calibrate (can be repeated) [default: every zone that uses
+    /// the session]
+    #[clap(short, long)]
+    zone: Vec<u8>,
+
+    /// Fan sensor that must keep spinning (can be repeated) [default: every
+    /// fan sensor]
+    #[clap(short, long)]
+    fan: Vec<String>,
+
+    /// Amount to decrease the duty cycle by at each step
+    #[clap(long, default_value_t = 5, value_parser = clap::value_parser!(u8).range(1..=100))]
+    step: u8,
+
+    /// Lowest duty cycle to try
+    #[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    min_dcycle: u8,
+
+    /// Seconds to wait for the fans to settle at each step
+    #[clap(long, default_value_t = 10)]
+    settle_secs: u64,
+
+    /// Fans reporting less than this speed are considered stalled
+    #[clap(long, default_value_t = 0.0)]
+    min_rpm: f64,
+
+    /// Margin to add to the lowest stable duty cycle for the recommendation
+    #[clap(long, default_value_t = 5, value_parser = clap::value_parser!(u8).range(0..=100))]
+    margin: u8,
+}
+
+/// Options for managing fan sensor thresholds
+#[derive(Debug, Args)]
+struct ThresholdsOpt {
+    /// Path to config file
+    #[clap(short, long)]
+    config: PathBuf,
+
+    /// Name of the IPMI session to use
+    #[clap(short, long, default_value = "default")]
+    session: String,
+
+    /// Set the thresholds from the config. The BMC keeps them until it is
+    /// reset or they are changed again. The original thresholds are saved in
+    /// the state directory, so the daemon restores them on exit.
+    #[clap(long)]
+    apply: bool,
+}
 
+/// Options for simulating a zone against a thermal model
+#[derive(Debug, Args)]
+struct SimulateOpt {
+    /// Path to config file
+    #[clap(short, long)]
+    config: PathBuf,
+
+    /// Index of the zone to simulate in the config file
+    #[clap(short, long, default_value_t = 0)]
+    zone: usize,
+
+    /// Path to thermal model file
+    #[clap(short, long)]
+    model: PathBuf,
+
+    /// Path to write the CSV timeline to [default: stdout]
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+/// Options for replaying a recording against a config
+#[derive(Debug, Args)]
+struct ReplayOpt {
+    /// Path to config file
+    #[clap(short, long)]
+    config: PathBuf,
+
+    /// Path to recording created with `run --record`
+    #[clap(short, long)]
+    recording: PathBuf,
+}
+
+/// Options for connecting to a BMC directly, either with a session from a
+/// config file or with inline credentials. The local BMC is used by default.
+#[derive(Debug, Args)]
+struct ConnectionOpt {
+    /// Path to config file containing the session
+    #[clap(short, long, global = true, conflicts_with = "hostname")]
+    config: Option<PathBuf>,
+
+    /// Name of the IPMI session to use from the config file [default: default]
+    #[clap(short, long, global = true, requires = "config")]
+    session: Option<String>,
+
+    /// Hostname of a remote BMC
+    #[clap(short = 'H', long, global = true, requires_all = &["username", "password"])]
+    hostname: Option<String>,
+
+    /// Username for the remote BMC
+    #[clap(short = 'U', long, global = true, requires = "hostname")]
+    username: Option<String>,
+
+    /// Password for the remote BMC
+    #[clap(short = 'P', long, global = true, requires = "hostname")]
+    password: Option<String>,
+
+    /// Path to the control socket of a running daemon to check for conflicts
+    /// [default: control_socket from the config file]
+    #[cfg(unix)]
+    #[clap(long, global = true)]
+    socket: Option<PathBuf>,
+}
+
+impl ConnectionOpt {
+    /// Load the config file, if any, and find the name and configuration of
+    /// the session to connect to.
+    fn resolve(&self) -> Result<(Option<Config>, String, SessionType)> {
+        if let Some(path) = &self.config {
+            let config = load_config(path)?;
+            let name = self.session.clone().unwrap_or_else(|| "default".to_owned());
+            let st = config.sessions.0.get(&name)
+                .ok_or_else(|| Error::SessionNotFound(name.clone()))?
+                .0
+                .clone();
+
+            return Ok((Some(config), name, st));
+        }
+
+        let st = match (&self.hostname, &self.username, &self.password) {
+            // Guaranteed by clap
+            (Some(h), Some(u), Some(p)) => SessionType::Remote {
+                hostname: h.clone(),
+                username: u.clone(),
+                password: Password(p.clone()),
+            },
+            _ => SessionType::Local,
+        };
+
+        Ok((None, "default".to_owned(), st))
+    }
+
+    /// Warn if a running daemon controls the same BMC, since it would override
+    /// any changes on its next iteration. Sessions from a config file are also
+    /// matched by name. The daemon can only be found if its control socket is
+    /// known.
+    #[cfg(unix)]
+    async fn warn_if_controlled(&self, config: Option<&Config>, name: &str, st: &SessionType) {
+        let path = match (&self.socket, config.and_then(|c| c.control_socket.as_ref())) {
+            (Some(p), _) => p.clone(),
+            (None, Some(p)) => PathBuf::from(p),
+            (None, None) => {
+                warn!("[{}] Cannot check for a running daemon controlling this BMC: \
+                       no control socket configured (use --socket)", name);
+                return;
+            }
+        };
+
+        let status = match timeout(Duration::from_secs(2), client::get_status(&path)).await {
+            Ok(Ok(s)) => s,
+            Ok(Err(e)) => {
+                debug!("No running daemon found: {}", e);
+                return;
+            }
+            Err(_) => {
+                warn!("[{}] Cannot check for a running daemon controlling this BMC: \
+                       timed out querying status: {:?}", name, path);
+                return;
+            }
+        };
+
+        let name_match = config.map(|_| name);
+
+        for s in status.sessions.iter().filter(|s| s.controls_same_bmc(name_match, st)) {
+            warn!("[{}] A running daemon controls this BMC (session: {}) and may override changes",
+                  name, s.name);
+        }
+    }
+}
+
+/// Fan modes that can be set from the command line
+#[derive(Clone, Copy, Debug, ValueEnum)]
+#[clap(rename_all = "lower")]
+enum FanModeArg {
+    Standard,
+    Full,
+    Optimal,
+    HeavyIo,
+}
+
+impl From<FanModeArg> for FanMode {
+    fn from(mode: FanModeArg) -> Self {
+        match mode {
+            FanModeArg::Standard => Self::Standard,
+            FanModeArg::Full => Self::Full,
+            FanModeArg::Optimal => Self::Optimal,
+            FanModeArg::HeavyIo => Self::HeavyIo,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum FanModeAction {
+    /// Print the current fan mode
+    Get,
+    /// Set the fan mode
+    Set {
+        #[clap(value_enum)]
+        mode: FanModeArg,
+    },
+}
+
+/// Options for getting or setting the fan mode
+#[derive(Debug, Args)]
+struct FanModeOpt {
+    #[clap(flatten)]
+    connection: ConnectionOpt,
+
+    #[clap(subcommand)]
+    action: FanModeAction,
+}
+
+#[derive(Debug, Subcommand)]
+enum DutyAction {
+    /// Print the current duty cycle of an IPMI zone
+    Get {
+        /// IPMI zone
+        zone: u8,
+    },
+    /// Set the duty cycle of an IPMI zone. This only has an effect while the
+    /// fan mode is `full`.
+    Set {
+        /// IPMI zone
+        zone: u8,
+        /// Duty cycle in percent
+        #[clap(value_parser = clap::value_parser!(u8).range(0..=100))]
+        dcycle: u8,
+    },
+}
+
+/// Options for getting or setting a duty cycle
+#[derive(Debug, Args)]
+struct DutyOpt {
+    #[clap(flatten)]
+    connection: ConnectionOpt,
+
+    #[clap(subcommand)]
+    action: DutyAction,
+}
+
+/// Options for querying the running daemon
+#[cfg(unix)]
+#[derive(Debug, Args)]
+struct StatusOpt {
+    /// Path to config file containing the control socket path
+    #[clap(short, long, required_unless_present = "socket")]
+    config: Option<PathBuf>,
+
+    /// Path to control socket
+    #[clap(short, long, conflicts_with = "config")]
+    socket: Option<PathBuf>,
+
+    /// Print the raw status as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the fan control daemon
+    Run(RunOpt),
+    /// Find the lowest duty cycle that keeps every fan spinning
+    Calibrate(CalibrateOpt),
+    /// Show or set fan sensor thresholds
+    Thresholds(ThresholdsOpt),
+    /// Simulate a zone against a thermal model and print a CSV timeline
+    Simulate(SimulateOpt),
+    /// Replay a recording and show where the config would choose a different
+    /// duty cycle
+    Replay(ReplayOpt),
+    /// Get or set the fan mode directly
+    FanMode(FanModeOpt),
+    /// Get or set the duty cycle of an IPMI zone directly
+    Duty(DutyOpt),
+    /// Show the status of the running daemon
+    #[cfg(unix)]
+    Status(StatusOpt),
+}
+
+#[derive(Debug, Parser)]
+struct Opt {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+fn init_logging(log_level: LogLevel) {
     let pkg_name = env!("CARGO_PKG_NAME").replace('-', "_");
 
     // RUST_LOG has higher precedence than the config file option because it has
     // more flexibility (eg. turning on logs for dependencies)
     let mut builder = env_logger::Builder::from_env(
         env_logger::Env::default()
-            .default_filter_or(format!("{}={}", pkg_name, config.log_level)));
+            .default_filter_or(format!("{}={}", pkg_name, log_level)));
 
     // Don't include timestamps in the log if requested (eg. if logs are going
     // to something like journald that already has timestamps)
@@ -365,13 +1652,241 @@ async fn main_wrapper() -> Result<()> {
 
     builder.init();
     LOGGING_INITIALIZED.store(true, Ordering::SeqCst);
+}
+
+async fn run_subcommand(opt: &RunOpt) -> Result<()> {
+    let config = load_config(&opt.config)?;
+
+    init_logging(config.log_level);
 
     trace!("Loaded config: {:#?}", config);
 
-    let mut app = MainApp::new(config)?;
+    // Fail before taking control of the fans if another daemon is running
+    #[cfg(unix)]
+    if let Some(path) = &config.control_socket {
+        server::remove_stale_socket(path.as_ref())?;
+    }
+
+    let recorder = opt.record.as_deref().map(Recorder::open).transpose()?;
+
+    let mut app = MainApp::new(opt.config.clone(), config, recorder, opt.dry_run)?;
     app.run().await
 }
 
+async fn calibrate_subcommand(opt: &CalibrateOpt) -> Result<()> {
+    let config = load_config(&opt.config)?;
+
+    init_logging(config.log_level);
+
+    let st = config.sessions.0.get(&opt.session)
+        .ok_or_else(|| Error::SessionNotFound(opt.session.clone()))?;
+    let zones = if opt.zone.is_empty() {
+        MainApp::session_zones(&config, &opt.session)
+    } else {
+        opt.zone.clone()
+    };
+
+    if zones.is_empty() {
+        return Err(Error::NoZones(opt.session.clone()));
+    }
+
+    // Dropping the session sets the zones back to 100% and restores the
+    // original fan mode, including when interrupted
+    let session = IpmiSession::new(&opt.session, &st.0, zones.iter().copied(), false)?;
+    // Keep the BMC from taking over if the fans go below its thresholds
+    session.set_fan_thresholds(config.fan_thresholds.get(&opt.session))?;
+    let fans = if opt.fan.is_empty() {
+        calibrate::all_fans(&session)?
+    } else {
+        opt.fan.clone()
+    };
+
+    let params = calibrate::Params {
+        fans,
+        min_dcycle: opt.min_dcycle,
+        step: opt.step,
+        settle: Duration::from_secs(opt.settle_secs),
+        min_rpm: opt.min_rpm,
+        margin: opt.margin,
+    };
+
+    let calibrate_zones = async {
+        for z in &zones {
+            let calibration = calibrate::calibrate_zone(&session, *z, &params).await?;
+            calibrate::print_report(&calibration, &params);
+        }
+
+        Ok(())
+    };
+
+    tokio::select! {
+        c = interrupted() => {
+            if c.is_ok() {
+                info!("Interrupted");
+            }
+            c.map_err(|e| Error::Io { path: "(interrupt)".into(), source: e })
+        }
+        r = calibrate_zones => r,
+    }
+}
+
+fn thresholds_subcommand(opt: &ThresholdsOpt) -> Result<()> {
+    let config = load_config(&opt.config)?;
+
+    init_logging(config.log_level);
+
+    let st = config.sessions.0.get(&opt.session)
+        .ok_or_else(|| Error::SessionNotFound(opt.session.clone()))?;
+    let fan_thresholds = config.fan_thresholds.get(&opt.session);
+
+    // Don't use IpmiSession because the fan mode should not be changed
+    let mut ipmi = ipmi::connect(&st.0)?;
+
+    let read_all = |ipmi: &mut dyn IpmiBackend| -> Result<Vec<_>> {
+        let mut result = vec![];
+
+        for sensor in threshold::fan_sensors(ipmi)? {
+            let current = ipmi.get_sensor_thresholds(sensor.sensor_number)?;
+            result.push((sensor, current));
+        }
+
+        Ok(result)
+    };
+
+    let sensors = read_all(&mut *ipmi)?;
+    if sensors.is_empty() {
+        return Err(Error::NoFanSensors);
+    }
+
+    threshold::print_thresholds(&sensors, fan_thresholds);
+
+    if opt.apply {
+        let fan_thresholds = fan_thresholds
+            .ok_or_else(|| Error::NoFanThresholds(opt.session.clone()))?;
+
+        // Keep the originals so that the daemon restores them on exit
+        let state_dir = config.state_dir().ok_or(Error::NoStateDir)?;
+        let store = ThresholdStore::new(&state_dir, &opt.session, &st.0);
+
+        IpmiSession::update_fan_thresholds(&opt.session, &mut *ipmi, &mut vec![],
+                                           Some(&store), Some(fan_thresholds))?;
+
+        println!();
+        threshold::print_thresholds(&read_all(&mut *ipmi)?, Some(fan_thresholds));
+    }
+
+    Ok(())
+}
+
+fn simulate_subcommand(opt: &SimulateOpt) -> Result<()> {
+    let config = load_config(&opt.config)?;
+
+    init_logging(config.log_level);
+
+    let zone_config = config.zones.get(opt.zone)
+        .ok_or_else(|| Error::ZoneNotFound(opt.zone))?;
+    let model = simulate::ThermalModel::load(&opt.model)?;
+
+    match &opt.output {
+        Some(path) => {
+            let mut file = fs::File::create(path)
+                .map_err(|e| Error::Io { path: path.clone(), source: e })?;
+            simulate::simulate(zone_config, &model, &mut file)
+        }
+        None => simulate::simulate(zone_config, &model, &mut io::stdout().lock()),
+    }
+}
+
+fn replay_subcommand(opt: &ReplayOpt) -> Result<()> {
+    let config = load_config(&opt.config)?;
+
+    init_logging(config.log_level);
+
+    record::replay(&config, &opt.recording, &mut io::stdout().lock())
+}
+
+/// Connect to the BMC for a direct control subcommand. The fan mode is not
+/// changed and nothing is restored afterwards. If `writing` is true, a warning
+/// is logged if a running daemon controls the same BMC.
+async fn connect_direct(
+    opt: &ConnectionOpt,
+    writing: bool,
+) -> Result<(String, Box<dyn IpmiBackend>)> {
+    let (config, name, st) = opt.resolve()?;
+
+    init_logging(config.as_ref().map_or_else(LogLevel::default, |c| c.log_level));
+
+    #[cfg(unix)]
+    if writing {
+        opt.warn_if_controlled(config.as_ref(), &name, &st).await;
+    }
+
+    Ok((name, ipmi::connect(&st)?))
+}
+
+async fn fan_mode_subcommand(opt: &FanModeOpt) -> Result<()> {
+    let writing = matches!(opt.action, FanModeAction::Set { .. });
+    let (name, mut ipmi) = connect_direct(&opt.connection, writing).await?;
+
+    match opt.action {
+        FanModeAction::Get => println!("{}", ipmi.get_fan_mode()?),
+        FanModeAction::Set { mode } => {
+            let mode = FanMode::from(mode);
+            info!("[{}] Setting fan mode to: {:?}", name, mode);
+            ipmi.set_fan_mode(mode)?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn duty_subcommand(opt: &DutyOpt) -> Result<()> {
+    let writing = matches!(opt.action, DutyAction::Set { .. });
+    let (name, mut ipmi) = connect_direct(&opt.connection, writing).await?;
+
+    match opt.action {
+        DutyAction::Get { zone } => println!("{}%", ipmi.get_duty_cycle(zone)?),
+        DutyAction::Set { zone, dcycle } => {
+            let fan_mode = ipmi.get_fan_mode()?;
+            if fan_mode != FanMode::Full {
+                warn!("[{}] Fan mode is {}, so the BMC may override the duty cycle",
+                      name, fan_mode);
+            }
+
+            info!("[{}] Setting zone {} duty cycle to {}%", name, zone, dcycle);
+            ipmi.set_duty_cycle(zone, dcycle)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn status_subcommand(opt: &StatusOpt) -> Result<()> {
+    let path = client::socket_path(opt.socket.as_deref(), opt.config.as_deref())?;
+    let status = client::get_status(&path).await?;
+
+    print!("{}", client::format_status(&status, opt.json)?);
+
+    Ok(())
+}
+
+async fn main_wrapper() -> Result<()> {
+    let opt = Opt::parse();
+
+    match &opt.command {
+        Command::Run(o) => run_subcommand(o).await,
+        Command::Calibrate(o) => calibrate_subcommand(o).await,
+        Command::Thresholds(o) => thresholds_subcommand(o),
+        Command::Simulate(o) => simulate_subcommand(o),
+        Command::Replay(o) => replay_subcommand(o),
+        Command::FanMode(o) => fan_mode_subcommand(o).await,
+        Command::Duty(o) => duty_subcommand(o).await,
+        #[cfg(unix)]
+        Command::Status(o) => status_subcommand(o).await,
+    }
+}
+
 #[tokio::main]
 async fn main() {
     match main_wrapper().await {
@@ -386,3 +1901,316 @@ async fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a config with a single zone that controls IPMI zones 0 and 1 of
+    /// a simulated BMC. The CPU temperature follows `temps`. `failures` is the
+    /// body of the simulated BMC's `failures` table and `zone` is appended to
+    /// the zone's definition.
+    fn config(temps: &[f64], failures: &str, zone: &str) -> Config {
+        let config = format!(r#"
+            [[zones]]
+            session = "sim"
+            ipmi_zones = [0, 1]
+            retries = 0
+            sources = [{{ type = "ipmi", sensor = "CPU Temp" }}]
+            steps = [{{ temp = 30, dcycle = 20 }}, {{ temp = 70, dcycle = 100 }}]
+            {zone}
+
+            [sessions.sim]
+            type = "simulated"
+            fan_mode = "optimal"
+            temperature_sensors = [{{ name = "CPU Temp", values = {temps:?} }}]
+            fan_sensors = [{{ name = "FAN1", values = [1200] }}]
+            failures = {{ {failures} }}
+
+            [fan_thresholds.sim]
+            fans = ["FAN1"]
+            lower_critical = 100
+        "#);
+
+        toml::from_str(&config).unwrap()
+    }
+
+    fn new_app(config: Config) -> MainApp {
+        MainApp::new(PathBuf::new(), config, None, false).unwrap()
+    }
+
+    /// Run one fan update iteration of the first zone, the same way that
+    /// [`MainApp::zone_loop`] does.
+    fn tick(app: &MainApp) -> Result<()> {
+        tick_zone(app, 0)
+    }
+
+    /// Run one fan update iteration of the zone at `index`.
+    fn tick_zone(app: &MainApp, index: usize) -> Result<()> {
+        let zone_config = &app.config.zones[index];
+        let session = app.sessions[&zone_config.session.0].clone();
+        let handle = &app.zones[index];
+        let mut state = app.zone_states[index].lock().unwrap();
+
+        let result = MainApp::update_duty_cycle(
+            session.clone(), zone_config, handle, &mut state, &Instant::now);
+
+        MainApp::handle_result(&session, zone_config, handle, &mut state, result.map(|_| ()))
+    }
+
+    fn duty_cycles(ipmi: &Mutex<Box<dyn IpmiBackend>>) -> [u8; 2] {
+        let mut ipmi = ipmi.lock().unwrap();
+
+        [ipmi.get_duty_cycle(0).unwrap(), ipmi.get_duty_cycle(1).unwrap()]
+    }
+
+    fn fan_mode(ipmi: &Mutex<Box<dyn IpmiBackend>>) -> FanMode {
+        ipmi.lock().unwrap().get_fan_mode().unwrap()
+    }
+
+    #[test]
+    fn duty_cycles_follow_curve() {
+        let app = new_app(config(&[40.0, 50.0, 60.0], "", ""));
+        let ipmi = app.sessions["sim"].ipmi.clone();
+
+        assert_eq!(fan_mode(&ipmi), FanMode::Full);
+
+        for expected in [40, 60, 80] {
+            tick(&app).unwrap();
+            assert_eq!(duty_cycles(&ipmi), [expected; 2]);
+        }
+    }
+
+    #[test]
+    fn manual_override_expiry() {
+        let app = new_app(config(&[40.0], "", ""));
+        let ipmi = app.sessions["sim"].ipmi.clone();
+
+        app.zones[0].set_manual(90, Some(Duration::from_millis(200)));
+        tick(&app).unwrap();
+        assert_eq!(duty_cycles(&ipmi), [90, 90]);
+
+        // The controller takes back control once the override expires
+        std::thread::sleep(Duration::from_millis(300));
+        tick(&app).unwrap();
+        assert_eq!(duty_cycles(&ipmi), [40, 40]);
+        assert!(matches!(app.zones[0].current_mode(), ControlMode::Auto));
+    }
+
+    #[test]
+    fn failsafe_policy() {
+        let app = new_app(config(
+            &[40.0],
+            "get_temperature_readings = [2]",
+            "on_failure = \"failsafe\"\nfailsafe_dcycle = 70",
+        ));
+        let ipmi = app.sessions["sim"].ipmi.clone();
+
+        tick(&app).unwrap();
+        assert_eq!(duty_cycles(&ipmi), [40, 40]);
+
+        tick(&app).unwrap();
+        assert_eq!(duty_cycles(&ipmi), [70, 70]);
+        assert_eq!(app.zones[0].status.lock().unwrap().failures, 1);
+
+        tick(&app).unwrap();
+        assert_eq!(duty_cycles(&ipmi), [40, 40]);
+        assert_eq!(app.zones[0].status.lock().unwrap().failures, 0);
+    }
+
+    #[test]
+    fn exit_policy() {
+        let app = new_app(config(&[40.0], "get_temperature_readings = [2]", ""));
+        let ipmi = app.sessions["sim"].ipmi.clone();
+
+        tick(&app).unwrap();
+        assert!(matches!(tick(&app), Err(Error::RetriesFailed { .. })));
+        assert_eq!(duty_cycles(&ipmi), [40, 40]);
+
+        // The final failure is still counted
+        assert_eq!(app.zone_states[0].lock().unwrap().failures, 1);
+        let status = app.zones[0].status.lock().unwrap();
+        assert_eq!(status.failures, 1);
+        assert_eq!(status.retries_exhausted, 1);
+        assert!(status.last_error.is_some());
+    }
+
+    #[test]
+    fn restore_policy() {
+        let app = new_app(config(
+            &[40.0],
+            "get_temperature_readings = [2, 3]",
+            "on_failure = \"restore\"",
+        ));
+        let ipmi = app.sessions["sim"].ipmi.clone();
+
+        tick(&app).unwrap();
+        assert_eq!(fan_mode(&ipmi), FanMode::Full);
+
+        for _ in 0..2 {
+            tick(&app).unwrap();
+            assert_eq!(fan_mode(&ipmi), FanMode::Optimal);
+            assert_eq!(duty_cycles(&ipmi), [100, 100]);
+        }
+
+        tick(&app).unwrap();
+        assert_eq!(fan_mode(&ipmi), FanMode::Full);
+        assert_eq!(duty_cycles(&ipmi), [40, 40]);
+    }
+
+    #[test]
+    fn restore_policy_reacquire_failure() {
+        // The first call sets the fan mode to full when the session is opened
+        // and the second restores it when the zone is released
+        let app = new_app(config(
+            &[40.0],
+            "get_temperature_readings = [2], set_fan_mode = [3]",
+            "on_failure = \"restore\"",
+        ));
+        let ipmi = app.sessions["sim"].ipmi.clone();
+
+        tick(&app).unwrap();
+        tick(&app).unwrap();
+        assert_eq!(fan_mode(&ipmi), FanMode::Optimal);
+
+        // Taking back control fails, which is retried on the next iteration
+        tick(&app).unwrap();
+        assert_eq!(fan_mode(&ipmi), FanMode::Optimal);
+        assert_eq!(duty_cycles(&ipmi), [100, 100]);
+        assert_eq!(app.zones[0].status.lock().unwrap().failures, 2);
+
+        tick(&app).unwrap();
+        assert_eq!(fan_mode(&ipmi), FanMode::Full);
+        assert_eq!(duty_cycles(&ipmi), [40, 40]);
+        assert_eq!(app.zones[0].status.lock().unwrap().failures, 0);
+    }
+
+    #[test]
+    fn reload_with_released_zones() {
+        let failures = "get_temperature_readings = [2]";
+        let app = new_app(config(&[40.0], failures, "on_failure = \"restore\""));
+        let ipmi = app.sessions["sim"].ipmi.clone();
+
+        tick(&app).unwrap();
+        tick(&app).unwrap();
+        assert_eq!(fan_mode(&ipmi), FanMode::Optimal);
+
+        // The zone is still released, so the BMC stays in control
+        let opened = app.prepare_sessions(
+            &config(&[40.0], failures, "on_failure = \"restore\""), false).unwrap();
+        assert!(opened.is_empty());
+        assert_eq!(fan_mode(&ipmi), FanMode::Optimal);
+
+        // The released zones are no longer used, so nothing else would take
+        // back control
+        let mut config = config(&[40.0], failures, "");
+        config.zones[0].ipmi_zones = vec![2];
+        let opened = app.prepare_sessions(&config, false).unwrap();
+        assert!(opened.is_empty());
+        assert_eq!(fan_mode(&ipmi), FanMode::Full);
+    }
+
+    #[test]
+    fn thresholds_saved_without_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&[40.0], "", "");
+        let st = &config.sessions.0["sim"].0;
+        let fan_thresholds = &config.fan_thresholds["sim"];
+        let store = ThresholdStore::new(dir.path(), "sim", st);
+        let mut ipmi = ipmi::connect(st).unwrap();
+        let orig = ipmi.get_sensor_thresholds(0).unwrap();
+
+        IpmiSession::update_fan_thresholds("sim", &mut *ipmi, &mut vec![], Some(&store),
+                                           Some(fan_thresholds)).unwrap();
+        assert_ne!(ipmi.get_sensor_thresholds(0).unwrap(), orig);
+
+        let saved = store.load().unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].thresholds.values, orig.values);
+
+        // A later run without the thresholds in the config restores them
+        IpmiSession::update_fan_thresholds("sim", &mut *ipmi, &mut vec![], Some(&store), None)
+            .unwrap();
+        assert_eq!(ipmi.get_sensor_thresholds(0).unwrap(), orig);
+        assert!(store.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn restore_policy_shared_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("temp");
+        let config = format!(r#"
+            [[zones]]
+            session = "sim"
+            ipmi_zones = [0]
+            retries = 0
+            on_failure = "restore"
+            sources = [{{ type = "ipmi", sensor = "CPU Temp" }}]
+            steps = [{{ temp = 30, dcycle = 20 }}, {{ temp = 70, dcycle = 100 }}]
+
+            [[zones]]
+            session = "sim"
+            ipmi_zones = [1]
+            retries = 0
+            on_failure = "restore"
+            sources = [{{ type = "file", path = {path:?} }}]
+            steps = [{{ temp = 30, dcycle = 20 }}, {{ temp = 70, dcycle = 100 }}]
+
+            [sessions.sim]
+            type = "simulated"
+            fan_mode = "optimal"
+            temperature_sensors = [{{ name = "CPU Temp", values = [40, 50] }}]
+            failures = {{ get_temperature_readings = [3] }}
+        "#);
+        let app = new_app(toml::from_str(&config).unwrap());
+        let ipmi = app.sessions["sim"].ipmi.clone();
+
+        tick_zone(&app, 0).unwrap();
+        assert_eq!(duty_cycles(&ipmi), [40, 100]);
+
+        // The file does not exist yet, so the second zone hands control back
+        tick_zone(&app, 1).unwrap();
+        assert_eq!(fan_mode(&ipmi), FanMode::Optimal);
+
+        // The first zone is healthy, but leaves the fans to the BMC
+        tick_zone(&app, 0).unwrap();
+        assert_eq!(fan_mode(&ipmi), FanMode::Optimal);
+        assert_eq!(duty_cycles(&ipmi), [40, 100]);
+
+        // The first zone fails and recovers, but the second zone is still
+        // released
+        tick_zone(&app, 0).unwrap();
+        assert_eq!(duty_cycles(&ipmi), [100, 100]);
+        tick_zone(&app, 0).unwrap();
+        assert_eq!(fan_mode(&ipmi), FanMode::Optimal);
+        assert_eq!(duty_cycles(&ipmi), [100, 100]);
+
+        // Control is taken back once the second zone recovers too
+        fs::write(&path, "60000").unwrap();
+        tick_zone(&app, 1).unwrap();
+        assert_eq!(fan_mode(&ipmi), FanMode::Full);
+        assert_eq!(duty_cycles(&ipmi), [100, 80]);
+
+        tick_zone(&app, 0).unwrap();
+        assert_eq!(duty_cycles(&ipmi), [60, 80]);
+    }
+
+    #[test]
+    fn restore_on_shutdown() {
+        let app = new_app(config(&[40.0], "", ""));
+        let ipmi = app.sessions["sim"].ipmi.clone();
+
+        tick(&app).unwrap();
+        assert_eq!(fan_mode(&ipmi), FanMode::Full);
+        assert_eq!(duty_cycles(&ipmi), [40, 40]);
+        assert_eq!(ipmi.lock().unwrap().get_sensor_thresholds(0).unwrap().values,
+                   [7, 1, 3, 0, 0, 0]);
+
+        drop(app);
+
+        assert_eq!(fan_mode(&ipmi), FanMode::Optimal);
+        assert_eq!(duty_cycles(&ipmi), [100, 100]);
+        assert_eq!(ipmi.lock().unwrap().get_sensor_thresholds(0).unwrap().values,
+                   [7, 5, 3, 0, 0, 0]);
+    }
+}
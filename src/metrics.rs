@@ -0,0 +1,392 @@
+use {
+    std::{
+        fmt::{Display, Write},
+        io,
+        net::SocketAddr,
+        sync::Arc,
+        time::Duration,
+    },
+    log::{debug, info, warn},
+    tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+        task::JoinSet,
+        time,
+    },
+    crate::{
+        error::{Error, Result},
+        ipmi::FanMode,
+        status::{ControlState, DaemonStatus},
+    },
+};
+
+/// Maximum time to wait for a client to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum size of the request line and headers. Clients that send more are
+/// disconnected.
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+/// Fan modes that always have a series, so that a mode change does not create
+/// a new one.
+const FAN_MODES: [FanMode; 4] =
+    [FanMode::Standard, FanMode::Full, FanMode::Optimal, FanMode::HeavyIo];
+
+/// Escape a label value for the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Builder for metrics in the Prometheus text exposition format.
+struct Exposition(String);
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.0, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        writeln!(self.0, "{}{{{}}} {}", name, labels, value).unwrap();
+    }
+}
+
+/// Render the daemon status as Prometheus metrics.
+fn render(status: &DaemonStatus) -> String {
+    let mut e = Exposition(String::new());
+
+    // The fan mode and duty cycles are the values that the daemon last set or
+    // read. The BMC is not queried when scraping.
+    e.header("ipmi_fan_control_commanded_fan_mode", "gauge",
+             "Whether the fan mode last set by the IPMI session is the mode (1) or not (0)");
+    for s in &status.sessions {
+        let mut modes: Vec<_> = FAN_MODES.iter().map(FanMode::to_string).collect();
+        // Unknown modes only have a series while they are active
+        if !modes.contains(&s.fan_mode) {
+            modes.push(s.fan_mode.clone());
+        }
+
+        for m in &modes {
+            e.sample("ipmi_fan_control_commanded_fan_mode",
+                     &[("session", &s.name), ("mode", m)], u8::from(*m == s.fan_mode));
+        }
+    }
+
+    e.header("ipmi_fan_control_ipmi_errors_total", "counter",
+             "Number of failed IPMI commands and sensor queries");
+    for s in &status.sessions {
+        e.sample("ipmi_fan_control_ipmi_errors_total",
+                 &[("session", &s.name)], s.ipmi_errors);
+    }
+
+    let zones: Vec<_> = status.zones
+        .iter()
+        .map(|z| (z, z.index.to_string()))
+        .collect();
+
+    e.header("ipmi_fan_control_zone_raw_temperature_celsius", "gauge",
+             "Aggregated zone temperature before smoothing");
+    for (z, index) in &zones {
+        if let Some(t) = z.status.temp_raw {
+            e.sample("ipmi_fan_control_zone_raw_temperature_celsius",
+                     &[("session", &z.status.session), ("zone", index)], t);
+        }
+    }
+
+    e.header("ipmi_fan_control_zone_temperature_celsius", "gauge",
+             "Aggregated zone temperature after smoothing");
+    for (z, index) in &zones {
+        if let Some(t) = z.status.temp {
+            e.sample("ipmi_fan_control_zone_temperature_celsius",
+                     &[("session", &z.status.session), ("zone", index)], t);
+        }
+    }
+
+    e.header("ipmi_fan_control_source_temperature_celsius", "gauge",
+             "Temperature reading of a zone's source");
+    for (z, index) in &zones {
+        for s in &z.status.sources {
+            if let Some(t) = s.temp {
+                e.sample("ipmi_fan_control_source_temperature_celsius",
+                         &[("session", &z.status.session), ("zone", index),
+                           ("source", &s.source)], t);
+            }
+        }
+    }
+
+    e.header("ipmi_fan_control_fan_speed_rpm", "gauge",
+             "Speed of a fan monitored by a zone's stall detection");
+    for (z, index) in &zones {
+        for f in &z.status.fans {
+            if let Some(rpm) = f.rpm {
+                e.sample("ipmi_fan_control_fan_speed_rpm",
+                         &[("session", &z.status.session), ("zone", index),
+                           ("fan", &f.fan)], rpm);
+            }
+        }
+    }
+
+    e.header("ipmi_fan_control_source_errors_total", "counter",
+             "Number of failed readings of a zone's source");
+    for (z, index) in &zones {
+        for s in &z.status.sources {
+            e.sample("ipmi_fan_control_source_errors_total",
+                     &[("session", &z.status.session), ("zone", index),
+                       ("source", &s.source)], s.errors);
+        }
+    }
+
+    e.header("ipmi_fan_control_zone_target_duty_cycle_percent", "gauge",
+             "Duty cycle chosen by the zone's controller");
+    for (z, index) in &zones {
+        if let Some(d) = z.status.dcycle {
+            e.sample("ipmi_fan_control_zone_target_duty_cycle_percent",
+                     &[("session", &z.status.session), ("zone", index)], d);
+        }
+    }
+
+    e.header("ipmi_fan_control_commanded_duty_cycle_percent", "gauge",
+             "Duty cycle of an IPMI zone as last set or read by the zone");
+    for (z, index) in &zones {
+        for a in &z.status.actual_dcycles {
+            e.sample("ipmi_fan_control_commanded_duty_cycle_percent",
+                     &[("session", &z.status.session), ("zone", index),
+                       ("ipmi_zone", &a.zone.to_string())], a.dcycle);
+        }
+    }
+
+    e.header("ipmi_fan_control_retries_exhausted_total", "counter",
+             "Number of times all attempts to query a zone's sources failed");
+    for (z, index) in &zones {
+        e.sample("ipmi_fan_control_retries_exhausted_total",
+                 &[("session", &z.status.session), ("zone", index)],
+                 z.status.retries_exhausted);
+    }
+
+    e.header("ipmi_fan_control_zone_consecutive_failures", "gauge",
+             "Number of consecutive failed fan updates of a zone");
+    for (z, index) in &zones {
+        e.sample("ipmi_fan_control_zone_consecutive_failures",
+                 &[("session", &z.status.session), ("zone", index)],
+                 z.status.failures);
+    }
+
+    e.0
+}
+
+/// Handle a single HTTP request and close the connection.
+async fn handle_client(stream: TcpStream, state: Arc<ControlState>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader.take(MAX_REQUEST_SIZE)).lines();
+
+    let request_line = time::timeout(REQUEST_TIMEOUT, async {
+        let request_line = lines.next_line().await?.unwrap_or_default();
+
+        // Skip the headers
+        loop {
+            match lines.next_line().await? {
+                Some(line) if line.is_empty() => break,
+                Some(_) => {}
+                None if lines.get_ref().get_ref().limit() == 0 => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "Request headers too large"));
+                }
+                None => break,
+            }
+        }
+
+        Ok::<_, io::Error>(request_line)
+    }).await??;
+
+    debug!("Metrics request: {:?}", request_line);
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            render(&state.status()),
+        ),
+        _ => ("404 Not Found", "text/plain; charset=utf-8", "Not found\n".to_owned()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body,
+    );
+
+    writer.write_all(response.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+/// Serve Prometheus metrics over HTTP at `/metrics` on the given address. This
+/// only returns if the listener cannot be created.
+pub async fn serve(address: SocketAddr, state: Arc<ControlState>) -> Result<()> {
+    let listener = TcpListener::bind(address).await
+        .map_err(|e| Error::MetricsBind { address, source: e })?;
+
+    info!("Serving metrics on: http://{}/metrics", address);
+
+    accept_clients(listener, state).await
+}
+
+/// Handle clients of the listener until the future is dropped.
+async fn accept_clients(listener: TcpListener, state: Arc<ControlState>) -> Result<()> {
+    // Dropping the JoinSet aborts all client tasks
+    let mut clients = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            c = listener.accept() => {
+                match c {
+                    Ok((stream, _)) => {
+                        let state = state.clone();
+
+                        clients.spawn(async move {
+                            if let Err(e) = handle_client(stream, state).await {
+                                debug!("Metrics client error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept metrics client: {}", e),
+                }
+            }
+            // Reap finished clients
+            Some(_) = clients.join_next() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::{
+            sync::{atomic::AtomicU64, Mutex},
+            time::Instant,
+        },
+        crate::{
+            config::Zone,
+            ipmi::FanMode,
+            status::{IpmiZoneStatus, SessionInfo, SessionKind, ZoneHandle},
+        },
+        super::*,
+    };
+
+    fn state() -> Arc<ControlState> {
+        let zone: Zone = toml::from_str(r#"
+            session = "sim"
+            ipmi_zones = [0]
+            sources = [{ type = "ipmi", sensor = "CPU Temp" }]
+        "#).unwrap();
+        let handle = ZoneHandle::new(&zone);
+
+        {
+            let mut status = handle.status.lock().unwrap();
+            status.temp_raw = Some(41.5);
+            status.temp = Some(40.0);
+            status.sources[0].temp = Some(41.5);
+            status.dcycle = Some(35);
+            status.actual_dcycles = vec![IpmiZoneStatus { zone: 0, dcycle: 35 }];
+        }
+
+        Arc::new(ControlState {
+            sessions: vec![SessionInfo {
+                name: "sim".to_owned(),
+                kind: SessionKind::Simulated,
+                hostname: None,
+                fan_mode: Arc::new(Mutex::new(FanMode::Full)),
+                ipmi_errors: Arc::new(AtomicU64::new(3)),
+            }],
+            zones: vec![Arc::new(handle)],
+        })
+    }
+
+    /// Send a GET request and return the response headers and body.
+    async fn get(address: SocketAddr, path: &str) -> (Vec<String>, String) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+
+        (head.split("\r\n").map(str::to_owned).collect(), body.to_owned())
+    }
+
+    #[tokio::test]
+    async fn scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(accept_clients(listener, state()));
+
+        let (headers, body) = get(address, "/metrics").await;
+        assert_eq!(headers[0], "HTTP/1.1 200 OK");
+        assert!(headers.contains(
+            &"Content-Type: text/plain; version=0.0.4; charset=utf-8".to_owned()));
+        assert!(headers.contains(&format!("Content-Length: {}", body.len())));
+        assert!(headers.contains(&"Connection: close".to_owned()));
+
+        for line in [
+            "# TYPE ipmi_fan_control_commanded_fan_mode gauge",
+            r#"ipmi_fan_control_commanded_fan_mode{session="sim",mode="standard"} 0"#,
+            r#"ipmi_fan_control_commanded_fan_mode{session="sim",mode="full"} 1"#,
+            r#"ipmi_fan_control_commanded_fan_mode{session="sim",mode="optimal"} 0"#,
+            r#"ipmi_fan_control_commanded_fan_mode{session="sim",mode="heavyio"} 0"#,
+            "# TYPE ipmi_fan_control_ipmi_errors_total counter",
+            r#"ipmi_fan_control_ipmi_errors_total{session="sim"} 3"#,
+            "# TYPE ipmi_fan_control_zone_raw_temperature_celsius gauge",
+            r#"ipmi_fan_control_zone_raw_temperature_celsius{session="sim",zone="0"} 41.5"#,
+            r#"ipmi_fan_control_zone_temperature_celsius{session="sim",zone="0"} 40"#,
+            r#"ipmi_fan_control_source_temperature_celsius{session="sim",zone="0",source="ipmi:CPU Temp"} 41.5"#,
+            "# TYPE ipmi_fan_control_source_errors_total counter",
+            r#"ipmi_fan_control_source_errors_total{session="sim",zone="0",source="ipmi:CPU Temp"} 0"#,
+            r#"ipmi_fan_control_zone_target_duty_cycle_percent{session="sim",zone="0"} 35"#,
+            r#"ipmi_fan_control_commanded_duty_cycle_percent{session="sim",zone="0",ipmi_zone="0"} 35"#,
+            "# TYPE ipmi_fan_control_retries_exhausted_total counter",
+            r#"ipmi_fan_control_retries_exhausted_total{session="sim",zone="0"} 0"#,
+            r#"ipmi_fan_control_zone_consecutive_failures{session="sim",zone="0"} 0"#,
+        ] {
+            assert!(body.lines().any(|l| l == line), "Missing line: {}\n{}", line, body);
+        }
+
+        // Only the sessions and zones that exist are reported
+        assert!(!body.contains("ipmi_fan_control_fan_speed_rpm{"));
+
+        let (headers, _) = get(address, "/").await;
+        assert_eq!(headers[0], "HTTP/1.1 404 Not Found");
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn request_too_large() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(accept_clients(listener, state()));
+
+        // The client keeps the connection open, but never ends the header
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let start = Instant::now();
+        let header = format!("GET /metrics HTTP/1.1\r\nX-Padding: {}",
+                             "a".repeat(2 * MAX_REQUEST_SIZE as usize));
+        let _ = stream.write_all(header.as_bytes()).await;
+
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        assert!(response.is_empty(), "{}", response);
+        assert!(start.elapsed() < REQUEST_TIMEOUT);
+
+        server.abort();
+    }
+}
@@ -0,0 +1,255 @@
+use {
+    std::{
+        env,
+        ffi::OsString,
+        os::unix::net::UnixDatagram,
+        path::PathBuf,
+        process,
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+    log::{debug, warn},
+    tokio::time,
+    crate::{
+        config::Zone,
+        error::{Error, Result},
+        status::ZoneHandle,
+    },
+};
+
+/// How often to publish the status if the watchdog is not enabled.
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Extra time allowed for the IPMI commands and source queries in a single
+/// iteration of a zone loop, on top of the interval and retry delays.
+const ITERATION_SLACK: Duration = Duration::from_secs(30);
+
+/// Client for the systemd service notification protocol. This sends datagrams
+/// to the socket specified by `$NOTIFY_SOCKET`.
+#[derive(Debug)]
+pub struct Notifier {
+    path: PathBuf,
+    socket: UnixDatagram,
+    /// Watchdog timeout if the watchdog is enabled for this process
+    watchdog_timeout: Option<Duration>,
+}
+
+impl Notifier {
+    /// Create a notifier from the environment variables set by systemd.
+    /// Returns [`None`] if the process is not running under a service manager
+    /// that supports notifications.
+    pub fn from_env() -> Result<Option<Self>> {
+        Self::from_vars(
+            env::var_os("NOTIFY_SOCKET"),
+            env::var("WATCHDOG_USEC").ok(),
+            env::var("WATCHDOG_PID").ok(),
+        )
+    }
+
+    /// Create a notifier from the values of `$NOTIFY_SOCKET`,
+    /// `$WATCHDOG_USEC`, and `$WATCHDOG_PID`.
+    fn from_vars(
+        notify_socket: Option<OsString>,
+        watchdog_usec: Option<String>,
+        watchdog_pid: Option<String>,
+    ) -> Result<Option<Self>> {
+        let path = match notify_socket {
+            Some(p) if !p.is_empty() => PathBuf::from(p),
+            _ => return Ok(None),
+        };
+
+        let socket = UnixDatagram::unbound()
+            .map_err(|e| Error::Io { path: path.clone(), source: e })?;
+
+        // The watchdog is only meant for this process if the PID matches
+        let pid_matches = match watchdog_pid {
+            Some(p) => p.parse() == Ok(process::id()),
+            None => true,
+        };
+        let watchdog_timeout = watchdog_usec
+            .and_then(|u| u.parse().ok())
+            .filter(|u| *u > 0 && pid_matches)
+            .map(Duration::from_micros);
+
+        debug!("Notification socket: {:?}, watchdog timeout: {:?}",
+               path, watchdog_timeout);
+
+        Ok(Some(Self {
+            path,
+            socket,
+            watchdog_timeout,
+        }))
+    }
+
+    /// Send a newline-separated list of `KEY=VALUE` assignments. Failures are
+    /// logged, but otherwise ignored.
+    pub fn notify(&self, state: &str) {
+        let result = match self.path.to_str().and_then(|p| p.strip_prefix('@')) {
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            Some(name) => {
+                use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+                SocketAddr::from_abstract_name(name)
+                    .and_then(|a| self.socket.send_to_addr(state.as_bytes(), &a))
+            }
+            _ => self.socket.send_to(state.as_bytes(), &self.path),
+        };
+
+        if let Err(e) = result {
+            warn!("Failed to send notification: {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Get the maximum amount of time that a zone loop may take between the ends
+/// of two iterations before it is considered stuck.
+pub fn zone_deadline(zone_config: &Zone) -> Duration {
+    zone_config.interval.to_duration()
+        + zone_config.retry_iter().sum::<Duration>()
+        + ITERATION_SLACK
+}
+
+/// Summarize the temperature and duty cycle of each zone.
+fn status_line(zones: &[(Arc<ZoneHandle>, Duration)]) -> String {
+    let summary = zones
+        .iter()
+        .enumerate()
+        .map(|(i, (h, _))| {
+            let status = h.status.lock().unwrap();
+
+            match (status.temp, status.dcycle) {
+                (Some(t), Some(d)) => format!("zone {}: {:.1}C {}%", i, t, d),
+                _ => format!("zone {}: no reading", i),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!("STATUS={}", summary)
+}
+
+/// Periodically publish the zone status and, if the watchdog is enabled, ping
+/// the watchdog as long as every zone loop has completed an iteration within
+/// its deadline. Each zone is paired with its deadline. This never returns.
+pub async fn watchdog(
+    notifier: Arc<Notifier>,
+    zones: Vec<(Arc<ZoneHandle>, Duration)>,
+) -> Result<()> {
+    let period = notifier.watchdog_timeout.map_or(STATUS_INTERVAL, |t| t / 2);
+    let mut interval = time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        let now = Instant::now();
+        let mut healthy = true;
+
+        for (i, (h, deadline)) in zones.iter().enumerate() {
+            let elapsed = now.duration_since(*h.last_iteration.lock().unwrap());
+
+            if elapsed > *deadline {
+                warn!("Zone {} has not completed an iteration in {:?} (deadline: {:?})",
+                      i, elapsed, deadline);
+                healthy = false;
+            }
+        }
+
+        let mut state = status_line(&zones);
+
+        if notifier.watchdog_timeout.is_some() && healthy {
+            state.push_str("\nWATCHDOG=1");
+        }
+
+        notifier.notify(&state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        tokio::net::UnixDatagram as TokioUnixDatagram,
+        crate::config::Zone,
+        super::*,
+    };
+
+    async fn recv(socket: &TokioUnixDatagram) -> String {
+        let mut buf = [0u8; 1024];
+        let n = time::timeout(Duration::from_secs(5), socket.recv(&mut buf)).await
+            .expect("Timed out waiting for notification")
+            .unwrap();
+
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    fn zone_handle() -> Arc<ZoneHandle> {
+        let zone: Zone = toml::from_str(r#"
+            ipmi_zones = [0]
+            sources = [{ type = "ipmi", sensor = "CPU Temp" }]
+        "#).unwrap();
+
+        Arc::new(ZoneHandle::new(&zone))
+    }
+
+    #[tokio::test]
+    async fn notifications() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let socket = TokioUnixDatagram::bind(&path).unwrap();
+
+        // Watchdog pings are sent every 50ms
+        let notifier = Notifier::from_vars(
+            Some(path.into_os_string()),
+            Some("100000".to_owned()),
+            Some(process::id().to_string()),
+        ).unwrap().unwrap();
+        let notifier = Arc::new(notifier);
+
+        notifier.notify("READY=1");
+        assert_eq!(recv(&socket).await, "READY=1");
+
+        let stuck = zone_handle();
+        {
+            let mut status = stuck.status.lock().unwrap();
+            status.temp = Some(40.0);
+            status.dcycle = Some(35);
+        }
+        let deadline = Duration::from_millis(300);
+        let zones = vec![
+            (stuck.clone(), deadline),
+            (zone_handle(), Duration::from_secs(3600)),
+        ];
+        let start = *stuck.last_iteration.lock().unwrap();
+        let watchdog = tokio::spawn(watchdog(notifier, zones));
+
+        assert_eq!(recv(&socket).await,
+                   "STATUS=zone 0: 40.0C 35%, zone 1: no reading\nWATCHDOG=1");
+
+        // The first zone never completes another iteration
+        loop {
+            let message = recv(&socket).await;
+            if !message.ends_with("\nWATCHDOG=1") {
+                assert!(start.elapsed() > deadline);
+                assert_eq!(message, "STATUS=zone 0: 40.0C 35%, zone 1: no reading");
+                break;
+            }
+        }
+
+        for _ in 0..3 {
+            assert!(!recv(&socket).await.contains("WATCHDOG=1"));
+        }
+
+        watchdog.abort();
+    }
+
+    #[test]
+    fn watchdog_for_other_process() {
+        let notifier = Notifier::from_vars(
+            Some("/nonexistent".into()),
+            Some("100000".to_owned()),
+            Some("1".to_owned()),
+        ).unwrap().unwrap();
+        assert_eq!(notifier.watchdog_timeout, None);
+
+        assert!(Notifier::from_vars(None, None, None).unwrap().is_none());
+    }
+}
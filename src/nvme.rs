@@ -0,0 +1,162 @@
+use {
+    std::{
+        fs::File,
+        io,
+        os::unix::io::AsRawFd,
+        path::Path,
+    },
+    log::trace,
+    crate::error::{Error, Result},
+};
+
+/// `struct nvme_passthru_cmd` from `<linux/nvme_ioctl.h>`
+#[repr(C)]
+#[derive(Default)]
+struct PassthruCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+/// `NVME_IOCTL_ADMIN_CMD`, which is `_IOWR('N', 0x41, struct nvme_admin_cmd)`
+const NVME_IOCTL_ADMIN_CMD: u32 = (3 << 30)
+    | ((std::mem::size_of::<PassthruCmd>() as u32) << 16)
+    | ((b'N' as u32) << 8)
+    | 0x41;
+
+/// Get Log Page admin command opcode
+const OPCODE_GET_LOG_PAGE: u8 = 0x02;
+/// SMART / Health Information log page identifier
+const LOG_ID_SMART: u8 = 0x02;
+/// Size of the SMART / Health Information log page
+const LOG_SIZE_SMART: usize = 512;
+/// Offset of the composite temperature in the SMART log page
+const OFFSET_COMPOSITE_TEMP: usize = 1;
+/// Offset of temperature sensor 1 in the SMART log page
+const OFFSET_SENSOR_TEMP: usize = 200;
+
+/// Read the SMART / Health Information log page for the controller.
+fn read_smart_log(device: &Path) -> Result<[u8; LOG_SIZE_SMART]> {
+    let file = File::open(device)
+        .map_err(|e| Error::Io { path: device.to_owned(), source: e })?;
+    let mut data = [0u8; LOG_SIZE_SMART];
+
+    // Number of dwords to read (0-based)
+    let numd = (LOG_SIZE_SMART / 4 - 1) as u32;
+
+    let mut cmd = PassthruCmd {
+        opcode: OPCODE_GET_LOG_PAGE,
+        // Controller-wide log page
+        nsid: u32::MAX,
+        addr: data.as_mut_ptr() as u64,
+        data_len: LOG_SIZE_SMART as u32,
+        cdw10: u32::from(LOG_ID_SMART) | ((numd & 0xffff) << 16),
+        cdw11: numd >> 16,
+        ..Default::default()
+    };
+
+    // The request type is c_ulong on glibc and c_int on musl
+    let ret = unsafe {
+        libc::ioctl(file.as_raw_fd(), NVME_IOCTL_ADMIN_CMD as _, &mut cmd as *mut PassthruCmd)
+    };
+
+    if ret < 0 {
+        return Err(Error::Io { path: device.to_owned(), source: io::Error::last_os_error() });
+    } else if ret > 0 {
+        // Positive values are NVMe status codes
+        return Err(Error::NvmeCommand { device: device.to_owned(), status: ret });
+    }
+
+    trace!("NVMe SMART log for {:?}: {:02x?}", device, data);
+
+    Ok(data)
+}
+
+/// Get a temperature from a SMART / Health Information log page. If `sensor`
+/// is [`None`], the composite temperature is returned. Otherwise, the
+/// temperature of the specified sensor (1-8) is returned. The values are
+/// reported in whole Kelvin and are converted to degrees Celsius. [`None`] is
+/// returned if the sensor is not implemented by the controller.
+fn parse_smart_log(data: &[u8; LOG_SIZE_SMART], sensor: Option<u8>) -> Option<f64> {
+    let offset = match sensor {
+        None => OFFSET_COMPOSITE_TEMP,
+        Some(n) => OFFSET_SENSOR_TEMP + 2 * usize::from(n - 1),
+    };
+    let kelvin = u16::from_le_bytes([data[offset], data[offset + 1]]);
+
+    // Unimplemented sensors report 0
+    if kelvin == 0 {
+        return None;
+    }
+
+    // Same conversion as smartctl and nvme-cli
+    Some(f64::from(kelvin) - 273.0)
+}
+
+/// Get the temperature of an NVMe controller from its SMART / Health
+/// Information log page. See [`parse_smart_log`] for the meaning of `sensor`.
+/// An error is returned if the sensor is not implemented by the controller.
+pub fn get_temperature(device: &Path, sensor: Option<u8>) -> Result<f64> {
+    let data = read_smart_log(device)?;
+
+    parse_smart_log(&data, sensor)
+        .ok_or_else(|| Error::NvmeNoReading { device: device.to_owned(), sensor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a SMART log page with the given little-endian 16-bit values at
+    /// the given offsets.
+    fn smart_log(values: &[(usize, u16)]) -> [u8; LOG_SIZE_SMART] {
+        let mut data = [0u8; LOG_SIZE_SMART];
+
+        for (offset, value) in values {
+            data[*offset..*offset + 2].copy_from_slice(&value.to_le_bytes());
+        }
+
+        data
+    }
+
+    #[test]
+    fn composite_temperature() {
+        // The critical warning byte before the temperature is ignored
+        let mut data = smart_log(&[(1, 318)]);
+        data[0] = 0xff;
+
+        assert_eq!(parse_smart_log(&data, None), Some(45.0));
+        assert_eq!(parse_smart_log(&smart_log(&[(1, 0x0113)]), None), Some(2.0));
+    }
+
+    #[test]
+    fn sensor_temperature() {
+        let data = smart_log(&[(1, 318), (200, 320), (202, 0), (214, 250)]);
+
+        assert_eq!(parse_smart_log(&data, Some(1)), Some(47.0));
+        assert_eq!(parse_smart_log(&data, Some(8)), Some(-23.0));
+    }
+
+    #[test]
+    fn sensor_not_implemented() {
+        let data = smart_log(&[(200, 320)]);
+
+        assert_eq!(parse_smart_log(&data, None), None);
+        assert_eq!(parse_smart_log(&data, Some(2)), None);
+    }
+}
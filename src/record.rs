@@ -0,0 +1,365 @@
+use {
+    std::{
+        collections::{BTreeMap, HashMap},
+        fs::{self, OpenOptions},
+        io::{BufRead, BufReader, Write},
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    serde::{Deserialize, Serialize},
+    crate::{
+        config::Config,
+        error::{Error, Result},
+        status::IpmiZoneStatus,
+        MainApp,
+        ZoneState,
+    },
+};
+
+/// Inputs and outputs of one fan update iteration for a zone.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Tick {
+    /// Seconds since the Unix epoch
+    pub time: f64,
+    /// Name of the IPMI session used by the zone
+    pub session: String,
+    /// IPMI zones controlled by the zone
+    pub ipmi_zones: Vec<u8>,
+    /// Readings for each source, including cached readings for stale sources
+    pub readings: Vec<Option<Vec<f64>>>,
+    /// Aggregated temperature before smoothing
+    pub temp_raw: f64,
+    /// Duty cycle written to each IPMI zone
+    pub dcycles: Vec<IpmiZoneStatus>,
+}
+
+impl Tick {
+    pub fn new(
+        session: &str,
+        ipmi_zones: &[u8],
+        readings: Vec<Option<Vec<f64>>>,
+        temp_raw: f64,
+        dcycles: Vec<IpmiZoneStatus>,
+    ) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        Self {
+            time,
+            session: session.to_owned(),
+            ipmi_zones: ipmi_zones.to_vec(),
+            readings,
+            temp_raw,
+            dcycles,
+        }
+    }
+}
+
+/// Appends ticks from every zone to a file as JSON lines.
+pub struct Recorder {
+    path: PathBuf,
+    file: Mutex<fs::File>,
+}
+
+impl Recorder {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::Io { path: path.to_owned(), source: e })?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append a tick as a single line. The line is written with one call so
+    /// that ticks from different zones are not interleaved.
+    pub fn record(&self, tick: &Tick) -> Result<()> {
+        let mut line = serde_json::to_vec(tick)
+            .map_err(|e| Error::Io { path: self.path.clone(), source: e.into() })?;
+        line.push(b'\n');
+
+        self.file.lock().unwrap()
+            .write_all(&line)
+            .map_err(|e| Error::Io { path: self.path.clone(), source: e })
+    }
+}
+
+/// Maximum time between two ticks of a zone, in zone intervals, that is used
+/// when replaying.
+const MAX_GAP_INTERVALS: f64 = 5.0;
+
+/// Totals for a zone over the whole recording.
+#[derive(Default)]
+struct Summary {
+    ticks: usize,
+    different: usize,
+    max_diff: u8,
+}
+
+/// Feed the ticks in a recording through the zone logic of the config and
+/// print every tick where a different duty cycle would have been chosen. Ticks
+/// are matched to zones by session name and IPMI zones, and the ticks without a
+/// matching zone are counted. The controller state carries over between ticks
+/// of the same zone, as it would in the daemon.
+///
+/// If the zone's sources changed so that the recorded readings no longer
+/// apply, the recorded aggregated temperature is used instead. The recorded
+/// duty cycles may come from a manual or paused control mode, while the
+/// replayed duty cycles are always chosen by the controller.
+pub fn replay(config: &Config, path: &Path, out: &mut dyn Write) -> Result<()> {
+    let file = fs::File::open(path)
+        .map_err(|e| Error::Io { path: path.to_owned(), source: e })?;
+    let io_err = |e| Error::Io { path: path.to_owned(), source: e };
+    let write_err = |e| Error::Io { path: "(output)".into(), source: e };
+
+    let mut states: HashMap<usize, ZoneState> = HashMap::new();
+    let mut last_times: HashMap<usize, f64> = HashMap::new();
+    let mut summaries: BTreeMap<usize, Summary> = BTreeMap::new();
+    let mut unmatched: BTreeMap<(String, Vec<u8>), usize> = BTreeMap::new();
+    let mut start = None;
+
+    writeln!(out, "{:>10} {:>8} {:>14} {:>9} {:>9} {:>9}",
+             "TIME", "ZONE", "SESSION", "TEMP_RAW", "RECORDED", "REPLAYED")
+        .map_err(write_err)?;
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(io_err)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let tick: Tick = serde_json::from_str(&line)
+            .map_err(|e| Error::RecordingParse { path: path.to_owned(), line: i + 1, source: e })?;
+        let start = *start.get_or_insert(tick.time);
+
+        let zone_index = config.zones
+            .iter()
+            .position(|z| z.session.0 == tick.session && z.ipmi_zones == tick.ipmi_zones);
+        let Some(zone_index) = zone_index else {
+            *unmatched.entry((tick.session, tick.ipmi_zones)).or_default() += 1;
+            continue;
+        };
+        let zone_config = &config.zones[zone_index];
+
+        // The new config may have fewer sources or a higher minimum
+        let available = tick.readings.iter().filter(|r| r.is_some()).count();
+        let temp_raw = if tick.readings.len() == zone_config.sources.len()
+            && available >= zone_config.min_sources.0
+            && tick.readings.iter().flatten().any(|r| !r.is_empty())
+        {
+            MainApp::aggregate(zone_config, &tick.readings)
+        } else {
+            tick.temp_raw
+        };
+
+        // Use the actual time between ticks, like the daemon does. Long gaps
+        // usually mean that the daemon was not running, so they are capped to
+        // keep them from winding up the integral or stalling the ramp limits.
+        let interval = zone_config.interval.to_duration().as_secs_f64();
+        let dt = match last_times.insert(zone_index, tick.time) {
+            Some(t) => (tick.time - t).clamp(0.0, interval * MAX_GAP_INTERVALS),
+            None => interval,
+        };
+
+        let state = states.entry(zone_index).or_default();
+        let decision = state.decide(zone_config, temp_raw, None, dt);
+        let summary = summaries.entry(zone_index).or_default();
+
+        summary.ticks += 1;
+        if tick.dcycles.iter().any(|z| z.dcycle != decision.dcycle) {
+            summary.different += 1;
+        }
+
+        for z in &tick.dcycles {
+            if z.dcycle == decision.dcycle {
+                continue;
+            }
+
+            summary.max_diff = summary.max_diff.max(z.dcycle.abs_diff(decision.dcycle));
+
+            writeln!(out, "{:>10.0} {:>8} {:>14} {:>9.1} {:>8}% {:>8}%",
+                     tick.time - start, z.zone, tick.session, temp_raw, z.dcycle,
+                     decision.dcycle)
+                .map_err(write_err)?;
+        }
+    }
+
+    writeln!(out).map_err(write_err)?;
+
+    for (zone_index, s) in &summaries {
+        let z = &config.zones[*zone_index];
+
+        writeln!(out, "[{}] Zones {:?}: {} of {} tick(s) differ (largest difference: {}%)",
+                 z.session.0, z.ipmi_zones, s.different, s.ticks, s.max_diff)
+            .map_err(write_err)?;
+    }
+
+    for ((session, ipmi_zones), count) in &unmatched {
+        writeln!(out, "[{}] Zones {:?}: skipped {} tick(s): no matching zone in config",
+                 session, ipmi_zones, count)
+            .map_err(write_err)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        tempfile::TempDir,
+        super::*,
+    };
+
+    fn config(steps: &str) -> Config {
+        toml::from_str(&format!(r#"
+            [[zones]]
+            session = "sim"
+            ipmi_zones = [0, 1]
+            sources = [{{ type = "ipmi", sensor = "CPU Temp" }}]
+            steps = {steps}
+        "#)).unwrap()
+    }
+
+    fn tick(time: f64, session: &str, temp: f64, dcycle: u8) -> Tick {
+        Tick {
+            time,
+            session: session.to_owned(),
+            ipmi_zones: vec![0, 1],
+            readings: vec![Some(vec![temp])],
+            temp_raw: temp,
+            dcycles: vec![
+                IpmiZoneStatus { zone: 0, dcycle },
+                IpmiZoneStatus { zone: 1, dcycle },
+            ],
+        }
+    }
+
+    /// Record the ticks to a file and replay it with the config.
+    fn replay_ticks(config: &Config, ticks: &[Tick]) -> String {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("recording.jsonl");
+        let recorder = Recorder::open(&path).unwrap();
+
+        for tick in ticks {
+            recorder.record(tick).unwrap();
+        }
+
+        let mut out = vec![];
+        replay(config, &path, &mut out).unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Curve that the ticks from `recorded_ticks()` follow.
+    const STEPS: &str = "[{ temp = 30, dcycle = 20 }, { temp = 70, dcycle = 100 }]";
+
+    fn recorded_ticks() -> Vec<Tick> {
+        vec![
+            tick(1000.0, "sim", 30.0, 20),
+            tick(1001.0, "sim", 50.0, 60),
+            tick(1002.0, "sim", 70.0, 100),
+        ]
+    }
+
+    #[test]
+    fn tick_json_round_trip() {
+        let tick = Tick {
+            readings: vec![Some(vec![40.5, 41.0]), None, Some(vec![])],
+            ..tick(1234.5, "sim", 41.0, 35)
+        };
+        let json = serde_json::to_string(&tick).unwrap();
+        let parsed: Tick = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(parsed.time, tick.time);
+        assert_eq!(parsed.session, tick.session);
+        assert_eq!(parsed.ipmi_zones, tick.ipmi_zones);
+        assert_eq!(parsed.readings, tick.readings);
+        assert_eq!(parsed.temp_raw, tick.temp_raw);
+        assert_eq!(parsed.dcycles.len(), 2);
+        assert!(parsed.dcycles.iter().zip(&tick.dcycles)
+            .all(|(a, b)| a.zone == b.zone && a.dcycle == b.dcycle));
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+    }
+
+    #[test]
+    fn replay_unchanged_config() {
+        let out = replay_ticks(&config(STEPS), &recorded_ticks());
+        let lines: Vec<_> = out.lines().collect();
+
+        assert_eq!(lines, [
+            "      TIME     ZONE        SESSION  TEMP_RAW  RECORDED  REPLAYED",
+            "",
+            "[sim] Zones [0, 1]: 0 of 3 tick(s) differ (largest difference: 0%)",
+        ]);
+    }
+
+    #[test]
+    fn replay_changed_steps() {
+        let steps = "[{ temp = 30, dcycle = 30 }, { temp = 70, dcycle = 100 }]";
+        let out = replay_ticks(&config(steps), &recorded_ticks());
+        let lines: Vec<_> = out.lines().collect();
+
+        // Only the tick in the middle of the curve changes
+        assert_eq!(lines[1..], [
+            "         0        0            sim      30.0       20%       30%",
+            "         0        1            sim      30.0       20%       30%",
+            "         1        0            sim      50.0       60%       65%",
+            "         1        1            sim      50.0       60%       65%",
+            "",
+            "[sim] Zones [0, 1]: 2 of 3 tick(s) differ (largest difference: 10%)",
+        ]);
+    }
+
+    #[test]
+    fn replay_unmatched_ticks() {
+        let mut ticks = recorded_ticks();
+        ticks.push(tick(1003.0, "other", 40.0, 40));
+        ticks.push(tick(1004.0, "other", 40.0, 40));
+        ticks.push(Tick { ipmi_zones: vec![0], ..tick(1005.0, "sim", 40.0, 40) });
+
+        let out = replay_ticks(&config(STEPS), &ticks);
+        let lines: Vec<_> = out.lines().collect();
+
+        assert_eq!(lines[lines.len() - 3..], [
+            "[sim] Zones [0, 1]: 0 of 3 tick(s) differ (largest difference: 0%)",
+            "[other] Zones [0, 1]: skipped 2 tick(s): no matching zone in config",
+            "[sim] Zones [0]: skipped 1 tick(s): no matching zone in config",
+        ]);
+    }
+
+    #[test]
+    fn replay_gap_capped() {
+        let config: Config = toml::from_str(r#"
+            [[zones]]
+            session = "sim"
+            ipmi_zones = [0, 1]
+            interval = 2
+            sources = [{ type = "ipmi", sensor = "CPU Temp" }]
+            controller = { type = "pid", target = 40, kp = 0.0, ki = 1.0, kd = 0.0, min_dcycle = 0, max_dcycle = 100 }
+        "#).unwrap();
+        // The daemon was stopped for an hour between the first two ticks
+        let ticks = [
+            tick(1000.0, "sim", 41.0, 0),
+            tick(4600.0, "sim", 41.0, 0),
+            tick(4602.0, "sim", 41.0, 0),
+        ];
+
+        let out = replay_ticks(&config, &ticks);
+        let replayed: Vec<_> = out.lines()
+            .map(|l| l.split_whitespace().collect::<Vec<_>>())
+            .filter(|f| f.len() == 6 && f[1] == "0")
+            .map(|f| f[5])
+            .collect();
+
+        // The integral grows by 1% per second over the first interval, the
+        // capped gap of 5 intervals, and the last interval
+        assert_eq!(replayed, ["2%", "12%", "14%"]);
+    }
+}
@@ -0,0 +1,285 @@
+use {
+    std::{
+        fs,
+        io,
+        os::unix::{
+            fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+            net::UnixStream as StdUnixStream,
+        },
+        path::{Path, PathBuf},
+        process,
+        sync::Arc,
+        time::Duration,
+    },
+    log::{debug, info, warn},
+    tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{UnixListener, UnixStream},
+        task::JoinSet,
+    },
+    crate::{
+        error::{Error, Result},
+        status::{ControlMode, ControlState, Request, Response, ZoneHandle},
+    },
+};
+
+impl ControlState {
+    fn zone(&self, index: usize) -> Result<&ZoneHandle, String> {
+        self.zones.get(index)
+            .map(|z| z.as_ref())
+            .ok_or_else(|| format!("Zone does not exist: {}", index))
+    }
+
+    fn handle(&self, request: Request) -> Response {
+        let result = match request {
+            Request::Status => return Response::Status(self.status()),
+            Request::Pause { zone } => self.zone(zone).map(|z| {
+                info!("Pausing zone {}", zone);
+                *z.mode.lock().unwrap() = ControlMode::Paused;
+            }),
+            Request::Manual { dcycle, .. } if dcycle > 100 => {
+                Err(format!("Invalid duty cycle percentage: {}", dcycle))
+            }
+            Request::Manual { zone, dcycle, duration_secs } => self.zone(zone).map(|z| {
+                info!("Setting zone {} to manual duty cycle: {}% (duration: {:?}s)",
+                      zone, dcycle, duration_secs);
+                z.set_manual(dcycle, duration_secs.map(Duration::from_secs));
+            }),
+            Request::Resume { zone } => self.zone(zone).map(|z| {
+                info!("Resuming automatic control of zone {}", zone);
+                *z.mode.lock().unwrap() = ControlMode::Auto;
+            }),
+        };
+
+        match result {
+            Ok(()) => Response::Ok,
+            Err(message) => Response::Error { message },
+        }
+    }
+}
+
+/// Removes the socket file when dropped.
+struct SocketGuard(PathBuf);
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            warn!("Failed to remove control socket: {:?}: {}", self.0, e);
+        }
+    }
+}
+
+/// Handle requests from a single client until it disconnects.
+async fn handle_client(stream: UnixStream, state: Arc<ControlState>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(r) => {
+                debug!("Control socket request: {:?}", r);
+                state.handle(r)
+            }
+            Err(e) => Response::Error { message: format!("Invalid request: {}", e) },
+        };
+
+        let mut data = serde_json::to_vec(&response)?;
+        data.push(b'\n');
+
+        writer.write_all(&data).await?;
+    }
+
+    Ok(())
+}
+
+/// Remove a stale socket from a previous run. Fails if another process is
+/// still serving on the socket or if the path is not a socket.
+pub fn remove_stale_socket(path: &Path) -> Result<()> {
+    let io_err = |e| Error::Io { path: path.to_owned(), source: e };
+
+    match fs::symlink_metadata(path) {
+        Ok(m) if !m.file_type().is_socket() => {
+            return Err(io_err(io::Error::new(
+                io::ErrorKind::AlreadyExists, "Path exists and is not a socket")));
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(io_err(e)),
+    }
+
+    match StdUnixStream::connect(path) {
+        Ok(_) => Err(Error::ControlSocketInUse(path.to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            fs::remove_file(path).map_err(io_err)
+        }
+        Err(e) => Err(io_err(e)),
+    }
+}
+
+/// Bind a socket that is only accessible by the owner. The socket is created
+/// inside a private directory and then moved into place so that no other user
+/// can connect before its permissions are restricted.
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let io_err = |p: &Path, e| Error::Io { path: p.to_owned(), source: e };
+
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_dir = parent.join(format!(".{}.{}.tmp", file_name, process::id()));
+    let temp_path = temp_dir.join("socket");
+
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&temp_dir)
+        .map_err(|e| io_err(&temp_dir, e))?;
+
+    let result = UnixListener::bind(&temp_path)
+        .map_err(|e| io_err(&temp_path, e))
+        .and_then(|listener| {
+            fs::set_permissions(&temp_path, fs::Permissions::from_mode(0o600))
+                .map_err(|e| io_err(&temp_path, e))?;
+            fs::rename(&temp_path, path)
+                .map_err(|e| io_err(path, e))?;
+
+            Ok(listener)
+        });
+
+    if let Err(e) = fs::remove_dir_all(&temp_dir) {
+        warn!("Failed to remove temporary directory: {:?}: {}", temp_dir, e);
+    }
+
+    result
+}
+
+/// Serve JSON requests on a Unix socket at the given path. Each request and
+/// response is a single line of JSON. The socket is only accessible by the
+/// owner. This only returns if the socket cannot be created.
+pub async fn serve(path: &Path, state: Arc<ControlState>) -> Result<()> {
+    remove_stale_socket(path)?;
+
+    let listener = bind_private(path)?;
+    let _guard = SocketGuard(path.to_owned());
+
+    info!("Listening on control socket: {:?}", path);
+
+    // Dropping the JoinSet aborts all client tasks
+    let mut clients = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            c = listener.accept() => {
+                match c {
+                    Ok((stream, _)) => {
+                        let state = state.clone();
+
+                        clients.spawn(async move {
+                            if let Err(e) = handle_client(stream, state).await {
+                                debug!("Control socket client error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept control socket client: {}", e),
+                }
+            }
+            // Reap finished clients
+            Some(_) = clients.join_next() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        serde_json::{json, Value},
+        crate::config::Zone,
+        super::*,
+    };
+
+    fn state() -> Arc<ControlState> {
+        let zone: Zone = toml::from_str(r#"
+            session = "sim"
+            ipmi_zones = [0]
+            sources = [{ type = "ipmi", sensor = "CPU Temp" }]
+        "#).unwrap();
+
+        Arc::new(ControlState {
+            sessions: vec![],
+            zones: vec![Arc::new(ZoneHandle::new(&zone))],
+        })
+    }
+
+    /// Send each request line to a client handler and return the parsed
+    /// response lines.
+    async fn send(state: Arc<ControlState>, requests: &[&str]) -> Vec<Value> {
+        let (client, server) = UnixStream::pair().unwrap();
+        let handler = tokio::spawn(handle_client(server, state));
+        let (reader, mut writer) = client.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut responses = vec![];
+
+        for request in requests {
+            writer.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+            let line = lines.next_line().await.unwrap().unwrap();
+            responses.push(serde_json::from_str(&line).unwrap());
+        }
+
+        drop(writer);
+        handler.await.unwrap().unwrap();
+
+        responses
+    }
+
+    fn mode(state: &ControlState) -> ControlMode {
+        state.zones[0].current_mode()
+    }
+
+    #[tokio::test]
+    async fn requests() {
+        let state = state();
+
+        let responses = send(state.clone(), &[r#"{"command": "pause", "zone": 0}"#]).await;
+        assert_eq!(responses, [json!({"result": "ok"})]);
+        assert!(matches!(mode(&state), ControlMode::Paused));
+
+        let responses = send(state.clone(), &[
+            r#"{"command": "manual", "zone": 0, "dcycle": 60, "duration_secs": 3600}"#,
+        ]).await;
+        assert_eq!(responses, [json!({"result": "ok"})]);
+        assert!(matches!(mode(&state), ControlMode::Manual { dcycle: 60, until: Some(_) }));
+
+        let responses = send(state.clone(), &[
+            r#"{"command": "status"}"#,
+            r#"{"command": "resume", "zone": 0}"#,
+        ]).await;
+        assert_eq!(responses[0]["result"], "status");
+        assert_eq!(responses[0]["zones"][0]["mode"]["type"], "manual");
+        assert_eq!(responses[0]["zones"][0]["mode"]["dcycle"], 60);
+        assert_eq!(responses[1], json!({"result": "ok"}));
+        assert!(matches!(mode(&state), ControlMode::Auto));
+    }
+
+    #[tokio::test]
+    async fn error_replies() {
+        let state = state();
+
+        let responses = send(state.clone(), &[
+            "not json",
+            r#"{"command": "reboot"}"#,
+            r#"{"command": "pause"}"#,
+            r#"{"command": "pause", "zone": 1}"#,
+            r#"{"command": "manual", "zone": 0, "dcycle": 101}"#,
+            r#"{"command": "resume", "zone": 0}"#,
+        ]).await;
+
+        for r in &responses[..3] {
+            assert_eq!(r["result"], "error");
+            assert!(r["message"].as_str().unwrap().starts_with("Invalid request: "), "{}", r);
+        }
+        assert_eq!(responses[3], json!({"result": "error", "message": "Zone does not exist: 1"}));
+        assert_eq!(responses[4],
+                   json!({"result": "error", "message": "Invalid duty cycle percentage: 101"}));
+
+        // The connection is still usable after errors
+        assert_eq!(responses[5], json!({"result": "ok"}));
+        assert!(matches!(mode(&state), ControlMode::Auto));
+    }
+}
@@ -0,0 +1,431 @@
+use {
+    std::{
+        collections::BTreeMap,
+        fs,
+        io::Write,
+        path::Path,
+        sync::{
+            Arc,
+            atomic::AtomicU64,
+            Mutex,
+        },
+        time::{Duration, Instant},
+    },
+    serde::Deserialize,
+    crate::{
+        config::{IpmiUnits, SessionType, Source, Zone, ZoneSource},
+        error::{Error, Result},
+        freeipmi::{SensorReading, SensorUnits, SensorValue},
+        ipmi::{self, FanMode, IpmiBackend, RawThresholds, Sensor, SensorRecord},
+        status::ZoneHandle,
+        IpmiSession,
+        MainApp,
+        ZoneState,
+    },
+};
+
+/// Heat load that applies from `time_secs` until the next point.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoadPoint {
+    pub time_secs: f64,
+    pub watts: f64,
+}
+
+/// First-order thermal model of the components cooled by a zone. The
+/// temperature changes according to:
+///
+/// ```text
+/// C * dT/dt = P(t) - (passive_cooling + fan_cooling * dcycle / 100) * (T - ambient)
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThermalModel {
+    /// Ambient temperature in degrees Celsius
+    pub ambient_c: f64,
+    /// Initial temperature in degrees Celsius (default: ambient)
+    pub initial_c: Option<f64>,
+    /// Heat capacity (C) in J/K
+    pub heat_capacity: f64,
+    /// Heat dissipated without any airflow in W/K
+    #[serde(default)]
+    pub passive_cooling: f64,
+    /// Additional heat dissipated at 100% duty cycle in W/K
+    pub fan_cooling: f64,
+    /// Length of the simulation in seconds
+    pub duration_secs: f64,
+    /// Heat load profile (P). The load is 0 W before the first point.
+    pub load: Vec<LoadPoint>,
+}
+
+impl ThermalModel {
+    /// Load and validate a thermal model from a TOML file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::Io { path: path.to_owned(), source: e })?;
+
+        let model: Self = toml::from_str(&contents)
+            .map_err(|e| Error::ConfigParse { path: path.to_owned(), source: e })?;
+
+        let invalid = |reason: &str| Error::ConfigValidation {
+            path: path.to_owned(),
+            reason: reason.to_owned(),
+        };
+
+        let finite = [model.ambient_c, model.initial_c.unwrap_or_default()];
+        if finite.iter().any(|v| !v.is_finite()) {
+            return Err(invalid("ambient_c, initial_c: must be finite numbers"));
+        } else if !(model.heat_capacity.is_finite() && model.heat_capacity > 0.0) {
+            return Err(invalid("heat_capacity: must be greater than 0"));
+        } else if !(model.passive_cooling.is_finite() && model.passive_cooling >= 0.0) {
+            return Err(invalid("passive_cooling: must be a non-negative number"));
+        } else if !(model.fan_cooling.is_finite() && model.fan_cooling >= 0.0) {
+            return Err(invalid("fan_cooling: must be a non-negative number"));
+        } else if !(model.duration_secs.is_finite() && model.duration_secs > 0.0) {
+            return Err(invalid("duration_secs: must be greater than 0"));
+        } else if model.load.is_empty() {
+            return Err(invalid("load: must be non-empty"));
+        } else if model.load.iter().any(|p| !p.time_secs.is_finite() || !p.watts.is_finite()) {
+            return Err(invalid("load[*]: time_secs and watts must be finite numbers"));
+        } else if model.load.windows(2).any(|w| w[0].time_secs >= w[1].time_secs) {
+            return Err(invalid("load[*].time_secs: values are not strictly increasing"));
+        }
+
+        Ok(model)
+    }
+
+    /// Get the heat load at the given time.
+    fn watts(&self, time_secs: f64) -> f64 {
+        self.load
+            .iter()
+            .take_while(|p| p.time_secs <= time_secs)
+            .last()
+            .map_or(0.0, |p| p.watts)
+    }
+
+    /// Get the temperature after `secs` seconds, assuming that the heat load
+    /// and duty cycle stay constant. This uses the exact solution of the
+    /// differential equation, so it is stable for any step size.
+    fn step(&self, temp: f64, watts: f64, dcycle: u8, secs: f64) -> f64 {
+        let k = self.passive_cooling + self.fan_cooling * f64::from(dcycle) / 100.0;
+
+        if k == 0.0 {
+            return temp + watts * secs / self.heat_capacity;
+        }
+
+        let steady = self.ambient_c + watts / k;
+
+        steady + (temp - steady) * (-k * secs / self.heat_capacity).exp()
+    }
+}
+
+/// State of the simulated hardware.
+#[derive(Debug, Default)]
+struct Plant {
+    temp: f64,
+    /// Names of the sensors that report the temperature
+    sensors: Vec<String>,
+    duty_cycles: BTreeMap<u8, u8>,
+}
+
+/// Backend that reports the model temperature on every sensor and keeps the
+/// duty cycles for the model.
+struct ThermalBackend {
+    plant: Arc<Mutex<Plant>>,
+    errors: Arc<AtomicU64>,
+}
+
+impl IpmiBackend for ThermalBackend {
+    fn error_counter(&self) -> Arc<AtomicU64> {
+        self.errors.clone()
+    }
+
+    fn get_fan_mode(&mut self) -> ipmi::Result<FanMode> {
+        Ok(FanMode::Full)
+    }
+
+    fn set_fan_mode(&mut self, _mode: FanMode) -> ipmi::Result<()> {
+        Ok(())
+    }
+
+    fn get_duty_cycle(&mut self, zone: u8) -> ipmi::Result<u8> {
+        Ok(self.plant.lock().unwrap().duty_cycles.get(&zone).copied().unwrap_or(100))
+    }
+
+    fn set_duty_cycle(&mut self, zone: u8, dcycle: u8) -> ipmi::Result<()> {
+        self.plant.lock().unwrap().duty_cycles.insert(zone, dcycle);
+        Ok(())
+    }
+
+    fn get_sensor_thresholds(&mut self, _sensor_number: u8) -> ipmi::Result<RawThresholds> {
+        Ok(RawThresholds::default())
+    }
+
+    fn set_sensor_thresholds(
+        &mut self,
+        _sensor_number: u8,
+        _thresholds: &RawThresholds,
+    ) -> ipmi::Result<()> {
+        Ok(())
+    }
+
+    fn get_temperature_readings(&mut self) -> ipmi::Result<Vec<Sensor>> {
+        let plant = self.plant.lock().unwrap();
+
+        Ok(plant.sensors
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Sensor {
+                record_id: i as u16 + 1,
+                name: name.clone(),
+                entity_id: 0,
+                entity_instance: 0,
+                reading: Some(SensorReading {
+                    value: SensorValue::Double(plant.temp),
+                    units: SensorUnits::Celsius,
+                }),
+            })
+            .collect())
+    }
+
+    fn get_fan_readings(&mut self) -> ipmi::Result<Vec<Sensor>> {
+        Ok(vec![])
+    }
+
+    fn get_fan_records(&mut self) -> ipmi::Result<Vec<SensorRecord>> {
+        Ok(vec![])
+    }
+}
+
+/// Run a zone's control logic against the thermal model and write a CSV
+/// timeline. Every source of the zone is replaced by a sensor that reports the
+/// model temperature, so the aggregation, smoothing, controller, and limits
+/// all behave as they would with real readings. Stall detection is disabled
+/// because the model has no fans. The simulation advances by the zone's
+/// interval on each iteration without waiting.
+pub fn simulate(zone_config: &Zone, model: &ThermalModel, out: &mut dyn Write) -> Result<()> {
+    let mut zone_config = zone_config.clone();
+    let sensors: Vec<_> = (0..zone_config.sources.len())
+        .map(|i| format!("source {}", i))
+        .collect();
+
+    zone_config.sources = zone_config.sources
+        .iter()
+        .zip(&sensors)
+        .map(|(s, name)| ZoneSource {
+            source: Source::Ipmi {
+                sensor: Some(name.clone()),
+                sensor_regex: None,
+                record_id: None,
+                entity_id: None,
+                entity_instance: None,
+                units: IpmiUnits::Celsius,
+            },
+            ..s.clone()
+        })
+        .collect();
+    zone_config.stall_detection = None;
+
+    let plant = Arc::new(Mutex::new(Plant {
+        temp: model.initial_c.unwrap_or(model.ambient_c),
+        sensors,
+        duty_cycles: BTreeMap::new(),
+    }));
+    let backend = ThermalBackend {
+        plant: plant.clone(),
+        errors: Arc::default(),
+    };
+    let session = Arc::new(IpmiSession::with_backend(
+        "simulate",
+        &SessionType::Local,
+        Box::new(backend),
+        zone_config.ipmi_zones.clone(),
+        false,
+    )?);
+
+    let handle = ZoneHandle::new(&zone_config);
+    let mut state = ZoneState::default();
+    let interval = zone_config.interval.to_duration().as_secs_f64();
+    let write_err = |e| Error::Io { path: "(output)".into(), source: e };
+
+    writeln!(out, "time_secs,load_watts,temp_c,smoothed_temp_c,dcycle")
+        .map_err(write_err)?;
+
+    let start = Instant::now();
+    let mut time = 0.0;
+
+    while time <= model.duration_secs {
+        let clock = || start + Duration::from_secs_f64(time);
+        MainApp::update_duty_cycle(session.clone(), &zone_config, &handle, &mut state, &clock)?;
+
+        let smoothed = handle.status.lock().unwrap().temp.unwrap_or_default();
+        let mut plant = plant.lock().unwrap();
+        // Every IPMI zone is set to the same duty cycle
+        let dcycle = plant.duty_cycles.get(&zone_config.ipmi_zones[0]).copied().unwrap_or(100);
+        let watts = model.watts(time);
+
+        writeln!(out, "{},{},{:.2},{:.2},{}", time, watts, plant.temp, smoothed, dcycle)
+            .map_err(write_err)?;
+
+        plant.temp = model.step(plant.temp, watts, dcycle, interval);
+        time += interval;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        tempfile::TempDir,
+        super::*,
+    };
+
+    const MODEL: &str = r#"
+        ambient_c = 25.0
+        heat_capacity = 100.0
+        passive_cooling = 1.0
+        fan_cooling = 10.0
+        duration_secs = 60.0
+        load = [{ time_secs = 0.0, watts = 0.0 }, { time_secs = 20.0, watts = 300.0 }]
+    "#;
+
+    /// Write a model to a temporary file and load it.
+    fn load_model(contents: &str) -> Result<ThermalModel> {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("model.toml");
+        fs::write(&path, contents).unwrap();
+
+        ThermalModel::load(&path)
+    }
+
+    fn model(passive_cooling: f64, fan_cooling: f64) -> ThermalModel {
+        ThermalModel {
+            passive_cooling,
+            fan_cooling,
+            ..load_model(MODEL).unwrap()
+        }
+    }
+
+    #[test]
+    fn load_validation() {
+        let model = load_model(MODEL).unwrap();
+        assert_eq!(model.watts(-1.0), 0.0);
+        assert_eq!(model.watts(19.9), 0.0);
+        assert_eq!(model.watts(20.0), 300.0);
+        assert_eq!(model.watts(1000.0), 300.0);
+
+        for (replace, with, reason) in [
+            ("heat_capacity = 100.0", "heat_capacity = 0.0", "heat_capacity"),
+            ("heat_capacity = 100.0", "heat_capacity = -1.0", "heat_capacity"),
+            ("passive_cooling = 1.0", "passive_cooling = -1.0", "passive_cooling"),
+            ("fan_cooling = 10.0", "fan_cooling = -1.0", "fan_cooling"),
+            ("duration_secs = 60.0", "duration_secs = 0.0", "duration_secs"),
+            ("ambient_c = 25.0", "ambient_c = nan", "ambient_c"),
+            ("time_secs = 20.0", "time_secs = 0.0", "load[*].time_secs"),
+            ("time_secs = 20.0", "time_secs = -5.0", "load[*].time_secs"),
+            ("watts = 300.0", "watts = inf", "load[*]"),
+        ] {
+            let contents = MODEL.replace(replace, with);
+
+            assert!(matches!(
+                load_model(&contents),
+                Err(Error::ConfigValidation { reason: r, .. }) if r.starts_with(reason),
+            ), "{with}");
+        }
+
+        let contents = MODEL.replace(
+            "load = [{ time_secs = 0.0, watts = 0.0 }, { time_secs = 20.0, watts = 300.0 }]",
+            "load = []",
+        );
+        assert!(matches!(
+            load_model(&contents),
+            Err(Error::ConfigValidation { reason, .. }) if reason.starts_with("load:"),
+        ));
+    }
+
+    #[test]
+    fn step_exact_solution() {
+        let model = model(1.0, 10.0);
+
+        for (temp, watts, dcycle, secs) in [
+            (25.0, 300.0, 0, 1.0),
+            (25.0, 300.0, 100, 1.0),
+            (60.0, 0.0, 50, 10.0),
+            (40.0, 100.0, 30, 1000.0),
+        ] {
+            let k = 1.0 + 10.0 * f64::from(dcycle) / 100.0;
+            let steady = 25.0 + watts / k;
+            let expected = steady + (temp - steady) * (-k * secs / 100.0).exp();
+
+            assert!((model.step(temp, watts, dcycle, secs) - expected).abs() < 1e-9,
+                    "temp={temp}, watts={watts}, dcycle={dcycle}, secs={secs}");
+        }
+
+        // Splitting a step does not change the result
+        let whole = model.step(30.0, 200.0, 40, 8.0);
+        let split = (0..8).fold(30.0, |t, _| model.step(t, 200.0, 40, 1.0));
+        assert!((whole - split).abs() < 1e-9);
+
+        // Converges to the steady state
+        assert!((model.step(30.0, 200.0, 100, 1e6) - (25.0 + 200.0 / 11.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn step_no_cooling() {
+        let model = model(0.0, 10.0);
+
+        assert_eq!(model.step(30.0, 200.0, 0, 5.0), 40.0);
+        assert_eq!(model.step(30.0, 0.0, 0, 5.0), 30.0);
+    }
+
+    #[test]
+    fn simulate_load_step() {
+        let zone: Zone = toml::from_str(r#"
+            ipmi_zones = [0]
+            interval = 1
+            sources = [{ type = "ipmi", sensor = "CPU Temp" }]
+            steps = [{ temp = 30, dcycle = 20 }, { temp = 70, dcycle = 100 }]
+        "#).unwrap();
+        let mut out = vec![];
+
+        simulate(&zone, &load_model(MODEL).unwrap(), &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+
+        assert_eq!(lines[0], "time_secs,load_watts,temp_c,smoothed_temp_c,dcycle");
+        assert_eq!(lines[1], "0,0,25.00,25.00,20");
+        assert_eq!(lines.len(), 62);
+
+        let rows: Vec<(f64, f64, f64, u8)> = lines[1..]
+            .iter()
+            .map(|l| {
+                let fields: Vec<_> = l.split(',').collect();
+                assert_eq!(fields.len(), 5, "{l}");
+                (
+                    fields[0].parse().unwrap(),
+                    fields[1].parse().unwrap(),
+                    fields[2].parse().unwrap(),
+                    fields[4].parse().unwrap(),
+                )
+            })
+            .collect();
+
+        // No load before the step, so the temperature stays at ambient
+        for &(time, watts, temp, dcycle) in &rows[..20] {
+            assert_eq!((watts, temp, dcycle), (0.0, 25.0, 20), "time={time}");
+        }
+
+        // The duty cycle rises with the temperature after the step
+        for w in rows[20..].windows(2) {
+            assert_eq!(w[1].1, 300.0);
+            assert!(w[1].3 >= w[0].3, "{:?} -> {:?}", w[0], w[1]);
+        }
+
+        let last = rows.last().unwrap();
+        assert_eq!(last.0, 60.0);
+        assert!(last.2 > 30.0, "{last:?}");
+        assert!(last.3 > 40, "{last:?}");
+    }
+}
@@ -0,0 +1,262 @@
+use {
+    std::{
+        collections::{BTreeMap, HashMap},
+        sync::{
+            Arc,
+            atomic::{AtomicU64, Ordering},
+        },
+    },
+    log::{debug, info},
+    crate::{
+        config::{SimulatedBmc, SimulatedSensor},
+        freeipmi::{SensorReading, SensorUnits, SensorValue},
+        ipmi::{Error, FanMode, IpmiBackend, RawThresholds, Result, Sensor, SensorRecord},
+    },
+};
+
+/// Duty cycle of zones that have not been set yet. This matches the full fan
+/// mode.
+const DEFAULT_DUTY_CYCLE: u8 = 100;
+
+/// Default raw lower thresholds (lower non-critical, lower critical, lower
+/// non-recoverable) of simulated fans. These are 700, 500, and 300 RPM.
+const DEFAULT_THRESHOLDS: [u8; 6] = [7, 5, 3, 0, 0, 0];
+
+/// Number of RPM per raw threshold count (the `M` factor in the SDR).
+const RPM_PER_COUNT: u8 = 100;
+
+/// State of a simulated BMC.
+#[derive(Debug)]
+struct SimulatedState {
+    fan_mode: FanMode,
+    /// Duty cycle of each zone that has been set
+    duty_cycles: BTreeMap<u8, u8>,
+    /// Raw thresholds of each fan sensor, keyed by sensor number
+    thresholds: BTreeMap<u8, [u8; 6]>,
+    temperature_sensors: Vec<SimulatedSensor>,
+    fan_sensors: Vec<SimulatedSensor>,
+    /// Number of times each operation has been called
+    calls: HashMap<&'static str, u64>,
+    /// Call numbers of each operation that should fail
+    failures: HashMap<&'static str, Vec<u64>>,
+}
+
+impl SimulatedState {
+    /// Count a call to an operation and return an error if the call should
+    /// fail.
+    fn call(&mut self, operation: &'static str) -> Result<u64> {
+        let n = self.calls.entry(operation).or_default();
+        *n += 1;
+
+        if self.failures.get(operation).is_some_and(|f| f.contains(n)) {
+            debug!("Simulated BMC: failing call {} to {}", n, operation);
+            return Err(Error::Simulated(operation));
+        }
+
+        Ok(*n)
+    }
+}
+
+/// In-memory BMC that keeps the fan mode and zone duty cycles, reports scripted
+/// sensor values, and fails the configured calls.
+pub struct SimulatedIpmi {
+    state: SimulatedState,
+    errors: Arc<AtomicU64>,
+}
+
+impl SimulatedIpmi {
+    pub fn new(bmc: &SimulatedBmc) -> Self {
+        let failures = [
+            ("get_fan_mode", &bmc.failures.get_fan_mode),
+            ("set_fan_mode", &bmc.failures.set_fan_mode),
+            ("get_duty_cycle", &bmc.failures.get_duty_cycle),
+            ("set_duty_cycle", &bmc.failures.set_duty_cycle),
+            ("get_temperature_readings", &bmc.failures.get_temperature_readings),
+            ("get_fan_readings", &bmc.failures.get_fan_readings),
+        ];
+
+        let state = SimulatedState {
+            fan_mode: bmc.fan_mode.unwrap_or(FanMode::Standard),
+            duty_cycles: BTreeMap::new(),
+            thresholds: (0..bmc.fan_sensors.len())
+                .map(|i| (i as u8, DEFAULT_THRESHOLDS))
+                .collect(),
+            temperature_sensors: bmc.temperature_sensors.clone(),
+            fan_sensors: bmc.fan_sensors.clone(),
+            calls: HashMap::new(),
+            failures: failures
+                .into_iter()
+                .map(|(op, calls)| (op, calls.clone()))
+                .collect(),
+        };
+
+        Self {
+            state,
+            errors: Arc::default(),
+        }
+    }
+
+    /// Run an operation on the state and increment the error counter if the
+    /// result is an error.
+    fn with_state<T>(&mut self, f: impl FnOnce(&mut SimulatedState) -> Result<T>) -> Result<T> {
+        let result = f(&mut self.state);
+
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+}
+
+/// Get the readings of simulated sensors for the `n`th call (starting at 1).
+fn readings(sensors: &[SimulatedSensor], n: u64, units: SensorUnits) -> Vec<Sensor> {
+    sensors
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let index = usize::try_from(n - 1).unwrap_or(usize::MAX)
+                .min(s.values.len().saturating_sub(1));
+
+            Sensor {
+                record_id: i as u16 + 1,
+                name: s.name.clone(),
+                entity_id: 0,
+                entity_instance: 0,
+                reading: s.values.get(index).map(|v| SensorReading {
+                    value: SensorValue::Double(*v),
+                    units,
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Build a minimal full sensor record for a simulated fan. The record is
+/// owned by the BMC, has readable and settable lower thresholds, and uses
+/// linear conversion with `M = 100`.
+fn fan_record(name: &str, sensor_number: u8) -> Vec<u8> {
+    let mut sdr = vec![0u8; 48];
+
+    // Record type: full sensor record
+    sdr[3] = 0x01;
+    // Sensor owner: BMC, LUN 0
+    sdr[5] = 0x20;
+    sdr[7] = sensor_number;
+    // Threshold access: readable and settable
+    sdr[11] = 0b10 << 2;
+    // Readable and settable lower thresholds
+    sdr[18] = 0b111;
+    sdr[19] = 0b111;
+    // M
+    sdr[24] = RPM_PER_COUNT;
+    sdr.extend_from_slice(name.as_bytes());
+
+    sdr
+}
+
+impl Drop for SimulatedIpmi {
+    /// Log the final state so that it can be checked that everything was
+    /// restored on exit.
+    fn drop(&mut self) {
+        let state = &self.state;
+
+        info!("Simulated BMC: final fan mode: {}, duty cycles: {:?}, thresholds: {:?}",
+              state.fan_mode, state.duty_cycles, state.thresholds);
+    }
+}
+
+impl IpmiBackend for SimulatedIpmi {
+    fn error_counter(&self) -> Arc<AtomicU64> {
+        self.errors.clone()
+    }
+
+    fn get_fan_mode(&mut self) -> Result<FanMode> {
+        self.with_state(|s| {
+            s.call("get_fan_mode")?;
+            Ok(s.fan_mode)
+        })
+    }
+
+    fn set_fan_mode(&mut self, mode: FanMode) -> Result<()> {
+        self.with_state(|s| {
+            s.call("set_fan_mode")?;
+            debug!("Simulated BMC: fan mode set to {}", mode);
+            s.fan_mode = mode;
+            Ok(())
+        })
+    }
+
+    fn get_duty_cycle(&mut self, zone: u8) -> Result<u8> {
+        self.with_state(|s| {
+            s.call("get_duty_cycle")?;
+            Ok(s.duty_cycles.get(&zone).copied().unwrap_or(DEFAULT_DUTY_CYCLE))
+        })
+    }
+
+    fn set_duty_cycle(&mut self, zone: u8, dcycle: u8) -> Result<()> {
+        self.with_state(|s| {
+            s.call("set_duty_cycle")?;
+            debug!("Simulated BMC: zone {} duty cycle set to {}%", zone, dcycle);
+            s.duty_cycles.insert(zone, dcycle);
+            Ok(())
+        })
+    }
+
+    fn get_sensor_thresholds(&mut self, sensor_number: u8) -> Result<RawThresholds> {
+        self.with_state(|s| {
+            s.call("get_sensor_thresholds")?;
+            let values = s.thresholds.get(&sensor_number).copied().unwrap_or_default();
+            Ok(RawThresholds { mask: 0b111, values })
+        })
+    }
+
+    fn set_sensor_thresholds(
+        &mut self,
+        sensor_number: u8,
+        thresholds: &RawThresholds,
+    ) -> Result<()> {
+        self.with_state(|s| {
+            s.call("set_sensor_thresholds")?;
+            debug!("Simulated BMC: sensor {} thresholds set to {:?}", sensor_number, thresholds);
+
+            let values = s.thresholds.entry(sensor_number).or_default();
+            for (i, v) in values.iter_mut().enumerate() {
+                if thresholds.mask & (1 << i) != 0 {
+                    *v = thresholds.values[i];
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_temperature_readings(&mut self) -> Result<Vec<Sensor>> {
+        self.with_state(|s| {
+            let n = s.call("get_temperature_readings")?;
+            Ok(readings(&s.temperature_sensors, n, SensorUnits::Celsius))
+        })
+    }
+
+    fn get_fan_readings(&mut self) -> Result<Vec<Sensor>> {
+        self.with_state(|s| {
+            let n = s.call("get_fan_readings")?;
+            Ok(readings(&s.fan_sensors, n, SensorUnits::Rpm))
+        })
+    }
+
+    fn get_fan_records(&mut self) -> Result<Vec<SensorRecord>> {
+        self.with_state(|s| {
+            s.call("get_fan_records")?;
+            Ok(s.fan_sensors
+                .iter()
+                .enumerate()
+                .map(|(i, f)| SensorRecord {
+                    name: f.name.clone(),
+                    sensor_number: i as u8,
+                    sdr: fan_record(&f.name, i as u8),
+                })
+                .collect())
+        })
+    }
+}
@@ -1,25 +1,49 @@
 use {
     std::{
-        collections::{HashMap, HashSet},
-        convert::TryInto,
+        collections::HashMap,
         fs,
-        io::{BufRead, BufReader, Read},
-        path::Path,
-        process::{Command, Stdio},
-        sync::{Arc, Mutex},
+        io::{self, BufRead, BufReader, Read},
+        path::{Path, PathBuf},
+        process::{Child, Command, ExitStatus, Stdio},
+        sync::{mpsc, Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
     },
+    log::{debug, trace, warn},
     crate::{
-        config::Source,
+        config::{CommandParser, FileFormat, IpmiUnits, Source, TempUnits, ZoneSource},
         error::{Error, Result},
-        freeipmi::{SensorUnits, SensorValue},
-        ipmi::Ipmi,
+        freeipmi::{SensorReading, SensorUnits, SensorValue},
+        ipmi::{IpmiBackend, Sensor},
     },
 };
 
+/// Check that a command exited with one of the accepted exit codes. If not,
+/// the error includes the first line of the command's stderr, which is only
+/// read in that case.
+fn check_exit_status(
+    command: &Path,
+    status: ExitStatus,
+    accepted: &[i32],
+    stderr: impl FnOnce() -> Vec<u8>,
+) -> Result<()> {
+    if matches!(status.code(), Some(c) if accepted.contains(&c)) {
+        return Ok(());
+    }
+
+    let stderr = stderr();
+    let stderr = String::from_utf8_lossy(&stderr);
+
+    Err(Error::Command {
+        command: command.to_owned(),
+        status,
+        stderr: stderr.lines().next().unwrap_or_default().to_owned(),
+    })
+}
+
 /// Get the temperature of a hard drive via smartctl. This function fails if
-/// smartctl does not return temperature data (eg. if a drive is in standby) or
-/// if the reported temperature does not fit in a [`u8`].
-fn parse_smart_source<T: AsRef<Path>>(block_dev: T) -> Result<u8> {
+/// smartctl does not return temperature data (eg. if a drive is in standby).
+fn parse_smart_source<T: AsRef<Path>>(block_dev: T) -> Result<f64> {
     let block_dev = block_dev.as_ref();
 
     let mut proc = Command::new("smartctl")
@@ -29,18 +53,16 @@ fn parse_smart_source<T: AsRef<Path>>(block_dev: T) -> Result<u8> {
         .arg("standby")
         .arg(block_dev)
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
         .map_err(|e| Error::Io { path: "(smartctl)".into(), source: e })?;
 
     let result = serde_json::from_reader(proc.stdout.take().unwrap());
-    let status = proc.wait()
+    let output = proc.wait_with_output()
         .map_err(|e| Error::Io { path: "(smartctl)".into(), source: e })?;
 
-    match status.code() {
-        // smartctl will return status code 2 when a drive is in standby
-        Some(0) | Some(2) => {},
-        _ => return Err(Error::Command { command: "smartctl".into(), status }),
-    }
+    // smartctl will return status code 2 when a drive is in standby
+    check_exit_status(Path::new("smartctl"), output.status, &[0, 2], || output.stderr)?;
 
     let root: serde_json::Value = result
         .map_err(|e| Error::SmartParse { block_dev: block_dev.to_owned(), source: e })?;
@@ -49,14 +71,13 @@ fn parse_smart_source<T: AsRef<Path>>(block_dev: T) -> Result<u8> {
         .get("temperature")
         .and_then(|v| v.get("current"))
         .ok_or_else(|| Error::SmartNoReading(block_dev.to_owned()))?
-        .as_u64()
-        .and_then(|v| v.try_into().ok())
+        .as_f64()
         .ok_or(Error::ReadingExceedsBounds)?;
 
     Ok(temperature)
 }
 
-fn parse_hdparm_output(block_dev: &Path, stdout: &mut dyn Read) -> Result<u8> {
+fn parse_hdparm_output(block_dev: &Path, stdout: &mut dyn Read) -> Result<f64> {
     let mut reader = BufReader::new(stdout);
     let mut line = String::new();
 
@@ -80,133 +101,981 @@ fn parse_hdparm_output(block_dev: &Path, stdout: &mut dyn Read) -> Result<u8> {
         let temperature = last_token
             // Can be negative, but is within the bounds of 1 byte
             .parse::<i8>()
-            .map_err(|e| Error::SensorValueParse { value: last_token.to_owned(), source: e })?
-            .try_into()
-            .map_err(|_| Error::ReadingExceedsBounds)?;
+            .map_err(|e| Error::SensorValueParse { value: last_token.to_owned(), source: e.into() })?;
 
-        return Ok(temperature);
+        return Ok(f64::from(temperature));
     }
 }
 
 /// Get the temperature of a Hitachi/HGST/WD drive via hdparm. This function
-/// fails if hdparm does not print the temperature line, hdparm prints the bad
-/// sense data line, or if the reported temperature does not fit in a [`u8`].
-fn parse_hdparm_source<T: AsRef<Path>>(block_dev: T) -> Result<u8> {
+/// fails if hdparm does not print the temperature line or if hdparm prints the
+/// bad sense data line.
+fn parse_hdparm_source<T: AsRef<Path>>(block_dev: T) -> Result<f64> {
     let block_dev = block_dev.as_ref();
 
     let mut proc = Command::new("hdparm")
         .arg("-H")
         .arg(block_dev)
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
         .map_err(|e| Error::Io { path: "(hdparm)".into(), source: e })?;
 
     let result = parse_hdparm_output(block_dev, &mut proc.stdout.take().unwrap());
-    let status = proc.wait()
+    let output = proc.wait_with_output()
         .map_err(|e| Error::Io { path: "(hdparm)".into(), source: e })?;
 
-    if status.code() != Some(0) {
-        return Err(Error::Command { command: "hdparm".into(), status });
-    }
+    check_exit_status(Path::new("hdparm"), output.status, &[0], || output.stderr)?;
 
     result
 }
 
+/// How long to wait for a killed command's output pipes to close.
+const PIPE_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Read all of `pipe` from a separate thread so that a timeout can be applied
+/// even if the pipe is never closed.
+fn spawn_reader(mut pipe: impl Read + Send + 'static) -> mpsc::Receiver<io::Result<Vec<u8>>> {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let result = pipe.read_to_end(&mut output).map(|_| output);
+        let _ = tx.send(result);
+    });
+
+    rx
+}
+
+/// Kill a command that has not exited yet. On unix, this kills the command's
+/// whole process group so that background children holding its output pipes
+/// open are killed too. This must happen before the command is reaped so that
+/// the process group ID cannot be reused.
+fn kill_command(proc: &mut Child) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(proc.id() as libc::pid_t), libc::SIGKILL);
+    }
+
+    let _ = proc.kill();
+    let _ = proc.wait();
+}
+
+/// Run a command and return its stdout. The command is killed if it does not
+/// exit within `timeout`. An error, including the first line of stderr, is
+/// returned if the exit code is not one of the `exit_codes`.
+fn run_command(argv: &[String], timeout: Duration, exit_codes: &[i32]) -> Result<String> {
+    let command = Path::new(&argv[0]);
+    let io_error = |e| Error::Io { path: command.to_owned(), source: e };
+
+    let mut cmd = Command::new(command);
+    cmd.args(&argv[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+
+    let mut proc = cmd.spawn().map_err(io_error)?;
+    let stdout_rx = spawn_reader(proc.stdout.take().unwrap());
+    let stderr_rx = spawn_reader(proc.stderr.take().unwrap());
+
+    let deadline = Instant::now() + timeout;
+    let timed_out = |proc: &mut Child| {
+        kill_command(proc);
+
+        // Killing the command closes its pipes, which ends the reader threads.
+        // Only wait a bounded amount of time in case something outside the
+        // process group still holds them open.
+        for rx in [&stdout_rx, &stderr_rx] {
+            if rx.recv_timeout(PIPE_CLOSE_TIMEOUT).is_err() {
+                warn!("Output pipe still open after killing command: {command:?}");
+            }
+        }
+
+        Error::CommandTimeout { command: command.to_owned(), timeout }
+    };
+
+    let output = match stdout_rx.recv_timeout(timeout) {
+        Ok(r) => r.map_err(io_error)?,
+        Err(_) => return Err(timed_out(&mut proc)),
+    };
+
+    let status = loop {
+        if let Some(s) = proc.try_wait().map_err(io_error)? {
+            break s;
+        } else if Instant::now() >= deadline {
+            return Err(timed_out(&mut proc));
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    check_exit_status(command, status, exit_codes, || {
+        stderr_rx
+            .recv_timeout(PIPE_CLOSE_TIMEOUT)
+            .ok()
+            .and_then(|r| r.ok())
+            .unwrap_or_default()
+    })?;
+
+    String::from_utf8(output).map_err(|e| io_error(io::Error::new(io::ErrorKind::InvalidData, e)))
+}
+
+/// Extract a number from a command's output using the specified parser.
+fn parse_command_output(command: &Path, parser: &CommandParser, output: &str) -> Result<f64> {
+    let bad_value = |value: &str| Error::CommandBadValue {
+        command: command.to_owned(),
+        value: value.to_owned(),
+    };
+
+    let value = match parser {
+        CommandParser::Number => output.trim(),
+        CommandParser::Regex { pattern } => {
+            let captures = pattern.0.captures(output)
+                .ok_or_else(|| Error::CommandNoMatch(command.to_owned()))?;
+
+            captures.get(1)
+                .or_else(|| captures.get(0))
+                .unwrap()
+                .as_str()
+                .trim()
+        }
+        CommandParser::Json { pointer } => {
+            let root: serde_json::Value = serde_json::from_str(output)
+                .map_err(|e| Error::CommandJson { command: command.to_owned(), source: e })?;
+            let value = root.pointer(pointer)
+                .ok_or_else(|| Error::CommandJsonPointer {
+                    command: command.to_owned(),
+                    pointer: pointer.clone(),
+                })?;
+
+            // Some tools report numbers as strings
+            return match value {
+                serde_json::Value::Number(n) => n.as_f64(),
+                serde_json::Value::String(s) => s.trim().parse().ok(),
+                _ => None,
+            }.ok_or_else(|| bad_value(&value.to_string()));
+        }
+    };
+
+    value.parse().map_err(|_| bad_value(value))
+}
+
+/// Convert a reading to degrees Celsius. If the result is not a finite number,
+/// then [`Error::ReadingExceedsBounds`] is returned.
+fn to_celsius(value: f64, units: TempUnits) -> Result<f64> {
+    let celsius = match units {
+        TempUnits::Celsius => value,
+        TempUnits::Fahrenheit => (value - 32.0) * 5.0 / 9.0,
+        TempUnits::Kelvin => value - 273.15,
+    };
+
+    if !celsius.is_finite() {
+        return Err(Error::ReadingExceedsBounds);
+    }
+
+    Ok(celsius)
+}
+
+/// Get the temperature by running an arbitrary command and parsing its output.
+/// The parsed value is multiplied by `scale` and then converted from `units` to
+/// degrees Celsius.
+fn parse_command_source(
+    argv: &[String],
+    parser: &CommandParser,
+    timeout: Duration,
+    exit_codes: &[i32],
+    scale: f64,
+    units: TempUnits,
+) -> Result<f64> {
+    let output = run_command(argv, timeout, exit_codes)?;
+
+    trace!("Command output: {:?}: {:?}", argv, output);
+
+    let value = parse_command_output(Path::new(&argv[0]), parser, &output)?;
+
+    to_celsius(value * scale, units)
+}
+
 /// Get the temperature from a plain-text file (typically a sysfs path). The
-/// contents of the file should be a decimal-formatted integer in units of
-/// thousandths degrees Celsius after whitespace is trimmed. If the temperature,
-/// after being converted to degrees Celsius, does not fit in a [`u8`], then
-/// [`Error::ReadingExceedsBounds`] is returned.
-fn parse_file_source<T: AsRef<Path>>(path: T) -> Result<u8> {
+/// contents of the file, after whitespace is trimmed, should be a decimal
+/// number in the specified `format`. The number is multiplied by `scale`,
+/// `offset` is added, and the result is converted from `units` to degrees
+/// Celsius.
+fn parse_file_source<T: AsRef<Path>>(
+    path: T,
+    scale: f64,
+    offset: f64,
+    units: TempUnits,
+    format: FileFormat,
+) -> Result<f64> {
     let contents = fs::read_to_string(path.as_ref())
         .map_err(|e| Error::Io { path: path.as_ref().to_owned(), source: e })?;
     let trimmed = contents.trim();
 
-    // The file should be in milli-degrees Celsius
-    let temperature = trimmed
-        .parse::<u32>()
-        .map_err(|e| Error::SensorValueParse { value: trimmed.to_owned(), source: e })?
-        .checked_div(1000)
-        .and_then(|t| t.try_into().ok())
-        .ok_or(Error::ReadingExceedsBounds)?;
+    let value = match format {
+        FileFormat::Integer => trimmed.parse::<i64>().map(|v| v as f64).map_err(Into::into),
+        FileFormat::Float => trimmed.parse::<f64>().map_err(Into::into),
+    }.map_err(|e| Error::SensorValueParse { value: trimmed.to_owned(), source: e })?;
 
-    Ok(temperature)
+    to_celsius(value * scale + offset, units)
+}
+
+/// Default location of the hwmon devices in sysfs.
+const HWMON_ROOT: &str = "/sys/class/hwmon";
+
+/// Read a sysfs attribute with the trailing newline removed.
+fn read_attribute(path: &Path) -> Result<String> {
+    fs::read_to_string(path)
+        .map(|s| s.trim_end().to_owned())
+        .map_err(|e| Error::Io { path: path.to_owned(), source: e })
+}
+
+/// Find the `tempN_input` file for the temperature sensor labelled `label` in
+/// the hwmon device named `chip`. Sensors without a `tempN_label` file are
+/// labelled `tempN`. All devices in `root` are scanned because the device
+/// indices are not stable across boots. An error is returned if no device or
+/// sensor matches or if multiple sensors match.
+fn find_hwmon_input(root: &Path, chip: &str, label: &str) -> Result<PathBuf> {
+    let mut chip_found = false;
+    let mut paths = vec![];
+
+    let devices = fs::read_dir(root)
+        .map_err(|e| Error::Io { path: root.to_owned(), source: e })?;
+
+    for device in devices {
+        let device = device
+            .map_err(|e| Error::Io { path: root.to_owned(), source: e })?
+            .path();
+
+        // Not every device has a name
+        match read_attribute(&device.join("name")) {
+            Ok(n) if n == chip => chip_found = true,
+            _ => continue,
+        }
+
+        let attributes = fs::read_dir(&device)
+            .map_err(|e| Error::Io { path: device.clone(), source: e })?;
+
+        for attribute in attributes {
+            let attribute = attribute
+                .map_err(|e| Error::Io { path: device.clone(), source: e })?
+                .file_name();
+            let prefix = match attribute.to_str()
+                .and_then(|a| a.strip_suffix("_input"))
+                .filter(|p| p.starts_with("temp"))
+            {
+                Some(p) => p,
+                None => continue,
+            };
+
+            let label_path = device.join(format!("{}_label", prefix));
+            let sensor_label = if label_path.exists() {
+                read_attribute(&label_path)?
+            } else {
+                prefix.to_owned()
+            };
+
+            if sensor_label == label {
+                paths.push(device.join(&attribute));
+            }
+        }
+    }
+
+    if !chip_found {
+        return Err(Error::HwmonChipNotFound(chip.to_owned()));
+    }
+
+    match paths.len() {
+        0 => Err(Error::HwmonLabelNotFound {
+            chip: chip.to_owned(),
+            label: label.to_owned(),
+        }),
+        1 => Ok(paths.pop().unwrap()),
+        _ => {
+            paths.sort();
+
+            Err(Error::HwmonAmbiguous {
+                chip: chip.to_owned(),
+                label: label.to_owned(),
+                paths,
+            })
+        }
+    }
 }
 
-/// Get the temperatures for the given list of sensors from IPMI. This queries
-/// all temperature sensors and then filters the results. This function only
-/// fails if the IPMI sensor query fails. If a sensor's unit is not degrees
-/// Celsius or if the value exceeds the bounds of a `u8`, then the reported
-/// value of that sensor will be `None`.
-fn parse_ipmi_sources(ipmi: Arc<Mutex<Ipmi>>, sensors: &HashSet<String>)
-    -> Result<HashMap<String, u8>>
-{
-    if sensors.is_empty() {
-        return Ok(HashMap::default());
+/// Get the temperature from the hwmon sensor labelled `label` in the device
+/// named `chip`. The sensor is looked up again for every reading because the
+/// device indices can change if a driver is reloaded.
+fn parse_hwmon_source(root: &Path, chip: &str, label: &str) -> Result<f64> {
+    let path = find_hwmon_input(root, chip, label)?;
+
+    // hwmon temperatures are always in milli-degrees Celsius
+    parse_file_source(path, 0.001, 0.0, TempUnits::Celsius, FileFormat::Integer)
+}
+
+/// Get the temperature of an NVMe drive from its SMART / Health Information log
+/// page. This is only supported on Linux.
+fn parse_nvme_source<T: AsRef<Path>>(device: T, sensor: Option<u8>) -> Result<f64> {
+    #[cfg(target_os = "linux")]
+    {
+        crate::nvme::get_temperature(device.as_ref(), sensor)
     }
 
-    let mut ipmi_lock = ipmi.lock().unwrap();
-    let ipmi_readings = ipmi_lock.get_temperature_readings()?;
-    let mut result = HashMap::new();
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = sensor;
 
-    for sensor in sensors {
-        let reading = match ipmi_readings.get(sensor) {
-            Some(r) => r,
-            None => return Err(Error::SensorNotFound(sensor.into())),
-        };
+        Err(Error::Io {
+            path: device.as_ref().to_owned(),
+            source: std::io::ErrorKind::Unsupported.into(),
+        })
+    }
+}
 
-        let reading = match reading {
-            Some(r) => r,
-            None => return Err(Error::SensorNoReading(sensor.into())),
-        };
+/// Check if an IPMI sensor is selected by an IPMI source.
+fn ipmi_sensor_matches(source: &Source, sensor: &Sensor) -> bool {
+    match source {
+        Source::Ipmi { sensor: Some(name), .. } => sensor.name == *name,
+        Source::Ipmi { sensor_regex: Some(regex), .. } => regex.0.is_match(&sensor.name),
+        Source::Ipmi { record_id: Some(id), .. } => sensor.record_id == *id,
+        Source::Ipmi { entity_id: Some(id), entity_instance, .. } => {
+            sensor.entity_id == *id
+                && !matches!(entity_instance, Some(i) if sensor.entity_instance != *i)
+        }
+        _ => false,
+    }
+}
+
+/// Get the temperature of an IPMI sensor in degrees Celsius. Fahrenheit
+/// readings are converted to degrees Celsius. If the sensor's units are not
+/// allowed by `units`, then an error is returned.
+fn parse_ipmi_reading(sensor: &str, reading: &SensorReading, units: IpmiUnits) -> Result<f64> {
+    let reading_units = match (units, reading.units) {
+        (IpmiUnits::Celsius | IpmiUnits::Any, SensorUnits::Celsius) => TempUnits::Celsius,
+        (IpmiUnits::Fahrenheit | IpmiUnits::Any, SensorUnits::Fahrenheit) => TempUnits::Fahrenheit,
+        _ => return Err(Error::SensorBadUnits {
+            sensor: sensor.into(),
+            units: reading.units,
+            expected: units,
+        }),
+    };
+
+    let value = match reading.value {
+        SensorValue::Uint32(t) => f64::from(t),
+        SensorValue::Double(t) => t,
+        v => return Err(Error::SensorBadValue {
+            sensor: sensor.into(),
+            value: v,
+        }),
+    };
+
+    to_celsius(value, reading_units)
+}
+
+/// Get the temperatures of all IPMI sensors selected by an IPMI source. Every
+/// selected sensor contributes a reading, even if multiple sensors have the
+/// same name. Selected sensors without a reading are skipped, but an error is
+/// returned if no selected sensor has a reading.
+fn parse_ipmi_source(sensors: &[Sensor], source: &Source, units: IpmiUnits) -> Result<Vec<f64>> {
+    let selected: Vec<_> = sensors.iter()
+        .filter(|s| ipmi_sensor_matches(source, s))
+        .collect();
 
-        if reading.units != SensorUnits::Celsius {
-            return Err(Error::SensorBadUnits {
-                sensor: sensor.into(),
-                units: reading.units,
-            });
+    if selected.is_empty() {
+        return Err(Error::SensorNotFound(source.to_string()));
+    }
+
+    let mut temperatures = vec![];
+
+    for sensor in &selected {
+        match &sensor.reading {
+            Some(r) => temperatures.push(parse_ipmi_reading(&sensor.name, r, units)?),
+            None => debug!("Sensor {:?} (record ID {}) has no reading",
+                           sensor.name, sensor.record_id),
+        }
+    }
+
+    if temperatures.is_empty() {
+        return Err(Error::SensorNoReading(source.to_string()));
+    }
+
+    Ok(temperatures)
+}
+
+/// Get the speed of a fan sensor in RPM.
+fn parse_fan_reading(sensor: &str, reading: &SensorReading) -> Result<f64> {
+    if reading.units != SensorUnits::Rpm {
+        return Err(Error::FanBadUnits {
+            sensor: sensor.into(),
+            units: reading.units,
+        });
+    }
+
+    match reading.value {
+        SensorValue::Uint32(rpm) => Ok(f64::from(rpm)),
+        SensorValue::Double(rpm) => Ok(rpm),
+        v => Err(Error::SensorBadValue {
+            sensor: sensor.into(),
+            value: v,
+        }),
+    }
+}
+
+/// Get the speeds of the named fan sensors in RPM. The returned values are in
+/// the same order as given. If multiple sensors have the same name, the lowest
+/// speed is used. Fans without a reading have a [`None`] value. An error is
+/// returned if a fan sensor does not exist.
+pub fn get_fan_speeds(ipmi: &mut dyn IpmiBackend, fans: &[String]) -> Result<Vec<Option<f64>>> {
+    let sensors = ipmi.get_fan_readings()?;
+    let mut speeds = Vec::with_capacity(fans.len());
+
+    for fan in fans {
+        let mut selected = sensors.iter().filter(|s| s.name == *fan).peekable();
+        if selected.peek().is_none() {
+            return Err(Error::SensorNotFound(fan.clone()));
         }
 
-        let temperature = match reading.value {
-            SensorValue::Uint32(t) => t.try_into().ok(),
-            SensorValue::Double(t) => (t as u32).try_into().ok(),
-            v => return Err(Error::SensorBadValue {
-                sensor: sensor.into(),
-                value: v,
-            }),
-        }.ok_or(Error::ReadingExceedsBounds)?;
+        let mut speed = Some(f64::INFINITY);
+
+        for sensor in selected {
+            match &sensor.reading {
+                Some(r) => {
+                    let rpm = parse_fan_reading(&sensor.name, r)?;
+                    speed = speed.map(|s| s.min(rpm));
+                }
+                None => {
+                    debug!("Fan sensor {:?} (record ID {}) has no reading",
+                           sensor.name, sensor.record_id);
+                    speed = None;
+                }
+            }
+        }
 
-        result.insert(sensor.into(), temperature);
+        speeds.push(speed);
     }
 
-    Ok(result)
+    Ok(speeds)
+}
+
+/// Cache of the last successful reading and the number of failed readings for
+/// each source in a zone, keyed by the index of the source. This persists
+/// across fan update iterations.
+#[derive(Debug, Default)]
+pub struct ReadingCache {
+    readings: HashMap<usize, (Instant, Vec<f64>)>,
+    errors: HashMap<usize, u64>,
+}
+
+impl ReadingCache {
+    /// Get the total number of failed readings for a source.
+    pub fn error_count(&self, index: usize) -> u64 {
+        self.errors.get(&index).copied().unwrap_or_default()
+    }
 }
 
 /// Get temperature readings for the given sources. The returned values are in
-/// the same order as given.
-pub fn get_source_readings(ipmi: Arc<Mutex<Ipmi>>, sources: &[Source])
-    -> Result<Vec<u8>>
-{
+/// the same order as given. Each source has one or more readings (multiple if
+/// an IPMI source selects multiple sensors). Sources that were dropped have a
+/// [`None`] value.
+///
+/// If a source fails and it has `stale_after_secs` set, then its last good
+/// reading is reused if the reading is not older than the limit. Otherwise, if
+/// the source is optional, it is dropped from the results. If neither applies,
+/// the error is returned. An error is also returned if fewer than
+/// `min_sources` sources have readings.
+pub fn get_source_readings(
+    ipmi: Arc<Mutex<Box<dyn IpmiBackend>>>,
+    sources: &[ZoneSource],
+    min_sources: usize,
+    cache: &mut ReadingCache,
+) -> Result<Vec<Option<Vec<f64>>>> {
     // Get IPMI sensor readings in one go for better performance.
-    let ipmi_sensors = sources.iter()
-        .filter_map(|s| {
-            match s {
-                Source::Ipmi { sensor } => Some(sensor.clone()),
-                _ => None,
+    let ipmi_readings = if sources.iter().any(|s| matches!(s.source, Source::Ipmi { .. })) {
+        let mut ipmi_lock = ipmi.lock().unwrap();
+        Some(ipmi_lock.get_temperature_readings())
+    } else {
+        None
+    };
+
+    let mut readings = vec![];
+
+    for (i, s) in sources.iter().enumerate() {
+        let result = match &s.source {
+            Source::Ipmi { units, .. } => match ipmi_readings.as_ref().unwrap() {
+                Ok(r) => parse_ipmi_source(r, &s.source, *units),
+                Err(e) => Err(e.clone().into()),
+            },
+            Source::File { path, scale, offset, units, format } => {
+                parse_file_source(path, scale.0, *offset, *units, *format).map(|t| vec![t])
             }
-        })
-        .collect::<HashSet<_>>();
+            Source::Smart { block_dev } => parse_smart_source(block_dev).map(|t| vec![t]),
+            Source::Hdparm { block_dev } => parse_hdparm_source(block_dev).map(|t| vec![t]),
+            Source::Hwmon { chip, label } => {
+                parse_hwmon_source(Path::new(HWMON_ROOT), chip, label).map(|t| vec![t])
+            }
+            Source::Nvme { device, sensor } => {
+                parse_nvme_source(device, *sensor).map(|t| vec![t])
+            }
+            Source::Command { command, parser, timeout_secs, exit_codes, scale, units } => {
+                parse_command_source(command, parser, timeout_secs.to_duration(),
+                                     &exit_codes.0, scale.0, *units).map(|t| vec![t])
+            }
+        };
+
+        let e = match result {
+            Ok(t) => {
+                cache.readings.insert(i, (Instant::now(), t.clone()));
+                readings.push(Some(t));
+                continue;
+            }
+            Err(e) => e,
+        };
+
+        *cache.errors.entry(i).or_default() += 1;
+
+        let cached = cache.readings.get(&i)
+            .filter(|(time, _)| s.stale_after().is_some_and(|d| time.elapsed() <= d));
+
+        if let Some((time, t)) = cached {
+            debug!("Source {} failed; reusing reading from {:?} ago: {}",
+                   s.source, time.elapsed(), e);
+            readings.push(Some(t.clone()));
+        } else if s.optional {
+            debug!("Optional source {} failed; dropping reading: {}", s.source, e);
+            readings.push(None);
+        } else {
+            return Err(e);
+        }
+    }
+
+    let available = readings.iter().flatten().count();
+    if available < min_sources {
+        return Err(Error::NotEnoughSources {
+            available,
+            required: min_sources,
+        });
+    }
+
+    Ok(readings)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        tempfile::TempDir,
+        crate::{config::SessionType, ipmi},
+        super::*,
+    };
+
+    /// Create a fake hwmon tree. Each device is a list of `(file, contents)`
+    /// pairs.
+    fn hwmon_tree(devices: &[&[(&str, &str)]]) -> TempDir {
+        let root = tempfile::tempdir().unwrap();
 
-    let ipmi_results = parse_ipmi_sources(ipmi, &ipmi_sensors)?;
+        for (i, files) in devices.iter().enumerate() {
+            let device = root.path().join(format!("hwmon{}", i));
+            fs::create_dir(&device).unwrap();
 
-    sources.iter()
-        .map(|s| {
-            match s {
-                Source::Ipmi { sensor } => Ok(ipmi_results[sensor.as_str()]),
-                Source::File { path } => parse_file_source(path),
-                Source::Smart { block_dev } => parse_smart_source(block_dev),
-                Source::Hdparm { block_dev } => parse_hdparm_source(block_dev),
+            for (name, contents) in *files {
+                fs::write(device.join(name), format!("{}\n", contents)).unwrap();
             }
-        })
-        .collect()
+        }
+
+        root
+    }
+
+    fn sample_tree() -> TempDir {
+        hwmon_tree(&[
+            &[
+                ("name", "coretemp"),
+                ("temp1_input", "45000"),
+                ("temp1_label", "Package id 0"),
+                ("temp2_input", "40000"),
+                ("temp2_label", "Core 0"),
+            ],
+            &[
+                ("name", "nvme"),
+                ("temp1_input", "38000"),
+            ],
+            &[
+                ("name", "coretemp"),
+                ("temp1_input", "41000"),
+                ("temp1_label", "Core 0"),
+            ],
+            // Devices without a name are skipped
+            &[
+                ("temp1_input", "50000"),
+                ("temp1_label", "Package id 0"),
+            ],
+        ])
+    }
+
+    #[test]
+    fn hwmon_chip_and_label() {
+        let root = sample_tree();
+
+        assert_eq!(find_hwmon_input(root.path(), "coretemp", "Package id 0").unwrap(),
+                   root.path().join("hwmon0/temp1_input"));
+        assert_eq!(parse_hwmon_source(root.path(), "coretemp", "Package id 0").unwrap(), 45.0);
+    }
+
+    #[test]
+    fn hwmon_unlabelled_fallback() {
+        let root = sample_tree();
+
+        assert_eq!(find_hwmon_input(root.path(), "nvme", "temp1").unwrap(),
+                   root.path().join("hwmon1/temp1_input"));
+
+        // Sensors with a label can only be found by their label
+        assert!(matches!(
+            find_hwmon_input(root.path(), "coretemp", "temp1"),
+            Err(Error::HwmonLabelNotFound { .. }),
+        ));
+    }
+
+    #[test]
+    fn hwmon_chip_not_found() {
+        let root = sample_tree();
+
+        assert!(matches!(
+            find_hwmon_input(root.path(), "k10temp", "Tctl"),
+            Err(Error::HwmonChipNotFound(c)) if c == "k10temp",
+        ));
+    }
+
+    #[test]
+    fn hwmon_label_not_found() {
+        let root = sample_tree();
+
+        assert!(matches!(
+            find_hwmon_input(root.path(), "coretemp", "Core 1"),
+            Err(Error::HwmonLabelNotFound { chip, label })
+                if chip == "coretemp" && label == "Core 1",
+        ));
+    }
+
+    #[test]
+    fn hwmon_ambiguous() {
+        let root = sample_tree();
+
+        match find_hwmon_input(root.path(), "coretemp", "Core 0") {
+            Err(Error::HwmonAmbiguous { paths, .. }) => {
+                assert_eq!(paths, [
+                    root.path().join("hwmon0/temp2_input"),
+                    root.path().join("hwmon2/temp1_input"),
+                ]);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    /// Write `contents` to a file and parse it as a file source.
+    fn parse_file(
+        contents: &str,
+        scale: f64,
+        offset: f64,
+        units: TempUnits,
+        format: FileFormat,
+    ) -> Result<f64> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("temp");
+        fs::write(&path, contents).unwrap();
+
+        parse_file_source(&path, scale, offset, units, format)
+    }
+
+    #[test]
+    fn file_millidegrees() {
+        assert_eq!(parse_file("42500\n", 0.001, 0.0, TempUnits::Celsius, FileFormat::Integer)
+                       .unwrap(), 42.5);
+        assert_eq!(parse_file("-5000", 0.001, 0.0, TempUnits::Celsius, FileFormat::Integer)
+                       .unwrap(), -5.0);
+        assert!(matches!(
+            parse_file("42.5", 0.001, 0.0, TempUnits::Celsius, FileFormat::Integer),
+            Err(Error::SensorValueParse { .. }),
+        ));
+    }
+
+    #[test]
+    fn file_fahrenheit() {
+        assert_eq!(parse_file(" 212.0 ", 1.0, 0.0, TempUnits::Fahrenheit, FileFormat::Float)
+                       .unwrap(), 100.0);
+        // The scale and offset apply before the units are converted
+        assert_eq!(parse_file("1040", 0.1, -72.0, TempUnits::Fahrenheit, FileFormat::Integer)
+                       .unwrap(), 0.0);
+        assert_eq!(parse_file("300.15", 1.0, 0.0, TempUnits::Kelvin, FileFormat::Float)
+                       .unwrap(), 27.0);
+    }
+
+    fn reading(value: SensorValue, units: SensorUnits) -> SensorReading {
+        SensorReading { value, units }
+    }
+
+    #[test]
+    fn ipmi_units_policy() {
+        let celsius = reading(SensorValue::Double(50.0), SensorUnits::Celsius);
+        let fahrenheit = reading(SensorValue::Uint32(122), SensorUnits::Fahrenheit);
+
+        for (units, c, f) in [
+            (IpmiUnits::Celsius, Some(50.0), None),
+            (IpmiUnits::Fahrenheit, None, Some(50.0)),
+            (IpmiUnits::Any, Some(50.0), Some(50.0)),
+        ] {
+            for (r, expected) in [(&celsius, c), (&fahrenheit, f)] {
+                match (parse_ipmi_reading("CPU Temp", r, units), expected) {
+                    (Ok(t), Some(e)) => assert_eq!(t, e),
+                    (Err(Error::SensorBadUnits { expected, .. }), None) => {
+                        assert_eq!(expected, units);
+                    }
+                    (result, _) => panic!("Unexpected result for {:?}: {:?}", units, result),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ipmi_units_rejected() {
+        let rpm = reading(SensorValue::Uint32(1200), SensorUnits::Rpm);
+        let unknown = reading(SensorValue::Unknown, SensorUnits::Celsius);
+
+        assert!(matches!(parse_ipmi_reading("FAN1", &rpm, IpmiUnits::Any),
+                         Err(Error::SensorBadUnits { .. })));
+        assert!(matches!(parse_ipmi_reading("CPU Temp", &unknown, IpmiUnits::Any),
+                         Err(Error::SensorBadValue { .. })));
+    }
+
+    fn sensor(record_id: u16, name: &str, entity: (u8, u8), temp: Option<f64>) -> Sensor {
+        Sensor {
+            record_id,
+            name: name.to_owned(),
+            entity_id: entity.0,
+            entity_instance: entity.1,
+            reading: temp.map(|t| reading(SensorValue::Double(t), SensorUnits::Celsius)),
+        }
+    }
+
+    /// Two CPUs whose sensors have the same name, but different entity
+    /// instances, and a system board sensor without a reading.
+    fn ipmi_sensors() -> Vec<Sensor> {
+        vec![
+            sensor(1, "CPU Temp", (3, 1), Some(50.0)),
+            sensor(2, "CPU Temp", (3, 2), Some(55.0)),
+            sensor(3, "PCH Temp", (7, 1), Some(45.0)),
+            sensor(4, "VRM Temp", (7, 1), None),
+        ]
+    }
+
+    /// Parse the IPMI source defined by the TOML table body `source`.
+    fn parse_ipmi(source: &str) -> Result<Vec<f64>> {
+        let source: Source = toml::from_str(&format!("type = \"ipmi\"\n{}", source)).unwrap();
+
+        parse_ipmi_source(&ipmi_sensors(), &source, IpmiUnits::Celsius)
+    }
+
+    #[test]
+    fn ipmi_select_by_name() {
+        assert_eq!(parse_ipmi(r#"sensor = "CPU Temp""#).unwrap(), [50.0, 55.0]);
+        assert_eq!(parse_ipmi(r#"sensor = "PCH Temp""#).unwrap(), [45.0]);
+        assert!(matches!(parse_ipmi(r#"sensor = "CPU""#), Err(Error::SensorNotFound(_))));
+    }
+
+    #[test]
+    fn ipmi_select_by_regex() {
+        assert_eq!(parse_ipmi(r#"sensor_regex = "^(CPU|PCH) ""#).unwrap(), [50.0, 55.0, 45.0]);
+        assert!(matches!(parse_ipmi(r#"sensor_regex = "^DIMM""#), Err(Error::SensorNotFound(_))));
+    }
+
+    #[test]
+    fn ipmi_select_by_record_id() {
+        assert_eq!(parse_ipmi("record_id = 2").unwrap(), [55.0]);
+        assert!(matches!(parse_ipmi("record_id = 4"), Err(Error::SensorNoReading(_))));
+        assert!(matches!(parse_ipmi("record_id = 5"), Err(Error::SensorNotFound(_))));
+    }
+
+    #[test]
+    fn ipmi_select_by_entity() {
+        // Sensors with the same name are told apart by the entity instance
+        assert_eq!(parse_ipmi("entity_id = 3").unwrap(), [50.0, 55.0]);
+        assert_eq!(parse_ipmi("entity_id = 3\nentity_instance = 2").unwrap(), [55.0]);
+        assert!(matches!(parse_ipmi("entity_id = 3\nentity_instance = 3"),
+                         Err(Error::SensorNotFound(_))));
+
+        // Sensors without a reading are skipped
+        assert_eq!(parse_ipmi("entity_id = 7").unwrap(), [45.0]);
+    }
+
+    /// Read file sources from `dir`. Each source is `(file, options)`, where
+    /// `options` is appended to the source's TOML table.
+    fn file_readings(
+        dir: &Path,
+        sources: &[(&str, &str)],
+        min_sources: usize,
+        cache: &mut ReadingCache,
+    ) -> Result<Vec<Option<Vec<f64>>>> {
+        let sources: Vec<ZoneSource> = sources.iter()
+            .map(|(file, options)| {
+                let path = dir.join(file);
+                toml::from_str(&format!("type = \"file\"\npath = {:?}\n{}", path, options))
+                    .unwrap()
+            })
+            .collect();
+        let ipmi = ipmi::connect(&SessionType::Simulated(toml::from_str("").unwrap())).unwrap();
+
+        get_source_readings(Arc::new(Mutex::new(ipmi)), &sources, min_sources, cache)
+    }
+
+    #[test]
+    fn optional_source() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "40000").unwrap();
+        let mut cache = ReadingCache::default();
+
+        let readings = file_readings(dir.path(), &[("a", ""), ("b", "optional = true")], 1,
+                                     &mut cache).unwrap();
+        assert_eq!(readings, [Some(vec![40.0]), None]);
+        assert_eq!((cache.error_count(0), cache.error_count(1)), (0, 1));
+
+        // A required source is not dropped
+        assert!(matches!(file_readings(dir.path(), &[("a", ""), ("b", "")], 1, &mut cache),
+                         Err(Error::Io { .. })));
+        assert_eq!(cache.error_count(1), 2);
+    }
+
+    #[test]
+    fn stale_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let mut cache = ReadingCache::default();
+
+        for (options, reused) in [("stale_after_secs = 3600", true), ("stale_after_secs = 0", false)] {
+            fs::write(&a, "40000").unwrap();
+            file_readings(dir.path(), &[("a", options)], 1, &mut cache).unwrap();
+
+            // The last good reading is reused until it is too old
+            fs::remove_file(&a).unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+            let result = file_readings(dir.path(), &[("a", options)], 1, &mut cache);
+            if reused {
+                assert_eq!(result.unwrap(), [Some(vec![40.0])]);
+            } else {
+                assert!(matches!(result, Err(Error::Io { .. })));
+            }
+        }
+    }
+
+    #[test]
+    fn min_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "40000").unwrap();
+        fs::write(dir.path().join("b"), "45000").unwrap();
+        let sources = [("a", "optional = true"), ("b", "optional = true"), ("c", "optional = true")];
+        let mut cache = ReadingCache::default();
+
+        let readings = file_readings(dir.path(), &sources, 2, &mut cache).unwrap();
+        assert_eq!(readings, [Some(vec![40.0]), Some(vec![45.0]), None]);
+
+        fs::remove_file(dir.path().join("b")).unwrap();
+        assert!(matches!(file_readings(dir.path(), &sources, 2, &mut cache),
+                         Err(Error::NotEnoughSources { available: 1, required: 2 })));
+        assert_eq!(file_readings(dir.path(), &sources, 1, &mut cache).unwrap().len(), 3);
+    }
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["sh".to_owned(), "-c".to_owned(), script.to_owned()]
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_stdout() {
+        let output = run_command(&sh("echo 42; exit 1"), Duration::from_secs(5), &[0, 1]).unwrap();
+
+        assert_eq!(output, "42\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_failed_stderr() {
+        let argv = sh("echo first >&2; echo second >&2; exit 2");
+
+        match run_command(&argv, Duration::from_secs(5), &[0]) {
+            Err(Error::Command { status, stderr, .. }) => {
+                assert_eq!(status.code(), Some(2));
+                assert_eq!(stderr, "first");
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_exit_codes() {
+        let argv = sh("echo 42; exit 3");
+
+        assert_eq!(run_command(&argv, Duration::from_secs(5), &[0, 3]).unwrap(), "42\n");
+
+        match run_command(&argv, Duration::from_secs(5), &[0]) {
+            Err(Error::Command { status, stderr, .. }) => {
+                assert_eq!(status.code(), Some(3));
+                assert_eq!(stderr, "");
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    /// Parse `output` with a parser defined by the TOML table `parser`.
+    fn parse_output(parser: &str, output: &str) -> Result<f64> {
+        let parser: CommandParser = toml::from_str(parser).unwrap();
+
+        parse_command_output(Path::new("cmd"), &parser, output)
+    }
+
+    #[test]
+    fn command_number_parser() {
+        assert_eq!(parse_output(r#"type = "number""#, " 42.5\n").unwrap(), 42.5);
+        assert!(matches!(parse_output(r#"type = "number""#, "42 C"), Err(Error::CommandBadValue { .. })));
+    }
+
+    #[test]
+    fn command_regex_parser() {
+        let group = r#"type = "regex"
+            pattern = 'temp: (\d+)'"#;
+        let whole = r#"type = "regex"
+            pattern = '\d+'"#;
+
+        assert_eq!(parse_output(group, "id: 3\ntemp: 45\n").unwrap(), 45.0);
+        assert_eq!(parse_output(whole, "temp: 45 C").unwrap(), 45.0);
+        assert!(matches!(parse_output(group, "temp: n/a"), Err(Error::CommandNoMatch(_))));
+    }
+
+    #[test]
+    fn command_json_parser() {
+        let parser = r#"type = "json"
+            pointer = "/sensors/1/temp""#;
+        let output = |v: &str| format!(r#"{{"sensors": [{{}}, {{"temp": {v}}}]}}"#);
+
+        assert_eq!(parse_output(parser, &output("41")).unwrap(), 41.0);
+        assert_eq!(parse_output(parser, &output(r#"" 41.5 ""#)).unwrap(), 41.5);
+        assert!(matches!(parse_output(parser, &output("null")),
+                         Err(Error::CommandBadValue { .. })));
+        assert!(matches!(parse_output(parser, r#"{"sensors": []}"#),
+                         Err(Error::CommandJsonPointer { .. })));
+        assert!(matches!(parse_output(parser, "temp: 41"), Err(Error::CommandJson { .. })));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_timeout_background_child() {
+        // The background child keeps stdout open after the shell exits
+        let argv = sh("sleep 30 & exit 0");
+        let start = Instant::now();
+
+        assert!(matches!(
+            run_command(&argv, Duration::from_millis(200), &[0]),
+            Err(Error::CommandTimeout { .. }),
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
 }
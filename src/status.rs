@@ -0,0 +1,410 @@
+use {
+    std::{
+        sync::{
+            Arc,
+            atomic::{AtomicU64, Ordering},
+            Mutex,
+        },
+        time::{Duration, Instant},
+    },
+    serde::{Deserialize, Serialize},
+    crate::{
+        config::{SessionType, Zone},
+        ipmi::FanMode,
+    },
+};
+
+/// Who is in control of a zone's duty cycle.
+#[derive(Clone, Copy, Debug)]
+pub enum ControlMode {
+    /// The duty cycle is chosen by the zone's controller
+    Auto,
+    /// The duty cycle is left as-is
+    Paused,
+    /// The duty cycle is forced to a fixed value until the (optional) expiry
+    Manual {
+        dcycle: u8,
+        until: Option<Instant>,
+    },
+}
+
+/// Serializable form of [`ControlMode`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase", tag = "type")]
+pub enum ControlModeStatus {
+    Auto,
+    Paused,
+    Manual {
+        dcycle: u8,
+        expires_in_secs: Option<u64>,
+    },
+}
+
+impl From<ControlMode> for ControlModeStatus {
+    fn from(mode: ControlMode) -> Self {
+        match mode {
+            ControlMode::Auto => Self::Auto,
+            ControlMode::Paused => Self::Paused,
+            ControlMode::Manual { dcycle, until } => Self::Manual {
+                dcycle,
+                expires_in_secs: until.map(|u| {
+                    u.saturating_duration_since(Instant::now()).as_secs()
+                }),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SourceStatus {
+    /// Human-readable description of the source
+    pub source: String,
+    /// Last reading in degrees Celsius or [`None`] if the source was dropped.
+    /// If the source has multiple readings, this is the highest one.
+    pub temp: Option<f64>,
+    /// Total number of failed readings
+    pub errors: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FanStatus {
+    /// Name of the IPMI fan sensor
+    pub fan: String,
+    /// Last reading in RPM or [`None`] if the fan has no reading
+    pub rpm: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IpmiZoneStatus {
+    pub zone: u8,
+    /// Duty cycle of the IPMI zone after any changes were made
+    pub dcycle: u8,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ZoneStatus {
+    pub session: String,
+    pub ipmi_zones: Vec<u8>,
+    /// Aggregated temperature before smoothing
+    pub temp_raw: Option<f64>,
+    /// Aggregated temperature after smoothing
+    pub temp: Option<f64>,
+    pub sources: Vec<SourceStatus>,
+    /// Duty cycle chosen by the controller after applying limits
+    pub dcycle: Option<u8>,
+    pub actual_dcycles: Vec<IpmiZoneStatus>,
+    /// Fans monitored by stall detection
+    #[serde(default)]
+    pub fans: Vec<FanStatus>,
+    pub fan_mode: Option<String>,
+    pub failures: u64,
+    /// Total number of times all attempts to query the sources failed
+    pub retries_exhausted: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ZoneEntry {
+    pub index: usize,
+    pub mode: ControlModeStatus,
+    #[serde(flatten)]
+    pub status: ZoneStatus,
+}
+
+/// Kind of BMC that a session talks to.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionKind {
+    Local,
+    Remote,
+    Simulated,
+}
+
+impl From<&SessionType> for SessionKind {
+    fn from(st: &SessionType) -> Self {
+        match st {
+            SessionType::Local => Self::Local,
+            SessionType::Remote { .. } => Self::Remote,
+            SessionType::Simulated(_) => Self::Simulated,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SessionStatus {
+    pub name: String,
+    pub kind: SessionKind,
+    /// Remote hostname or [`None`] for local and simulated sessions
+    pub hostname: Option<String>,
+    pub fan_mode: String,
+    /// Total number of failed IPMI commands and sensor queries
+    pub ipmi_errors: u64,
+}
+
+impl SessionStatus {
+    /// Whether this session controls the same BMC as a session of type `st`.
+    /// Local sessions match each other and remote sessions match if they have
+    /// the same hostname. If `name` is given, sessions with that name also
+    /// match in case the config changed since the daemon loaded it. Simulated
+    /// BMCs only exist in the process that created them, so they never match.
+    pub fn controls_same_bmc(&self, name: Option<&str>, st: &SessionType) -> bool {
+        match (self.kind, st) {
+            (SessionKind::Simulated, _) | (_, SessionType::Simulated(_)) => false,
+            (SessionKind::Local, SessionType::Local) => true,
+            (SessionKind::Remote, SessionType::Remote { hostname, .. })
+                if self.hostname.as_ref() == Some(hostname) => true,
+            _ => name == Some(self.name.as_str()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DaemonStatus {
+    pub sessions: Vec<SessionStatus>,
+    pub zones: Vec<ZoneEntry>,
+}
+
+/// Request sent to the control socket. Each request is a single line of JSON.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase", tag = "command")]
+pub enum Request {
+    /// Get the status of all sessions and zones
+    Status,
+    /// Stop changing the duty cycle of a zone
+    Pause {
+        zone: usize,
+    },
+    /// Force a zone to a fixed duty cycle, optionally for a limited time
+    Manual {
+        zone: usize,
+        dcycle: u8,
+        duration_secs: Option<u64>,
+    },
+    /// Return a zone to automatic control
+    Resume {
+        zone: usize,
+    },
+}
+
+/// Response sent from the control socket. Each response is a single line of
+/// JSON.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase", tag = "result")]
+pub enum Response {
+    Status(DaemonStatus),
+    Ok,
+    Error {
+        message: String,
+    },
+}
+
+/// State shared between a zone loop and the control socket.
+#[derive(Debug)]
+pub struct ZoneHandle {
+    pub status: Mutex<ZoneStatus>,
+    pub mode: Mutex<ControlMode>,
+    /// When the zone loop last completed an iteration (or was created)
+    pub last_iteration: Mutex<Instant>,
+}
+
+impl ZoneHandle {
+    pub fn new(zone_config: &Zone) -> Self {
+        Self {
+            status: Mutex::new(ZoneStatus {
+                session: zone_config.session.0.clone(),
+                ipmi_zones: zone_config.ipmi_zones.clone(),
+                sources: zone_config.sources
+                    .iter()
+                    .map(|s| SourceStatus {
+                        source: s.source.to_string(),
+                        temp: None,
+                        errors: 0,
+                    })
+                    .collect(),
+                fans: zone_config.stall_detection
+                    .iter()
+                    .flat_map(|s| &s.fans)
+                    .map(|f| FanStatus {
+                        fan: f.clone(),
+                        rpm: None,
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            mode: Mutex::new(ControlMode::Auto),
+            last_iteration: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Get the current control mode. If a manual override has expired, the
+    /// mode reverts to [`ControlMode::Auto`].
+    pub fn current_mode(&self) -> ControlMode {
+        let mut mode = self.mode.lock().unwrap();
+
+        if let ControlMode::Manual { until: Some(until), .. } = *mode {
+            if Instant::now() >= until {
+                *mode = ControlMode::Auto;
+            }
+        }
+
+        *mode
+    }
+
+    /// Force the zone to a fixed duty cycle. The override expires after
+    /// `duration`, if specified.
+    pub fn set_manual(&self, dcycle: u8, duration: Option<Duration>) {
+        *self.mode.lock().unwrap() = ControlMode::Manual {
+            dcycle,
+            until: duration.map(|d| Instant::now() + d),
+        };
+    }
+
+    pub fn entry(&self, index: usize) -> ZoneEntry {
+        ZoneEntry {
+            index,
+            mode: self.current_mode().into(),
+            status: self.status.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Session information for status reporting.
+#[derive(Debug)]
+pub struct SessionInfo {
+    pub name: String,
+    pub kind: SessionKind,
+    pub hostname: Option<String>,
+    pub fan_mode: Arc<Mutex<FanMode>>,
+    pub ipmi_errors: Arc<AtomicU64>,
+}
+
+/// Everything needed to report on and control the running daemon.
+#[derive(Debug)]
+pub struct ControlState {
+    pub sessions: Vec<SessionInfo>,
+    pub zones: Vec<Arc<ZoneHandle>>,
+}
+
+impl ControlState {
+    pub fn status(&self) -> DaemonStatus {
+        DaemonStatus {
+            sessions: self.sessions.iter()
+                .map(|s| SessionStatus {
+                    name: s.name.clone(),
+                    kind: s.kind,
+                    hostname: s.hostname.clone(),
+                    fan_mode: s.fan_mode.lock().unwrap().to_string(),
+                    ipmi_errors: s.ipmi_errors.load(Ordering::Relaxed),
+                })
+                .collect(),
+            zones: self.zones.iter()
+                .enumerate()
+                .map(|(i, z)| z.entry(i))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::config::Password,
+        super::*,
+    };
+
+    fn session(name: &str, kind: SessionKind, hostname: Option<&str>) -> SessionStatus {
+        SessionStatus {
+            name: name.to_owned(),
+            kind,
+            hostname: hostname.map(str::to_owned),
+            fan_mode: "full".to_owned(),
+            ipmi_errors: 0,
+        }
+    }
+
+    fn remote(hostname: &str) -> SessionType {
+        SessionType::Remote {
+            hostname: hostname.to_owned(),
+            username: "admin".to_owned(),
+            password: Password("admin".to_owned()),
+        }
+    }
+
+    fn simulated() -> SessionType {
+        SessionType::Simulated(toml::from_str("").unwrap())
+    }
+
+    fn zone_handle() -> ZoneHandle {
+        ZoneHandle::new(&toml::from_str(r#"
+            session = "sim"
+            ipmi_zones = [0]
+            sources = [{ type = "ipmi", sensor = "CPU Temp" }]
+        "#).unwrap())
+    }
+
+    #[test]
+    fn same_bmc_local() {
+        let local = session("local", SessionKind::Local, None);
+
+        assert!(local.controls_same_bmc(None, &SessionType::Local));
+        assert!(local.controls_same_bmc(Some("other"), &SessionType::Local));
+        assert!(!local.controls_same_bmc(None, &remote("bmc1")));
+        assert!(!local.controls_same_bmc(Some("other"), &remote("bmc1")));
+        assert!(!local.controls_same_bmc(None, &simulated()));
+
+        // The config may have changed the session's type
+        assert!(local.controls_same_bmc(Some("local"), &remote("bmc1")));
+    }
+
+    #[test]
+    fn same_bmc_remote() {
+        let remote1 = session("bmc1", SessionKind::Remote, Some("bmc1"));
+
+        assert!(remote1.controls_same_bmc(None, &remote("bmc1")));
+        assert!(!remote1.controls_same_bmc(None, &remote("bmc2")));
+        assert!(!remote1.controls_same_bmc(None, &SessionType::Local));
+        assert!(!remote1.controls_same_bmc(Some("local"), &SessionType::Local));
+
+        // The config may have changed the session's hostname
+        assert!(remote1.controls_same_bmc(Some("bmc1"), &remote("bmc2")));
+    }
+
+    #[test]
+    fn same_bmc_simulated() {
+        let sim = session("sim", SessionKind::Simulated, None);
+
+        assert!(!sim.controls_same_bmc(None, &SessionType::Local));
+        assert!(!sim.controls_same_bmc(Some("sim"), &SessionType::Local));
+        assert!(!sim.controls_same_bmc(Some("sim"), &simulated()));
+
+        let local = session("sim", SessionKind::Local, None);
+        assert!(!local.controls_same_bmc(Some("sim"), &simulated()));
+    }
+
+    #[test]
+    fn manual_mode() {
+        let handle = zone_handle();
+        assert!(matches!(handle.current_mode(), ControlMode::Auto));
+
+        handle.set_manual(60, None);
+        assert!(matches!(handle.current_mode(), ControlMode::Manual { dcycle: 60, until: None }));
+        assert!(matches!(handle.entry(0).mode,
+                         ControlModeStatus::Manual { dcycle: 60, expires_in_secs: None }));
+
+        handle.set_manual(70, Some(Duration::from_secs(3600)));
+        assert!(matches!(handle.entry(0).mode,
+                         ControlModeStatus::Manual { dcycle: 70, expires_in_secs: Some(3599..) }));
+    }
+
+    #[test]
+    fn manual_mode_expiry() {
+        let handle = zone_handle();
+
+        handle.set_manual(60, Some(Duration::ZERO));
+        assert!(matches!(handle.current_mode(), ControlMode::Auto));
+
+        // Pausing is never undone automatically
+        *handle.mode.lock().unwrap() = ControlMode::Paused;
+        assert!(matches!(handle.current_mode(), ControlMode::Paused));
+    }
+}
@@ -0,0 +1,571 @@
+use {
+    std::{
+        fs,
+        io,
+        path::{Path, PathBuf},
+    },
+    log::trace,
+    serde::{Deserialize, Serialize},
+    crate::{
+        config::{FanThresholds, SessionType},
+        error::{Error, Result},
+        ipmi::{IpmiBackend, RawThresholds, SensorRecord},
+    },
+};
+
+/// Names of the thresholds, in the order used by the Get/Set Sensor
+/// Thresholds commands and the SDR threshold masks.
+pub const THRESHOLD_NAMES: [&str; 6] = [
+    "lower_non_critical",
+    "lower_critical",
+    "lower_non_recoverable",
+    "upper_non_critical",
+    "upper_critical",
+    "upper_non_recoverable",
+];
+
+/// SDR record type of a full sensor record
+const SDR_TYPE_FULL: u8 = 0x01;
+/// Minimum length of a full sensor record that includes the conversion factors
+const SDR_MIN_LENGTH: usize = 30;
+/// Slave address of the BMC
+const BMC_SLAVE_ADDRESS: u8 = 0x20;
+
+/// Threshold access support in the sensor capabilities field
+const ACCESS_NONE: u8 = 0b00;
+const ACCESS_READABLE_SETTABLE: u8 = 0b10;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum AnalogFormat {
+    Unsigned,
+    OnesComplement,
+    TwosComplement,
+}
+
+/// Factors for converting between raw values and real values of a sensor with
+/// linear conversion: `y = (M * x + B * 10^K1) * 10^K2`.
+#[derive(Clone, Copy, Debug)]
+pub struct Factors {
+    format: AnalogFormat,
+    m: i16,
+    b: i16,
+    /// K1
+    b_exp: i8,
+    /// K2
+    r_exp: i8,
+}
+
+/// Sign extend the lowest `bits` bits of `value`.
+fn sign_extend(value: u16, bits: u32) -> i16 {
+    let shift = 16 - bits;
+    ((value << shift) as i16) >> shift
+}
+
+impl Factors {
+    /// Convert a raw value to a real value.
+    pub fn to_value(self, raw: u8) -> f64 {
+        let x = match self.format {
+            AnalogFormat::Unsigned => f64::from(raw),
+            AnalogFormat::OnesComplement if raw & 0x80 != 0 => -f64::from(!raw),
+            AnalogFormat::OnesComplement => f64::from(raw),
+            AnalogFormat::TwosComplement => f64::from(raw as i8),
+        };
+
+        (f64::from(self.m) * x + f64::from(self.b) * 10f64.powi(self.b_exp.into()))
+            * 10f64.powi(self.r_exp.into())
+    }
+
+    /// Convert a real value to the nearest raw value. Returns [`None`] if the
+    /// value cannot be represented.
+    pub fn to_raw(self, value: f64) -> Option<u8> {
+        if self.m == 0 {
+            return None;
+        }
+
+        let x = ((value / 10f64.powi(self.r_exp.into())
+            - f64::from(self.b) * 10f64.powi(self.b_exp.into()))
+            / f64::from(self.m))
+            .round();
+
+        match self.format {
+            AnalogFormat::Unsigned if (0.0..=255.0).contains(&x) => Some(x as u8),
+            AnalogFormat::OnesComplement if (0.0..=127.0).contains(&x) => Some(x as u8),
+            AnalogFormat::OnesComplement if (-127.0..0.0).contains(&x) => Some(!((-x) as u8)),
+            AnalogFormat::TwosComplement if (-128.0..=127.0).contains(&x) => Some(x as i8 as u8),
+            _ => None,
+        }
+    }
+}
+
+/// A fan sensor owned by the BMC whose thresholds can be accessed.
+#[derive(Clone, Debug)]
+pub struct FanSensor {
+    pub name: String,
+    pub sensor_number: u8,
+    /// Mask of thresholds that can be set
+    pub settable: u8,
+    pub factors: Factors,
+}
+
+impl FanSensor {
+    /// Parse the threshold masks and conversion factors from a sensor's full
+    /// sensor record.
+    pub fn from_record(record: &SensorRecord) -> Result<Self> {
+        let unsupported = |reason| Error::SdrUnsupported {
+            sensor: record.name.clone(),
+            reason,
+        };
+        let sdr = &record.sdr;
+
+        if sdr.len() < SDR_MIN_LENGTH {
+            return Err(unsupported("record is too short"));
+        } else if sdr[3] != SDR_TYPE_FULL {
+            return Err(unsupported("not a full sensor record"));
+        } else if sdr[5] != BMC_SLAVE_ADDRESS || sdr[6] & 0b11 != 0 {
+            return Err(unsupported("sensor is not owned by the BMC"));
+        }
+
+        let access = (sdr[11] >> 2) & 0b11;
+        if access == ACCESS_NONE {
+            return Err(unsupported("sensor has no thresholds"));
+        }
+
+        let format = match sdr[20] >> 6 {
+            0b00 => AnalogFormat::Unsigned,
+            0b01 => AnalogFormat::OnesComplement,
+            0b10 => AnalogFormat::TwosComplement,
+            _ => return Err(unsupported("sensor has no analog reading")),
+        };
+
+        if sdr[23] & 0x7f != 0 {
+            return Err(unsupported("sensor uses non-linear conversion"));
+        }
+
+        let factors = Factors {
+            format,
+            m: sign_extend(u16::from(sdr[24]) | (u16::from(sdr[25] >> 6) << 8), 10),
+            b: sign_extend(u16::from(sdr[26]) | (u16::from(sdr[27] >> 6) << 8), 10),
+            b_exp: sign_extend(u16::from(sdr[29] & 0xf), 4) as i8,
+            r_exp: sign_extend(u16::from(sdr[29] >> 4), 4) as i8,
+        };
+
+        let sensor = Self {
+            name: record.name.clone(),
+            sensor_number: record.sensor_number,
+            settable: if access == ACCESS_READABLE_SETTABLE { sdr[19] & 0x3f } else { 0 },
+            factors,
+        };
+
+        trace!("Fan sensor: {:?}", sensor);
+
+        Ok(sensor)
+    }
+
+    /// Get the raw thresholds to set for the sensor based on the config. Only
+    /// the configured thresholds are included in the mask.
+    pub fn desired(&self, config: &FanThresholds) -> Result<RawThresholds> {
+        let mut thresholds = RawThresholds::default();
+
+        for (i, (name, value)) in config.values().into_iter().enumerate() {
+            let value = match value {
+                Some(v) => v,
+                None => continue,
+            };
+
+            if self.settable & (1 << i) == 0 {
+                return Err(Error::ThresholdNotSettable {
+                    sensor: self.name.clone(),
+                    threshold: name,
+                });
+            }
+
+            thresholds.values[i] = self.factors.to_raw(value)
+                .ok_or_else(|| Error::ThresholdOutOfRange {
+                    sensor: self.name.clone(),
+                    threshold: name,
+                    value,
+                })?;
+            thresholds.mask |= 1 << i;
+        }
+
+        Ok(thresholds)
+    }
+
+    /// Format a raw threshold for display or `-` if it is not readable.
+    pub fn format(&self, thresholds: &RawThresholds, index: usize) -> String {
+        if thresholds.mask & (1 << index) == 0 {
+            "-".to_owned()
+        } else {
+            format!("{:.0}", self.factors.to_value(thresholds.values[index]))
+        }
+    }
+}
+
+/// Get every fan sensor whose thresholds can be accessed. Sensors that are not
+/// supported are skipped.
+pub fn fan_sensors(ipmi: &mut dyn IpmiBackend) -> Result<Vec<FanSensor>> {
+    let records = ipmi.get_fan_records()?;
+
+    Ok(records
+        .iter()
+        .filter_map(|r| match FanSensor::from_record(r) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                trace!("Skipping fan sensor: {}", e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Get the fan sensors named in the config. Every sensor with a matching name
+/// is returned. An error is returned if a fan does not exist or if its
+/// thresholds cannot be accessed.
+pub fn configured_sensors(ipmi: &mut dyn IpmiBackend, config: &FanThresholds) -> Result<Vec<FanSensor>> {
+    let records = ipmi.get_fan_records()?;
+    let mut sensors = vec![];
+
+    for fan in &config.fans {
+        let mut found = false;
+
+        for record in records.iter().filter(|r| r.name == *fan) {
+            sensors.push(FanSensor::from_record(record)?);
+            found = true;
+        }
+
+        if !found {
+            return Err(Error::SensorNotFound(fan.clone()));
+        }
+    }
+
+    Ok(sensors)
+}
+
+/// Get the names of the desired thresholds that differ from the current
+/// thresholds. Thresholds that are not readable are not compared.
+pub fn drift(current: &RawThresholds, desired: &RawThresholds) -> Vec<&'static str> {
+    (0..THRESHOLD_NAMES.len())
+        .filter(|i| desired.mask & current.mask & (1 << i) != 0)
+        .filter(|&i| desired.values[i] != current.values[i])
+        .map(|i| THRESHOLD_NAMES[i])
+        .collect()
+}
+
+/// Original thresholds of a sensor, saved so that they can be restored.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SavedThresholds {
+    pub name: String,
+    pub sensor_number: u8,
+    pub thresholds: RawThresholds,
+}
+
+/// File that keeps the original thresholds of a BMC's fan sensors while they
+/// are changed. If the daemon exits without restoring them, the next run uses
+/// the thresholds from the file instead of treating the changed thresholds as
+/// the originals.
+#[derive(Debug)]
+pub struct ThresholdStore {
+    path: PathBuf,
+}
+
+impl ThresholdStore {
+    /// Get the file in `dir` for the BMC of a session. Files are named after
+    /// the BMC so that renaming the session does not lose track of them.
+    pub fn new(dir: &Path, name: &str, st: &SessionType) -> Self {
+        let key = match st {
+            SessionType::Local => "local".to_owned(),
+            SessionType::Remote { hostname, .. } => format!("remote-{}", hostname),
+            SessionType::Simulated(_) => format!("simulated-{}", name),
+        };
+        let key: String = key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || "-._".contains(c) { c } else { '_' })
+            .collect();
+
+        Self {
+            path: dir.join(format!("thresholds-{}.json", key)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the saved thresholds. There are none if the file does not exist.
+    pub fn load(&self) -> Result<Vec<SavedThresholds>> {
+        let data = match fs::read(&self.path) {
+            Ok(d) => d,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(Error::Io { path: self.path.clone(), source: e }),
+        };
+
+        serde_json::from_slice(&data)
+            .map_err(|e| Error::ThresholdsParse { path: self.path.clone(), source: e })
+    }
+
+    /// Replace the saved thresholds. The file is removed once there is nothing
+    /// left to restore. The file is replaced atomically so that a crash never
+    /// leaves it partially written.
+    pub fn save(&self, saved: &[SavedThresholds]) -> Result<()> {
+        let io_err = |p: &Path, e| Error::Io { path: p.to_owned(), source: e };
+
+        if saved.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(io_err(&self.path, e)),
+                _ => Ok(()),
+            };
+        }
+
+        let mut temp_name = self.path.file_name().unwrap_or_default().to_owned();
+        temp_name.push(".tmp");
+        let temp_path = self.path.with_file_name(temp_name);
+
+        // Serializing plain data cannot fail
+        let data = serde_json::to_vec_pretty(saved).unwrap();
+
+        fs::write(&temp_path, data).map_err(|e| io_err(&temp_path, e))?;
+        fs::rename(&temp_path, &self.path).map_err(|e| io_err(&self.path, e))
+    }
+}
+
+/// Print the thresholds of fan sensors along with the configured thresholds
+/// and any drift from them. Each sensor is paired with its current thresholds.
+pub fn print_thresholds(sensors: &[(FanSensor, RawThresholds)], config: Option<&FanThresholds>) {
+    let width = sensors.iter()
+        .map(|(s, _)| s.name.len())
+        .chain([3])
+        .max()
+        .unwrap();
+
+    println!("{:<width$}  {:>6}  {:>8}  {:>8}  {:>8}  STATUS",
+             "FAN", "NUMBER", "LNC", "LC", "LNR", width = width);
+
+    for (sensor, current) in sensors {
+        let desired = config
+            .filter(|c| c.fans.contains(&sensor.name))
+            .map(|c| sensor.desired(c));
+
+        let status = match &desired {
+            None => "not configured".to_owned(),
+            Some(Err(e)) => e.to_string(),
+            Some(Ok(d)) => {
+                let drifted = drift(current, d);
+                if drifted.is_empty() {
+                    "ok".to_owned()
+                } else {
+                    let details = drifted.iter()
+                        .map(|n| {
+                            let i = THRESHOLD_NAMES.iter().position(|t| t == n).unwrap();
+                            format!("{} (configured: {})", n, sensor.format(d, i))
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ");
+
+                    format!("drift: {}", details)
+                }
+            }
+        };
+
+        println!("{:<width$}  {:>6}  {:>8}  {:>8}  {:>8}  {}",
+                 sensor.name, sensor.sensor_number,
+                 sensor.format(current, 0), sensor.format(current, 1),
+                 sensor.format(current, 2), status, width = width);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::config::Password,
+        super::*,
+    };
+
+    /// Build a full sensor record for a BMC-owned fan with the given
+    /// conversion factors. `format` is the analog data format bits and
+    /// `exponents` is the byte containing K2 (upper nibble) and K1 (lower
+    /// nibble).
+    fn fan_record(format: u8, m: u16, b: u16, exponents: u8) -> SensorRecord {
+        let mut sdr = vec![0u8; 48];
+
+        // Record type: full sensor record
+        sdr[3] = 0x01;
+        // Sensor owner: BMC, LUN 0
+        sdr[5] = 0x20;
+        sdr[7] = 7;
+        // Threshold access: readable and settable
+        sdr[11] = 0b10 << 2;
+        // Readable lower thresholds, settable lower non-critical and lower
+        // non-recoverable
+        sdr[18] = 0b111;
+        sdr[19] = 0b101;
+        sdr[20] = format << 6;
+        sdr[24] = m as u8;
+        sdr[25] = ((m >> 8) as u8) << 6;
+        sdr[26] = b as u8;
+        sdr[27] = ((b >> 8) as u8) << 6;
+        sdr[29] = exponents;
+        sdr.extend_from_slice(b"FAN1");
+
+        SensorRecord { name: "FAN1".to_owned(), sensor_number: 7, sdr }
+    }
+
+    fn factors(format: AnalogFormat, m: i16, b: i16, b_exp: i8, r_exp: i8) -> Factors {
+        Factors { format, m, b, b_exp, r_exp }
+    }
+
+    fn thresholds(lnc: Option<f64>, lc: Option<f64>, lnr: Option<f64>) -> FanThresholds {
+        FanThresholds {
+            fans: vec!["FAN1".to_owned()],
+            lower_non_critical: lnc,
+            lower_critical: lc,
+            lower_non_recoverable: lnr,
+        }
+    }
+
+    #[test]
+    fn sdr_factors() {
+        // M = -3 and B = 5 as 10-bit values, K2 = -1, K1 = 1
+        let sensor = FanSensor::from_record(&fan_record(0b00, 0x3fd, 5, 0xf1)).unwrap();
+
+        assert_eq!(sensor.name, "FAN1");
+        assert_eq!(sensor.sensor_number, 7);
+        assert_eq!(sensor.settable, 0b101);
+        assert_eq!(sensor.factors.format, AnalogFormat::Unsigned);
+        assert_eq!(sensor.factors.m, -3);
+        assert_eq!(sensor.factors.b, 5);
+        assert_eq!(sensor.factors.b_exp, 1);
+        assert_eq!(sensor.factors.r_exp, -1);
+        assert_eq!(sensor.factors.to_value(10), 2.0);
+
+        let sensor = FanSensor::from_record(&fan_record(0b01, 75, 0, 0)).unwrap();
+        assert_eq!(sensor.factors.format, AnalogFormat::OnesComplement);
+
+        let sensor = FanSensor::from_record(&fan_record(0b10, 75, 0, 0)).unwrap();
+        assert_eq!(sensor.factors.format, AnalogFormat::TwosComplement);
+    }
+
+    #[test]
+    fn sdr_unsupported() {
+        let modify = |f: &dyn Fn(&mut Vec<u8>)| {
+            let mut record = fan_record(0b00, 75, 0, 0);
+            f(&mut record.sdr);
+            record
+        };
+
+        for (record, expected) in [
+            (modify(&|s| s.truncate(SDR_MIN_LENGTH - 1)), "record is too short"),
+            (modify(&|s| s[3] = 0x02), "not a full sensor record"),
+            (modify(&|s| s[5] = 0x30), "sensor is not owned by the BMC"),
+            (modify(&|s| s[6] = 0x01), "sensor is not owned by the BMC"),
+            (modify(&|s| s[11] = 0), "sensor has no thresholds"),
+            (modify(&|s| s[20] = 0b11 << 6), "sensor has no analog reading"),
+            (modify(&|s| s[23] = 0x01), "sensor uses non-linear conversion"),
+        ] {
+            assert!(matches!(
+                FanSensor::from_record(&record),
+                Err(Error::SdrUnsupported { sensor, reason })
+                    if sensor == "FAN1" && reason == expected,
+            ), "{expected}");
+        }
+
+        // Readable but not settable
+        let sensor = FanSensor::from_record(&modify(&|s| s[11] = 0b01 << 2)).unwrap();
+        assert_eq!(sensor.settable, 0);
+    }
+
+    #[test]
+    fn factors_round_trip() {
+        for (factors, raw, value) in [
+            (factors(AnalogFormat::Unsigned, 75, 0, 0, 0), 10, 750.0),
+            (factors(AnalogFormat::Unsigned, 75, 0, 0, 0), 255, 19125.0),
+            (factors(AnalogFormat::Unsigned, -3, 5, 1, -1), 10, 2.0),
+            (factors(AnalogFormat::Unsigned, 1, 0, 0, 2), 3, 300.0),
+            (factors(AnalogFormat::OnesComplement, 2, 0, 0, 0), 0x05, 10.0),
+            (factors(AnalogFormat::OnesComplement, 2, 0, 0, 0), 0xfe, -2.0),
+            (factors(AnalogFormat::OnesComplement, 2, 0, 0, 0), 0x80, -254.0),
+            (factors(AnalogFormat::TwosComplement, 2, 0, 0, 0), 0xfe, -4.0),
+            (factors(AnalogFormat::TwosComplement, 2, 0, 0, 0), 0x80, -256.0),
+        ] {
+            assert_eq!(factors.to_value(raw), value, "{factors:?}: {raw:#x}");
+            assert_eq!(factors.to_raw(value), Some(raw), "{factors:?}: {value}");
+        }
+
+        // Values are rounded to the nearest raw value
+        let unsigned = factors(AnalogFormat::Unsigned, 75, 0, 0, 0);
+        assert_eq!(unsigned.to_raw(780.0), Some(10));
+        assert_eq!(unsigned.to_raw(790.0), Some(11));
+
+        // Values that cannot be represented
+        assert_eq!(unsigned.to_raw(-75.0), None);
+        assert_eq!(unsigned.to_raw(19200.0), None);
+        let ones = factors(AnalogFormat::OnesComplement, 2, 0, 0, 0);
+        assert_eq!(ones.to_raw(256.0), None);
+        assert_eq!(ones.to_raw(-256.0), None);
+        let twos = factors(AnalogFormat::TwosComplement, 2, 0, 0, 0);
+        assert_eq!(twos.to_raw(-258.0), None);
+        assert_eq!(factors(AnalogFormat::Unsigned, 0, 0, 0, 0).to_raw(0.0), None);
+    }
+
+    #[test]
+    fn desired_thresholds() {
+        let sensor = FanSensor::from_record(&fan_record(0b00, 75, 0, 0)).unwrap();
+
+        let desired = sensor.desired(&thresholds(Some(300.0), None, Some(150.0))).unwrap();
+        assert_eq!(desired, RawThresholds { mask: 0b101, values: [4, 0, 2, 0, 0, 0] });
+
+        let desired = sensor.desired(&thresholds(None, None, None)).unwrap();
+        assert_eq!(desired, RawThresholds::default());
+
+        assert!(matches!(
+            sensor.desired(&thresholds(None, Some(225.0), None)),
+            Err(Error::ThresholdNotSettable { threshold: "lower_critical", .. }),
+        ));
+        assert!(matches!(
+            sensor.desired(&thresholds(Some(-300.0), None, None)),
+            Err(Error::ThresholdOutOfRange { threshold: "lower_non_critical", .. }),
+        ));
+    }
+
+    #[test]
+    fn store_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ThresholdStore::new(dir.path(), "local", &SessionType::Local);
+
+        assert_eq!(store.path(), dir.path().join("thresholds-local.json"));
+        assert!(store.load().unwrap().is_empty());
+
+        let saved = vec![SavedThresholds {
+            name: "FAN1".to_owned(),
+            sensor_number: 7,
+            thresholds: RawThresholds { mask: 0b111, values: [4, 3, 2, 0, 0, 0] },
+        }];
+        store.save(&saved).unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].name, "FAN1");
+        assert_eq!(loaded[0].sensor_number, 7);
+        assert_eq!(loaded[0].thresholds, saved[0].thresholds);
+
+        store.save(&[]).unwrap();
+        assert!(!store.path().exists());
+        assert!(store.load().unwrap().is_empty());
+
+        // Removing again is not an error
+        store.save(&[]).unwrap();
+
+        fs::write(store.path(), "[").unwrap();
+        assert!(matches!(store.load(), Err(Error::ThresholdsParse { .. })));
+    }
+
+    #[test]
+    fn store_remote_name() {
+        let st = SessionType::Remote {
+            hostname: "fe80::1%eth0".to_owned(),
+            username: "admin".to_owned(),
+            password: Password("admin".to_owned()),
+        };
+        let store = ThresholdStore::new(Path::new("/state"), "bmc", &st);
+
+        assert_eq!(store.path(), Path::new("/state/thresholds-remote-fe80__1_eth0.json"));
+    }
+}